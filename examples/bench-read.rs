@@ -0,0 +1,109 @@
+//! Reads all given files simultaneously (one thread per stream) and reports the throughput.
+//!
+//! WAV files can be read with `--mmap` to compare with the default (hound) decoder.
+//!
+//! Usage: bench-read [--direct] [--mmap] [--read-size BYTES] [--read-ahead BYTES]
+//!                   [--streams N] [--samplerate HZ] FILE...
+
+use std::fs;
+use std::thread;
+use std::time::Instant;
+
+use failure::{bail, Error};
+
+use disk_streaming::disk::ReadOptions;
+use disk_streaming::streamer::{load_audio_file_with_options, Route};
+
+struct Report {
+    path: String,
+    bytes: u64,
+    frames: usize,
+    seconds: f64,
+}
+
+fn main() -> Result<(), Error> {
+    let mut options = ReadOptions::default();
+    let mut streams = 1;
+    let mut samplerate = 44_100;
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--direct" => options.direct = true,
+            "--mmap" => options.mmap = true,
+            "--read-size" => options.read_size = parse_next(&mut args, &arg)?,
+            "--read-ahead" => options.read_ahead = parse_next(&mut args, &arg)?,
+            "--streams" => streams = parse_next(&mut args, &arg)?,
+            "--samplerate" => samplerate = parse_next(&mut args, &arg)?,
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        bail!("No files given");
+    }
+
+    let blocksize = 1024;
+    let start = Instant::now();
+    let threads: Vec<_> = paths
+        .iter()
+        .cycle()
+        .take(streams * paths.len())
+        .cloned()
+        .map(|path| {
+            let options = options.clone();
+            thread::spawn(move || -> Result<Report, Error> {
+                let bytes = fs::metadata(&path)?.len();
+                let start = Instant::now();
+                let mut file = load_audio_file_with_options(&path, samplerate, &options)?;
+                let routing: Vec<_> = (0..file.channels())
+                    .map(|c| Route::new(c, c, 1.0))
+                    .collect();
+                let mut channels: Vec<Box<[f32]>> = (0..file.channels())
+                    .map(|_| vec![0.0; blocksize].into_boxed_slice())
+                    .collect();
+                let gains = vec![1.0; blocksize];
+                let frames = file.frames();
+                let mut offset = 0;
+                while offset < frames {
+                    file.fill_channels(&routing, &gains, blocksize, 0, &mut channels)?;
+                    offset += blocksize;
+                }
+                Ok(Report {
+                    path,
+                    bytes,
+                    frames,
+                    seconds: start.elapsed().as_secs_f64(),
+                })
+            })
+        })
+        .collect();
+
+    let mut total_bytes = 0;
+    for (i, thread) in threads.into_iter().enumerate() {
+        let report = thread.join().unwrap()?;
+        println!(
+            "stream {:3}: {:7.2} MB/s, {:8.1}x realtime ({})",
+            i,
+            report.bytes as f64 / report.seconds / 1e6,
+            report.frames as f64 / samplerate as f64 / report.seconds,
+            report.path,
+        );
+        total_bytes += report.bytes;
+    }
+    println!(
+        "total: {:.2} MB/s",
+        total_bytes as f64 / start.elapsed().as_secs_f64() / 1e6
+    );
+    Ok(())
+}
+
+fn parse_next<I, T>(args: &mut I, name: &str) -> Result<T, Error>
+where
+    I: Iterator<Item = String>,
+    T: std::str::FromStr,
+{
+    match args.next().and_then(|value| value.parse().ok()) {
+        Some(value) => Ok(value),
+        None => bail!("Invalid value for {}", name),
+    }
+}
@@ -22,7 +22,7 @@ typedef struct {
 int sync_callback(jack_transport_state_t state, jack_position_t* pos, void* arg)
 {
   auto* userdata = static_cast<userdata_t*>(arg);
-  return file_streamer_seek(userdata->streamer, pos->frame);
+  return file_streamer_seek(userdata->streamer, pos->frame) == DISK_STREAMING_STATUS_OK;
 }
 
 int process_callback(jack_nframes_t nframes, void *arg)
@@ -38,9 +38,11 @@ int process_callback(jack_nframes_t nframes, void *arg)
   data[2] = static_cast<float*>(jack_port_get_buffer(userdata->port3, nframes));
   data[3] = static_cast<float*>(jack_port_get_buffer(userdata->port4, nframes));
 
-  if (!file_streamer_get_data(userdata->streamer, data, state == JackTransportRolling))
+  auto status = file_streamer_get_data(
+      userdata->streamer, data, nframes, state == JackTransportRolling);
+  if (status != DISK_STREAMING_STATUS_OK)
   {
-    std::cerr << "empty queue, stopping callback" << std::endl;
+    std::cerr << "error " << status << ", stopping callback" << std::endl;
     return 1;
   }
   return 0;
@@ -61,14 +63,29 @@ int main()
   auto blocksize = jack_get_buffer_size(userdata.client);
   auto samplerate = jack_get_sample_rate(userdata.client);
 
-  userdata.streamer = file_streamer_new(blocksize, samplerate);
-  if (userdata.streamer == nullptr)
+  // For now, 4 channels/sources are hard-coded
+
+  auto* playlist = playlist_new(samplerate);
+  const intptr_t map1[] = {0, 1};
+  const intptr_t map2[] = {2, 3};
+  const intptr_t map3[] = {1};
+  const intptr_t map4[] = {0};
+  if (playlist_add_entry(playlist, "marimba.ogg", 0, 0, map1, 2)
+      || playlist_add_entry(playlist, "marimba.ogg", 3 * 44100, 0, map2, 2)
+      || playlist_add_entry(playlist, "ukewave.ogg", 4 * 44100, 0, map3, 1)
+      || playlist_add_entry(playlist, "xmas.wav", 5 * 44100, 0, map4, 1))
   {
-    std::cerr << "Cannot load files" << std::endl;
+    std::cerr << "Cannot load files: " << disk_streaming_last_error() << std::endl;
+    playlist_free(playlist);
     exit(1);
   }
 
-  // For now, 4 channels/sources are hard-coded
+  userdata.streamer = file_streamer_new(playlist, blocksize, 4);
+  if (userdata.streamer == nullptr)
+  {
+    std::cerr << "Cannot create file streamer: " << disk_streaming_last_error() << std::endl;
+    exit(1);
+  }
 
   std::vector<float*> storage(4);
   userdata.block_data = storage.data();
@@ -0,0 +1,165 @@
+/* Exercises the C API, run from the "examples" directory */
+
+#define _POSIX_C_SOURCE 199309L
+
+#include <assert.h>
+#include <stdio.h>
+#include <string.h>
+#include <time.h>
+
+#include "disk_streaming.h"
+
+#define BLOCKSIZE 512
+#define CHANNELS 2
+
+static void log_callback(DISK_STREAMING_LOG_LEVEL level, const char* message, void* user_data)
+{
+  size_t* counter = user_data;
+  (*counter)++;
+  fprintf(stderr, "%s: %s\n",
+      level == DISK_STREAMING_LOG_LEVEL_ERROR ? "error" : "warning", message);
+}
+
+int main(void)
+{
+  /* NB: The API calls are not inside assert(), they also have to run with NDEBUG */
+  size_t messages = 0;
+  disk_streaming_set_log_callback(log_callback, &messages);
+
+  const char* error = disk_streaming_last_error();
+  assert(error == NULL);
+
+  AUDIO_FILE_INFO info;
+  DISK_STREAMING_STATUS status = audio_file_info("does-not-exist.wav", &info);
+  assert(status == DISK_STREAMING_STATUS_FILE_NOT_FOUND);
+  error = disk_streaming_last_error();
+  assert(strstr(error, "does-not-exist.wav") != NULL);
+  assert(messages == 1);
+  status = audio_file_info("Makefile", &info);
+  assert(status == DISK_STREAMING_STATUS_UNSUPPORTED_FORMAT);
+  assert(messages == 2);
+  status = audio_file_info("xmas.wav", &info);
+  assert(status == DISK_STREAMING_STATUS_OK);
+  printf("xmas.wav: %zu channel(s), %zu frames, %zu Hz\n",
+      info.channels, info.frames, info.samplerate);
+  assert(info.channels == 1);
+
+  PLAYLIST* playlist = playlist_new(info.samplerate);
+  size_t len = playlist_len(playlist);
+  assert(len == 0);
+
+  const intptr_t map[] = {1};
+  const intptr_t wrong_map[] = {0, 1};
+  const intptr_t unused[] = {-1};
+  status = playlist_add_entry(playlist, "does-not-exist.wav", 0, 0, map, 1);
+  assert(status == DISK_STREAMING_STATUS_FILE_NOT_FOUND);
+  status = playlist_add_entry(playlist, "xmas.wav", 0, 0, wrong_map, 2);
+  assert(status == DISK_STREAMING_STATUS_INVALID_ARGUMENT);
+  status = playlist_add_entry(playlist, "xmas.wav", 0, 0, map, 1);
+  assert(status == DISK_STREAMING_STATUS_OK);
+  status = playlist_add_entry(playlist, "xmas.wav", 1000, 2000, unused, 1);
+  assert(status == DISK_STREAMING_STATUS_OK);
+  len = playlist_len(playlist);
+  assert(len == 2);
+  playlist_free(playlist);
+
+  /* The scene is loaded into a new playlist */
+  PLAYLIST* scene = NULL;
+  size_t scene_channels = 0;
+  status = playlist_load_asdf("scene.asdf", 44100, &scene, &scene_channels);
+  assert(status == DISK_STREAMING_STATUS_OK);
+  assert(scene_channels == 4);
+  len = playlist_len(scene);
+  assert(len == 4);
+  playlist_free(scene);
+  scene = NULL;
+  status = playlist_load_asdf("Makefile", 44100, &scene, &scene_channels);
+  assert(status == DISK_STREAMING_STATUS_INVALID_SCENE);
+  error = disk_streaming_last_error();
+  assert(strstr(error, "Makefile:1:") != NULL);
+  assert(scene == NULL);
+
+  playlist = playlist_new(info.samplerate);
+  status = playlist_add_entry(playlist, "xmas.wav", 0, 0, map, 1);
+  assert(status == DISK_STREAMING_STATUS_OK);
+
+  /* Output channel 1 doesn't exist */
+  FILE_STREAMER* streamer = file_streamer_new(playlist, BLOCKSIZE, 1);
+  assert(streamer == NULL);
+  error = disk_streaming_last_error();
+  assert(strstr(error, "output channel") != NULL);
+
+  playlist = playlist_new(info.samplerate);
+  status = playlist_add_entry(playlist, "xmas.wav", 0, 0, map, 1);
+  assert(status == DISK_STREAMING_STATUS_OK);
+  streamer = file_streamer_new(playlist, BLOCKSIZE, CHANNELS);
+  assert(streamer != NULL);
+
+  float left[BLOCKSIZE];
+  float right[BLOCKSIZE];
+  float* const data[CHANNELS] = {left, right};
+  float interleaved[BLOCKSIZE * CHANNELS];
+
+  /* Wait until the reader thread has filled the queue */
+  while ((status = file_streamer_seek(streamer, 0)) != DISK_STREAMING_STATUS_OK)
+  {
+    assert(status == DISK_STREAMING_STATUS_NOT_READY);
+    status = file_streamer_get_data(streamer, data, BLOCKSIZE, false);
+    assert(status == DISK_STREAMING_STATUS_OK);
+  }
+
+  /* At most 4096 frames (or twice the blocksize) are allowed */
+  static float large[8192 * CHANNELS];
+  status = file_streamer_get_data_interleaved(streamer, large, 8192, false);
+  assert(status == DISK_STREAMING_STATUS_TOO_MANY_FRAMES);
+
+  /* Play in real time to avoid buffer underruns */
+  const struct timespec duration = {0, 1000000000L / info.samplerate * BLOCKSIZE};
+  size_t blocks = 0;
+  float peak = 0.0f;
+  while (blocks * BLOCKSIZE < info.frames)
+  {
+    nanosleep(&duration, NULL);
+    status = file_streamer_get_data_interleaved(streamer, interleaved, BLOCKSIZE, true);
+    if (status != DISK_STREAMING_STATUS_OK)
+    {
+      fprintf(stderr, "get_data() failed with status %d\n", status);
+      return 1;
+    }
+    for (size_t i = 0; i < BLOCKSIZE; i++)
+    {
+      assert(interleaved[i * CHANNELS] == 0.0f);
+      float value = interleaved[i * CHANNELS + 1];
+      if (value > peak)
+      {
+        peak = value;
+      }
+    }
+    blocks++;
+  }
+  printf("%zu blocks, peak value %f\n", blocks, peak);
+  assert(peak > 0.0f);
+
+  size_t warnings = file_streamer_report_warnings(streamer);
+  assert(warnings == 0);
+  status = file_streamer_reader_error(streamer);
+  assert(status == DISK_STREAMING_STATUS_OK);
+
+  /* Panics don't unwind into C */
+  size_t before = messages;
+  status = file_streamer_seek(NULL, 0);
+  assert(status == DISK_STREAMING_STATUS_PANIC);
+  error = disk_streaming_last_error();
+  assert(strstr(error, "Panic") != NULL);
+  assert(messages == before + 1);
+
+  file_streamer_free(streamer);
+  disk_streaming_set_log_callback(NULL, NULL);
+
+  /* Only used in assertions, which are removed with NDEBUG */
+  (void)error;
+  (void)len;
+  (void)warnings;
+  (void)before;
+  return 0;
+}
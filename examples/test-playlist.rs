@@ -1,9 +1,11 @@
 use std::thread;
 use std::time::Duration;
 
-use failure::Error;
+use failure::{bail, Error};
 
-use disk_streaming::streamer::{load_audio_file, FileStreamer, PlaylistEntry};
+use disk_streaming::streamer::{
+    load_audio_file, EndAction, FileStreamer, PlaylistEntry, Route, State,
+};
 
 fn main() -> Result<(), Error> {
     // TODO: specify blocksize and samplerate!
@@ -14,17 +16,21 @@ fn main() -> Result<(), Error> {
 
     let file = load_audio_file("examples/marimba.ogg", 44_100)?;
 
-    playlist.push(PlaylistEntry {
-        start: 0,
-        end: Some(file.frames()),
+    playlist.push(PlaylistEntry::new(
+        0,
+        Some(file.frames()),
         file,
-        channels: Box::new([Some(0), Some(1)]),
-    });
+        Route::from_channel_map(&[Some(0), Some(1)]),
+    )?);
 
     let blocksize = 1024;
     let channels = 4;
+    let block_duration = Duration::from_secs_f64(blocksize as f64 / 44_100.0);
 
-    let mut streamer = FileStreamer::new(playlist, blocksize, channels);
+    let mut streamer = FileStreamer::new(playlist, blocksize, channels)?;
+    streamer.set_end_action(EndAction::Stop);
+
+    println!("playlist duration: {} frames", streamer.duration());
 
     let mut data: Vec<Vec<_>> = (0..streamer.channels())
         .map(|_| vec![0f32; blocksize])
@@ -42,11 +48,35 @@ fn main() -> Result<(), Error> {
         thread::sleep(Duration::from_millis(1));
     }
 
-    let result = unsafe { streamer.get_data(&pointers, true) };
+    let result = unsafe { streamer.get_data(&pointers, blocksize, true) };
 
     if result {
         println!("got data");
     }
 
+    println!(
+        "state: {:?}, position: {}",
+        streamer.state(),
+        streamer.position()
+    );
+
+    // NB: Like an audio callback, one block is played per block duration
+    loop {
+        match streamer.state() {
+            State::Ended => break,
+            State::Failed => bail!(
+                "reader failed: {}",
+                streamer.reader_error().unwrap_or_default()
+            ),
+            _ => {}
+        }
+        if !unsafe { streamer.get_data(&pointers, blocksize, true) } {
+            println!("underrun at frame {}", streamer.position());
+        }
+        thread::sleep(block_duration);
+    }
+
+    println!("reached end at frame {}", streamer.position());
+
     Ok(())
 }
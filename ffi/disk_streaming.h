@@ -1,24 +1,220 @@
 #ifndef DISK_STREAMING_H
 #define DISK_STREAMING_H
 
-/* Generated with cbindgen:0.8.7 */
+/* Generated with cbindgen:0.29.4 */
 
 #include <stdarg.h>
 #include <stdbool.h>
 #include <stdint.h>
 #include <stdlib.h>
 
+/**
+ * Return value of most functions, see also `disk_streaming_last_error()`
+ */
+typedef enum DISK_STREAMING_STATUS {
+  DISK_STREAMING_STATUS_OK = 0,
+  /**
+   * Seeking is still in progress, no data is available yet
+   */
+  DISK_STREAMING_STATUS_NOT_READY,
+  DISK_STREAMING_STATUS_INVALID_ARGUMENT,
+  DISK_STREAMING_STATUS_FILE_NOT_FOUND,
+  DISK_STREAMING_STATUS_IO_ERROR,
+  DISK_STREAMING_STATUS_UNSUPPORTED_FORMAT,
+  DISK_STREAMING_STATUS_SAMPLERATE_CONVERSION,
+  /**
+   * Syntax error in a scene file, see `playlist_load_asdf()`
+   */
+  DISK_STREAMING_STATUS_INVALID_SCENE,
+  /**
+   * More frames requested than allowed by the blocksize
+   */
+  DISK_STREAMING_STATUS_TOO_MANY_FRAMES,
+  /**
+   * The reader thread didn't provide data in time
+   */
+  DISK_STREAMING_STATUS_UNDERRUN,
+  /**
+   * Seeking while rolling is not supported
+   */
+  DISK_STREAMING_STATUS_SEEK_WHILE_ROLLING,
+  /**
+   * The reader has stopped, see `file_streamer_reader_error()`
+   */
+  DISK_STREAMING_STATUS_READER_FAILED,
+  /**
+   * A bug in the library, the object that was used should be freed
+   */
+  DISK_STREAMING_STATUS_PANIC,
+  DISK_STREAMING_STATUS_OTHER_ERROR,
+} DISK_STREAMING_STATUS;
+
+typedef enum DISK_STREAMING_LOG_LEVEL {
+  DISK_STREAMING_LOG_LEVEL_ERROR,
+  DISK_STREAMING_LOG_LEVEL_WARNING,
+} DISK_STREAMING_LOG_LEVEL;
+
 typedef struct FILE_STREAMER FILE_STREAMER;
 
-void file_streamer_free(FILE_STREAMER *ptr);
+/**
+ * A list of playlist entries that is used to create a `FileStreamer`
+ */
+typedef struct PLAYLIST PLAYLIST;
+
+/**
+ * Filled in by `audio_file_info()`
+ */
+typedef struct AUDIO_FILE_INFO {
+  size_t channels;
+  size_t frames;
+  size_t samplerate;
+} AUDIO_FILE_INFO;
+
+/**
+ * `message` is only valid during the call
+ */
+typedef void (*DISK_STREAMING_LOG_CALLBACK)(enum DISK_STREAMING_LOG_LEVEL level,
+                                            const char *message,
+                                            void *user_data);
+
+/**
+ * Reads the header of an audio file, `frames` is given at the file's own sample rate
+ */
+enum DISK_STREAMING_STATUS audio_file_info(const char *path, struct AUDIO_FILE_INFO *info);
+
+/**
+ * All files in the playlist are converted to `samplerate`
+ */
+struct PLAYLIST *playlist_new(size_t samplerate);
+
+void playlist_free(struct PLAYLIST *ptr);
+
+/**
+ * Loads a file and adds it to the playlist
+ *
+ * `start` and `end` are playlist frames, an `end` of `0` means until the end of the file.
+ * `channel_map` contains one output channel for each of the `channels` file channels,
+ * negative values mean that the file channel is not used.
+ */
+enum DISK_STREAMING_STATUS playlist_add_entry(struct PLAYLIST *ptr,
+                                              const char *path,
+                                              size_t start,
+                                              size_t end,
+                                              const intptr_t *channel_map,
+                                              size_t channels);
+
+/**
+ * Loads all files of an ASDF scene into a new playlist
+ *
+ * On success, `*playlist` is set to the new playlist (which has to be freed)
+ * and `*channels` to the number of sources in the scene.
+ * NB: `*playlist` is overwritten, a playlist it pointed to before is *not* freed.
+ * On errors, `*playlist` is not changed.
+ * Errors contain the file name and the line number.
+ */
+enum DISK_STREAMING_STATUS playlist_load_asdf(const char *path,
+                                              size_t samplerate,
+                                              struct PLAYLIST **playlist,
+                                              size_t *channels);
+
+/**
+ * Number of entries in the playlist
+ */
+size_t playlist_len(const struct PLAYLIST *ptr);
+
+/**
+ * Creates a streamer with `channels` output channels, `playlist` is consumed
+ *
+ * Returns NULL if the playlist uses output channels that are not less than `channels`
+ * (the playlist is freed anyway), see `disk_streaming_last_error()`.
+ */
+struct FILE_STREAMER *file_streamer_new(struct PLAYLIST *playlist,
+                                        size_t blocksize,
+                                        size_t channels);
+
+void file_streamer_free(struct FILE_STREAMER *ptr);
+
+/**
+ * Returns `NOT_READY` until the seek has finished, this function has to be called again
+ */
+enum DISK_STREAMING_STATUS file_streamer_seek(struct FILE_STREAMER *ptr, size_t frame);
+
+/**
+ * Returns `READER_FAILED` if the reader has stopped because of an error
+ *
+ * The reason can be obtained with `disk_streaming_last_error()`.
+ * This is not real-time safe.
+ */
+enum DISK_STREAMING_STATUS file_streamer_reader_error(struct FILE_STREAMER *ptr);
+
+/**
+ * Passes the warnings collected since the last call to the log callback
+ *
+ * Returns the number of warnings.
+ */
+size_t file_streamer_report_warnings(struct FILE_STREAMER *ptr);
+
+/**
+ * The output buffers are always filled (with zeros in case of an error)
+ *
+ * The only exception is `PANIC`, then the contents of the buffers are undefined.
+ */
+enum DISK_STREAMING_STATUS file_streamer_get_data(struct FILE_STREAMER *ptr,
+                                                  float *const *data,
+                                                  size_t frames,
+                                                  bool rolling);
+
+/**
+ * Like `file_streamer_get_data()`, but with `double` samples
+ */
+enum DISK_STREAMING_STATUS file_streamer_get_data_f64(struct FILE_STREAMER *ptr,
+                                                      double *const *data,
+                                                      size_t frames,
+                                                      bool rolling);
+
+/**
+ * Like `file_streamer_get_data()`, but with 16-bit integer samples (clipped)
+ */
+enum DISK_STREAMING_STATUS file_streamer_get_data_i16(struct FILE_STREAMER *ptr,
+                                                      int16_t *const *data,
+                                                      size_t frames,
+                                                      bool rolling);
 
 /**
- * Return value of `false` means un-recoverable error
+ * Like `file_streamer_get_data()`, but with 32-bit integer samples (clipped, 24-bit resolution)
  */
-bool file_streamer_get_data(FILE_STREAMER *ptr, float *const *data, bool rolling);
+enum DISK_STREAMING_STATUS file_streamer_get_data_i32(struct FILE_STREAMER *ptr,
+                                                      int32_t *const *data,
+                                                      size_t frames,
+                                                      bool rolling);
 
-FILE_STREAMER *file_streamer_new(size_t blocksize, size_t samplerate);
+/**
+ * `data` must have space for `frames` times the number of channels
+ */
+enum DISK_STREAMING_STATUS file_streamer_get_data_interleaved(struct FILE_STREAMER *ptr,
+                                                              float *data,
+                                                              size_t frames,
+                                                              bool rolling);
 
-bool file_streamer_seek(FILE_STREAMER *ptr, size_t frame);
+/**
+ * Return value of `false` means that the blocksize cannot be changed right now
+ */
+bool file_streamer_set_blocksize(struct FILE_STREAMER *ptr, size_t blocksize);
+
+/**
+ * Registers a function that receives all error messages and warnings
+ *
+ * The callback may be called from any thread (but not from the real-time functions
+ * `file_streamer_get_data*()`), passing NULL removes the callback.
+ */
+void disk_streaming_set_log_callback(DISK_STREAMING_LOG_CALLBACK callback, void *user_data);
+
+/**
+ * Message of the most recent error on the calling thread, NULL if there was none
+ *
+ * The string is valid until the next error happens on the same thread.
+ * `file_streamer_get_data*()` only return a status code, they don't set an error message.
+ */
+const char *disk_streaming_last_error(void);
 
-#endif /* DISK_STREAMING_H */
+#endif  /* DISK_STREAMING_H */
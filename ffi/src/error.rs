@@ -0,0 +1,199 @@
+//! Status codes, error messages and the log callback
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+
+use failure::Error;
+
+use disk_streaming::asdf::{AsdfError, AsdfErrorKind};
+use disk_streaming::file::converter::LibSamplerateError;
+use disk_streaming::streamer::{
+    panic_message, DataError, LoadError, OutputChannelError, RoutingError,
+};
+
+/// Return value of most functions, see also `disk_streaming_last_error()`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskStreamingStatus {
+    Ok = 0,
+    /// Seeking is still in progress, no data is available yet
+    NotReady,
+    InvalidArgument,
+    FileNotFound,
+    IoError,
+    UnsupportedFormat,
+    SamplerateConversion,
+    /// Syntax error in a scene file, see `playlist_load_asdf()`
+    InvalidScene,
+    /// More frames requested than allowed by the blocksize
+    TooManyFrames,
+    /// The reader thread didn't provide data in time
+    Underrun,
+    /// Seeking while rolling is not supported
+    SeekWhileRolling,
+    /// The reader has stopped, see `file_streamer_reader_error()`
+    ReaderFailed,
+    /// A bug in the library, the object that was used should be freed
+    Panic,
+    OtherError,
+}
+
+impl From<DataError> for DiskStreamingStatus {
+    fn from(error: DataError) -> DiskStreamingStatus {
+        match error {
+            DataError::TooManyFrames => DiskStreamingStatus::TooManyFrames,
+            DataError::NotReady => DiskStreamingStatus::NotReady,
+            DataError::Underrun => DiskStreamingStatus::Underrun,
+            DataError::SeekWhileRolling => DiskStreamingStatus::SeekWhileRolling,
+            DataError::ReaderFailed => DiskStreamingStatus::ReaderFailed,
+        }
+    }
+}
+
+impl From<&Error> for DiskStreamingStatus {
+    fn from(error: &Error) -> DiskStreamingStatus {
+        use DiskStreamingStatus::*;
+        for cause in error.iter_chain() {
+            if let Some(e) = cause.downcast_ref::<AsdfError>() {
+                match e.kind {
+                    AsdfErrorKind::Xml(_) | AsdfErrorKind::Invalid(_) => return InvalidScene,
+                    // The cause is checked in the next iteration
+                    _ => continue,
+                }
+            }
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    return FileNotFound;
+                } else {
+                    return IoError;
+                }
+            }
+            if cause.downcast_ref::<LoadError>().is_some() {
+                return UnsupportedFormat;
+            }
+            if cause.downcast_ref::<LibSamplerateError>().is_some() {
+                return SamplerateConversion;
+            }
+            if cause.downcast_ref::<RoutingError>().is_some()
+                || cause.downcast_ref::<OutputChannelError>().is_some()
+            {
+                return InvalidArgument;
+            }
+        }
+        OtherError
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskStreamingLogLevel {
+    Error,
+    Warning,
+}
+
+// NB: The Option is part of the alias, cbindgen doesn't resolve Option<alias>
+/// `message` is only valid during the call
+pub type DiskStreamingLogCallback = Option<
+    extern "C" fn(
+        level: DiskStreamingLogLevel,
+        message: *const libc::c_char,
+        user_data: *mut libc::c_void,
+    ),
+>;
+
+/// No messages are logged if `callback` is `None`
+struct Logger {
+    callback: DiskStreamingLogCallback,
+    user_data: *mut libc::c_void,
+}
+
+// NB: It's the caller's responsibility to provide a thread-safe callback
+unsafe impl Send for Logger {}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    callback: None,
+    user_data: std::ptr::null_mut(),
+});
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Registers a function that receives all error messages and warnings
+///
+/// The callback may be called from any thread (but not from the real-time functions
+/// `file_streamer_get_data*()`), passing NULL removes the callback.
+#[no_mangle]
+pub unsafe extern "C" fn disk_streaming_set_log_callback(
+    callback: DiskStreamingLogCallback,
+    user_data: *mut libc::c_void,
+) {
+    catch_panic((), || {
+        let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+        *logger = Logger {
+            callback,
+            user_data,
+        };
+    })
+}
+
+/// Message of the most recent error on the calling thread, NULL if there was none
+///
+/// The string is valid until the next error happens on the same thread.
+/// `file_streamer_get_data*()` only return a status code, they don't set an error message.
+#[no_mangle]
+pub extern "C" fn disk_streaming_last_error() -> *const libc::c_char {
+    catch_panic(std::ptr::null(), || {
+        LAST_ERROR.with(|last| match *last.borrow() {
+            Some(ref message) => message.as_ptr(),
+            None => std::ptr::null(),
+        })
+    })
+}
+
+/// Runs `f`, a panic is turned into an error message and `on_panic` is returned
+pub fn catch_panic<T, F>(on_panic: T, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => value,
+        Err(payload) => {
+            let message = panic_message(&*payload);
+            set_last_error(DiskStreamingStatus::Panic, &format!("Panic: {}", message));
+            on_panic
+        }
+    }
+}
+
+pub fn log(level: DiskStreamingLogLevel, message: &str) {
+    // NB: The lock is released before calling the callback,
+    //     which might call disk_streaming_set_log_callback() or cause further logging.
+    let (callback, user_data) = {
+        let logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+        (logger.callback, logger.user_data)
+    };
+    if let Some(callback) = callback {
+        let message = to_c_string(message);
+        callback(level, message.as_ptr(), user_data);
+    }
+}
+
+/// Stores the message for `disk_streaming_last_error()` and passes it to the log callback
+pub fn set_last_error(status: DiskStreamingStatus, message: &str) -> DiskStreamingStatus {
+    log(DiskStreamingLogLevel::Error, message);
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(to_c_string(message)));
+    status
+}
+
+/// Like `set_last_error()`, with the status code derived from `error`
+pub fn report_error(context: &str, error: &Error) -> DiskStreamingStatus {
+    set_last_error(error.into(), &format!("{}: {}", context, error))
+}
+
+fn to_c_string(message: &str) -> CString {
+    // NB: Interior NUL bytes would truncate the message anyway
+    CString::new(message.replace('\0', "")).unwrap()
+}
@@ -1,90 +1,396 @@
 // http://jakegoulding.com/rust-ffi-omnibus/objects/
 // https://blog.eqrion.net/announcing-cbindgen/
 
-extern crate failure;
-use failure::Error;
+// NB: All functions are meant to be called from C, pointers must be valid (or NULL
+// where this is allowed) and objects must not be used after they have been freed
+#![allow(clippy::missing_safety_doc)]
 
-extern crate disk_streaming;
-use disk_streaming::streamer::{load_audio_file, FileStreamer, PlaylistEntry};
+use std::ffi::CStr;
 
-// TODO: use catch_unwind()? https://doc.rust-lang.org/std/panic/fn.catch_unwind.html
+use disk_streaming::asdf::load_scene;
+use disk_streaming::sample::Sample;
+use disk_streaming::streamer::{
+    load_audio_file, load_audio_file_native, FileStreamer, PlaylistEntry, Route, State,
+};
 
-// TODO: accept callback function for error reporting?
+mod error;
+use crate::error::{catch_panic, log, report_error, set_last_error};
+pub use crate::error::{
+    disk_streaming_last_error, disk_streaming_set_log_callback, DiskStreamingLogCallback,
+    DiskStreamingLogLevel, DiskStreamingStatus,
+};
 
-fn load_file_streamer(blocksize: usize, samplerate: usize) -> Result<FileStreamer, Error> {
-    let channels = 4;
-    let mut playlist = Vec::new();
+// NB: Panics must not unwind into C, therefore all functions use catch_panic()
 
-    let file = load_audio_file("marimba.ogg", samplerate)?;
-    playlist.push(PlaylistEntry {
-        start: 0,
-        end: Some(file.frames()),
-        file,
-        channels: Box::new([Some(0), Some(1)]),
-    });
+/// A list of playlist entries that is used to create a `FileStreamer`
+pub struct Playlist {
+    samplerate: usize,
+    entries: Vec<PlaylistEntry>,
+}
 
-    let file = load_audio_file("marimba.ogg", samplerate)?;
-    playlist.push(PlaylistEntry {
-        start: 3 * 44_100,
-        end: Some(file.frames() + 3 * 44_100),
-        file,
-        channels: Box::new([Some(2), Some(3)]),
-    });
+/// Filled in by `audio_file_info()`
+#[repr(C)]
+pub struct AudioFileInfo {
+    pub channels: libc::size_t,
+    pub frames: libc::size_t,
+    pub samplerate: libc::size_t,
+}
 
-    let file = load_audio_file("ukewave.ogg", samplerate)?;
-    playlist.push(PlaylistEntry {
-        start: 4 * 44_100,
-        end: Some(file.frames() + 4 * 44_100),
-        file,
-        channels: Box::new([Some(1)]),
-    });
+/// Reads the header of an audio file, `frames` is given at the file's own sample rate
+#[no_mangle]
+pub unsafe extern "C" fn audio_file_info(
+    path: *const libc::c_char,
+    info: *mut AudioFileInfo,
+) -> DiskStreamingStatus {
+    catch_panic(DiskStreamingStatus::Panic, || {
+        assert!(!path.is_null());
+        assert!(!info.is_null());
+        let path = match to_path(path) {
+            Ok(path) => path,
+            Err(status) => return status,
+        };
+        match load_audio_file_native(path) {
+            Ok(file) => {
+                *info = AudioFileInfo {
+                    channels: file.channels(),
+                    frames: file.frames(),
+                    samplerate: file.samplerate(),
+                };
+                DiskStreamingStatus::Ok
+            }
+            Err(e) => report_error(path, &e),
+        }
+    })
+}
 
-    let file = load_audio_file("xmas.wav", samplerate)?;
-    playlist.push(PlaylistEntry {
-        start: 5 * 44_100,
-        end: Some(file.frames() + 5 * 44_100),
-        file,
-        channels: Box::new([Some(0)]),
-    });
+/// All files in the playlist are converted to `samplerate`
+#[no_mangle]
+pub extern "C" fn playlist_new(samplerate: libc::size_t) -> *mut Playlist {
+    catch_panic(std::ptr::null_mut(), || {
+        Box::into_raw(Box::new(Playlist {
+            samplerate,
+            entries: Vec::new(),
+        }))
+    })
+}
 
-    Ok(FileStreamer::new(playlist, blocksize, channels))
+#[no_mangle]
+pub unsafe extern "C" fn playlist_free(ptr: *mut Playlist) {
+    catch_panic((), || {
+        if !ptr.is_null() {
+            drop(Box::from_raw(ptr));
+        }
+    })
 }
 
+/// Loads a file and adds it to the playlist
+///
+/// `start` and `end` are playlist frames, an `end` of `0` means until the end of the file.
+/// `channel_map` contains one output channel for each of the `channels` file channels,
+/// negative values mean that the file channel is not used.
 #[no_mangle]
-pub extern "C" fn file_streamer_new(
-    blocksize: libc::size_t,
+pub unsafe extern "C" fn playlist_add_entry(
+    ptr: *mut Playlist,
+    path: *const libc::c_char,
+    start: libc::size_t,
+    end: libc::size_t,
+    channel_map: *const isize,
+    channels: libc::size_t,
+) -> DiskStreamingStatus {
+    catch_panic(DiskStreamingStatus::Panic, || {
+        assert!(!ptr.is_null());
+        assert!(!path.is_null());
+        assert!(!channel_map.is_null() || channels == 0);
+        let playlist = &mut *ptr;
+        let path = match to_path(path) {
+            Ok(path) => path,
+            Err(status) => return status,
+        };
+        let channel_map: Vec<_> = if channels == 0 {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(channel_map, channels)
+                .iter()
+                .map(|&channel| {
+                    if channel < 0 {
+                        None
+                    } else {
+                        Some(channel as usize)
+                    }
+                })
+                .collect()
+        };
+        let file = match load_audio_file(path, playlist.samplerate) {
+            Ok(file) => file,
+            Err(e) => return report_error(path, &e),
+        };
+        if channel_map.len() != file.channels() {
+            return set_last_error(
+                DiskStreamingStatus::InvalidArgument,
+                &format!(
+                    "{}: channel map has {} channels, but the file has {}",
+                    path,
+                    channel_map.len(),
+                    file.channels()
+                ),
+            );
+        }
+        let end = if end == 0 { None } else { Some(end) };
+        match PlaylistEntry::new(start, end, file, Route::from_channel_map(&channel_map)) {
+            Ok(entry) => {
+                playlist.entries.push(entry);
+                DiskStreamingStatus::Ok
+            }
+            Err(e) => report_error(path, &e),
+        }
+    })
+}
+
+/// Loads all files of an ASDF scene into a new playlist
+///
+/// On success, `*playlist` is set to the new playlist (which has to be freed)
+/// and `*channels` to the number of sources in the scene.
+/// NB: `*playlist` is overwritten, a playlist it pointed to before is *not* freed.
+/// On errors, `*playlist` is not changed.
+/// Errors contain the file name and the line number.
+#[no_mangle]
+pub unsafe extern "C" fn playlist_load_asdf(
+    path: *const libc::c_char,
     samplerate: libc::size_t,
+    playlist: *mut *mut Playlist,
+    channels: *mut libc::size_t,
+) -> DiskStreamingStatus {
+    catch_panic(DiskStreamingStatus::Panic, || {
+        assert!(!path.is_null());
+        assert!(!playlist.is_null());
+        assert!(!channels.is_null());
+        let path = match to_path(path) {
+            Ok(path) => path,
+            Err(status) => return status,
+        };
+        match load_scene(path, samplerate) {
+            Ok(scene) => {
+                *channels = scene.sources.len();
+                *playlist = Box::into_raw(Box::new(Playlist {
+                    samplerate,
+                    entries: scene.playlist,
+                }));
+                DiskStreamingStatus::Ok
+            }
+            Err(e) => {
+                let error: failure::Error = e.into();
+                set_last_error((&error).into(), &error.to_string())
+            }
+        }
+    })
+}
+
+/// Number of entries in the playlist
+#[no_mangle]
+pub unsafe extern "C" fn playlist_len(ptr: *const Playlist) -> libc::size_t {
+    catch_panic(0, || {
+        assert!(!ptr.is_null());
+        (*ptr).entries.len()
+    })
+}
+
+/// Creates a streamer with `channels` output channels, `playlist` is consumed
+///
+/// Returns NULL if the playlist uses output channels that are not less than `channels`
+/// (the playlist is freed anyway), see `disk_streaming_last_error()`.
+#[no_mangle]
+pub unsafe extern "C" fn file_streamer_new(
+    playlist: *mut Playlist,
+    blocksize: libc::size_t,
+    channels: libc::size_t,
 ) -> *mut FileStreamer {
-    if let Ok(streamer) = load_file_streamer(blocksize, samplerate) {
-        Box::into_raw(Box::new(streamer))
-    } else {
-        std::ptr::null_mut()
-    }
+    catch_panic(std::ptr::null_mut(), || {
+        assert!(!playlist.is_null());
+        let playlist = Box::from_raw(playlist);
+        match FileStreamer::new(playlist.entries, blocksize, channels) {
+            Ok(streamer) => Box::into_raw(Box::new(streamer)),
+            Err(e) => {
+                set_last_error(DiskStreamingStatus::InvalidArgument, &e.to_string());
+                std::ptr::null_mut()
+            }
+        }
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn file_streamer_free(ptr: *mut FileStreamer) {
-    if !ptr.is_null() {
-        Box::from_raw(ptr);
-    }
+    catch_panic((), || {
+        if !ptr.is_null() {
+            drop(Box::from_raw(ptr));
+        }
+    })
 }
 
+/// Returns `NOT_READY` until the seek has finished, this function has to be called again
 #[no_mangle]
-pub unsafe extern "C" fn file_streamer_seek(ptr: *mut FileStreamer, frame: libc::size_t) -> bool {
-    assert!(!ptr.is_null());
-    let streamer = &mut *ptr;
-    streamer.seek(frame)
+pub unsafe extern "C" fn file_streamer_seek(
+    ptr: *mut FileStreamer,
+    frame: libc::size_t,
+) -> DiskStreamingStatus {
+    catch_panic(DiskStreamingStatus::Panic, || {
+        assert!(!ptr.is_null());
+        let streamer = &mut *ptr;
+        if streamer.seek(frame) {
+            DiskStreamingStatus::Ok
+        } else if streamer.state() == State::Failed {
+            DiskStreamingStatus::ReaderFailed
+        } else {
+            DiskStreamingStatus::NotReady
+        }
+    })
 }
 
-/// Return value of `false` means un-recoverable error
+/// Returns `READER_FAILED` if the reader has stopped because of an error
+///
+/// The reason can be obtained with `disk_streaming_last_error()`.
+/// This is not real-time safe.
+#[no_mangle]
+pub unsafe extern "C" fn file_streamer_reader_error(ptr: *mut FileStreamer) -> DiskStreamingStatus {
+    catch_panic(DiskStreamingStatus::Panic, || {
+        assert!(!ptr.is_null());
+        let streamer = &*ptr;
+        match streamer.reader_error() {
+            Some(message) => set_last_error(DiskStreamingStatus::ReaderFailed, &message),
+            None => DiskStreamingStatus::Ok,
+        }
+    })
+}
+
+/// Passes the warnings collected since the last call to the log callback
+///
+/// Returns the number of warnings.
+#[no_mangle]
+pub unsafe extern "C" fn file_streamer_report_warnings(ptr: *mut FileStreamer) -> libc::size_t {
+    catch_panic(0, || {
+        assert!(!ptr.is_null());
+        let streamer = &mut *ptr;
+        let warnings = streamer.take_warnings();
+        for warning in &warnings {
+            log(DiskStreamingLogLevel::Warning, &warning.to_string());
+        }
+        warnings.len()
+    })
+}
+
+/// The output buffers are always filled (with zeros in case of an error)
+///
+/// The only exception is `PANIC`, then the contents of the buffers are undefined.
 #[no_mangle]
 pub unsafe extern "C" fn file_streamer_get_data(
     ptr: *mut FileStreamer,
     data: *const *mut f32,
+    frames: libc::size_t,
     rolling: bool,
-) -> bool {
+) -> DiskStreamingStatus {
+    catch_panic(DiskStreamingStatus::Panic, || {
+        get_data(ptr, data, frames, rolling)
+    })
+}
+
+/// Like `file_streamer_get_data()`, but with `double` samples
+#[no_mangle]
+pub unsafe extern "C" fn file_streamer_get_data_f64(
+    ptr: *mut FileStreamer,
+    data: *const *mut f64,
+    frames: libc::size_t,
+    rolling: bool,
+) -> DiskStreamingStatus {
+    catch_panic(DiskStreamingStatus::Panic, || {
+        get_data(ptr, data, frames, rolling)
+    })
+}
+
+/// Like `file_streamer_get_data()`, but with 16-bit integer samples (clipped)
+#[no_mangle]
+pub unsafe extern "C" fn file_streamer_get_data_i16(
+    ptr: *mut FileStreamer,
+    data: *const *mut i16,
+    frames: libc::size_t,
+    rolling: bool,
+) -> DiskStreamingStatus {
+    catch_panic(DiskStreamingStatus::Panic, || {
+        get_data(ptr, data, frames, rolling)
+    })
+}
+
+/// Like `file_streamer_get_data()`, but with 32-bit integer samples (clipped, 24-bit resolution)
+#[no_mangle]
+pub unsafe extern "C" fn file_streamer_get_data_i32(
+    ptr: *mut FileStreamer,
+    data: *const *mut i32,
+    frames: libc::size_t,
+    rolling: bool,
+) -> DiskStreamingStatus {
+    catch_panic(DiskStreamingStatus::Panic, || {
+        get_data(ptr, data, frames, rolling)
+    })
+}
+
+/// `data` must have space for `frames` times the number of channels
+#[no_mangle]
+pub unsafe extern "C" fn file_streamer_get_data_interleaved(
+    ptr: *mut FileStreamer,
+    data: *mut f32,
+    frames: libc::size_t,
+    rolling: bool,
+) -> DiskStreamingStatus {
+    catch_panic(DiskStreamingStatus::Panic, || {
+        assert!(!ptr.is_null());
+        let streamer = &mut *ptr;
+        let data = std::slice::from_raw_parts_mut(data, frames * streamer.channels());
+        if streamer.get_data_interleaved(data, rolling) {
+            DiskStreamingStatus::Ok
+        } else {
+            data_status(streamer)
+        }
+    })
+}
+
+unsafe fn get_data<S: Sample>(
+    ptr: *mut FileStreamer,
+    data: *const *mut S,
+    frames: libc::size_t,
+    rolling: bool,
+) -> DiskStreamingStatus {
     assert!(!ptr.is_null());
     let streamer = &mut *ptr;
     let data = std::slice::from_raw_parts(data, streamer.channels());
-    streamer.get_data(data, rolling)
+    if streamer.get_data(data, frames, rolling) {
+        DiskStreamingStatus::Ok
+    } else {
+        data_status(streamer)
+    }
+}
+
+fn data_status(streamer: &FileStreamer) -> DiskStreamingStatus {
+    streamer
+        .data_error()
+        .map_or(DiskStreamingStatus::OtherError, Into::into)
+}
+
+/// Return value of `false` means that the blocksize cannot be changed right now
+#[no_mangle]
+pub unsafe extern "C" fn file_streamer_set_blocksize(
+    ptr: *mut FileStreamer,
+    blocksize: libc::size_t,
+) -> bool {
+    catch_panic(false, || {
+        assert!(!ptr.is_null());
+        let streamer = &mut *ptr;
+        streamer.set_blocksize(blocksize)
+    })
+}
+
+unsafe fn to_path<'a>(path: *const libc::c_char) -> Result<&'a str, DiskStreamingStatus> {
+    CStr::from_ptr(path).to_str().map_err(|e| {
+        set_last_error(
+            DiskStreamingStatus::InvalidArgument,
+            &format!("Invalid path: {}", e),
+        )
+    })
 }
@@ -0,0 +1,45 @@
+use disk_streaming::file::Route;
+use disk_streaming::streamer::AudioFile;
+
+const BLOCKSIZE: usize = 256;
+const MAX_CHANNELS: usize = 64;
+/// Number of blocks that are read after each seek
+const BLOCKS: usize = 4;
+
+/// Seeks around and reads a few blocks each time, errors are ignored
+pub fn exercise(file: &mut dyn AudioFile, data: &[u8]) {
+    let channels = file.channels();
+    // NB: A malformed header can announce an arbitrary number of channels
+    if channels > MAX_CHANNELS {
+        return;
+    }
+    let routing: Vec<_> = (0..channels).map(|c| Route::new(c, c, 1.0)).collect();
+    let gains = vec![1.0; BLOCKSIZE];
+    let mut buffers: Vec<Box<[f32]>> = vec![vec![0.0; BLOCKSIZE].into_boxed_slice(); channels];
+    let frames = file.frames();
+    let mut positions = vec![
+        0,
+        frames / 2,
+        frames.saturating_sub(1),
+        frames,
+        frames + 1,
+        u32::MAX as usize + 1,
+    ];
+    // Some more positions taken from the input
+    positions.extend(
+        data.chunks_exact(4)
+            .take(4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize),
+    );
+    for position in positions {
+        if file.seek(position).is_err() {
+            continue;
+        }
+        for _ in 0..BLOCKS {
+            match file.fill_channels(&routing, &gains, BLOCKSIZE, 0, &mut buffers) {
+                Ok(filled) if !filled.end_of_file => {}
+                _ => break,
+            }
+        }
+    }
+}
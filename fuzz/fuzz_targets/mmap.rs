@@ -0,0 +1,23 @@
+//! Malformed WAV and RF64 files (memory-mapped)
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use disk_streaming::file::mmap;
+
+#[path = "common.rs"]
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    // NB: Only files can be mapped
+    let name = format!("disk-streaming-fuzz-{}.wav", std::process::id());
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, data).unwrap();
+    let file = mmap::File::open(&path);
+    // NB: The mapping stays valid after removing the file
+    std::fs::remove_file(&path).unwrap();
+    if let Ok(mut file) = file {
+        common::exercise(&mut file, data);
+    }
+});
@@ -0,0 +1,18 @@
+//! Malformed Ogg Vorbis files (decoded with `libvorbisfile`)
+
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+use disk_streaming::file::vorbis;
+
+#[path = "common.rs"]
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut file) = vorbis::File::new(Cursor::new(data)) {
+        common::exercise(&mut file, data);
+    }
+});
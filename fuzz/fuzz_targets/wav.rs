@@ -0,0 +1,18 @@
+//! Malformed WAV files (decoded with `hound`)
+
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+use disk_streaming::file::wav;
+
+#[path = "common.rs"]
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut file) = wav::File::new(Cursor::new(data)) {
+        common::exercise(&mut file, data);
+    }
+});
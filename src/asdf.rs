@@ -0,0 +1,594 @@
+//! Loading playlists from ASDF scene files.
+//!
+//! Only a small subset of the Audio Scene Description Format
+//! (https://github.com/AudioSceneDescriptionFormat) is supported:
+//!
+//! ```xml
+//! <asdf version="0.4">
+//!   <head>
+//!     <source id="left"/>
+//!     <source id="right"/>
+//!   </head>
+//!   <body>
+//!     <clip file="intro.wav">
+//!       <channel source="left"/>
+//!       <channel source="right"/>
+//!     </clip>
+//!     <par begin="1.5s">
+//!       <clip file="voice.ogg" duration="10s">
+//!         <channel source="left"/>
+//!       </clip>
+//!       <seq begin="2s">
+//!         <clip file="a.wav">
+//!           <channel/>
+//!           <channel source="right"/>
+//!         </clip>
+//!         <clip file="b.wav" begin="500ms">
+//!           <channel source="right"/>
+//!         </clip>
+//!       </seq>
+//!     </par>
+//!   </body>
+//! </asdf>
+//! ```
+//!
+//! Each source is an output channel (in the order of the `<source>` elements).
+//! A `<clip>` needs one `<channel>` element per file channel,
+//! file channels without `source` are not used.
+//! File paths are relative to the scene file.
+//!
+//! The elements in `<body>` and `<seq>` are played one after another,
+//! the elements in `<par>` are played at the same time.
+//! `begin` is an offset relative to the end of the previous element (in `<body>` and `<seq>`)
+//! or relative to the beginning of the enclosing `<par>`.
+//! Without `duration`, a clip is played until the end of its file.
+//!
+//! Times can be given in seconds (`1.5` or `1.5s`), as `250ms`, `2min`, `1h`
+//! or as clock values (`1:30` or `0:01:30.5`).
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use failure::{Error, Fail};
+use roxmltree::{Document, Node};
+
+use crate::streamer::{load_audio_file, PlaylistEntry, Route};
+
+pub struct Scene {
+    /// Source IDs, the output channels are in this order
+    pub sources: Vec<String>,
+    pub playlist: Vec<PlaylistEntry>,
+}
+
+#[derive(Debug)]
+pub struct AsdfError {
+    pub path: PathBuf,
+    /// Line and column (starting at 1), `None` if the file cannot be read
+    pub position: Option<(u32, u32)>,
+    pub kind: AsdfErrorKind,
+}
+
+#[derive(Debug)]
+pub enum AsdfErrorKind {
+    Io(std::io::Error),
+    Xml(roxmltree::Error),
+    Invalid(String),
+    AudioFile { file: String, error: Error },
+}
+
+impl fmt::Display for AsdfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some((line, column)) = self.position {
+            write!(f, ":{}:{}", line, column)?;
+        }
+        use AsdfErrorKind::*;
+        match self.kind {
+            Io(ref e) => write!(f, ": {}", e),
+            Xml(ref e) => write!(f, ": Invalid XML: {}", e),
+            Invalid(ref message) => write!(f, ": {}", message),
+            AudioFile {
+                ref file,
+                ref error,
+            } => write!(f, ": Cannot load \"{}\": {}", file, error),
+        }
+    }
+}
+
+impl Fail for AsdfError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        match self.kind {
+            AsdfErrorKind::Io(ref e) => Some(e),
+            AsdfErrorKind::AudioFile { ref error, .. } => Some(error.as_fail()),
+            _ => None,
+        }
+    }
+}
+
+/// Loads all audio files of the scene, they are converted to `samplerate`
+pub fn load_scene<P>(path: P, samplerate: usize) -> Result<Scene, AsdfError>
+where
+    P: AsRef<Path>,
+{
+    let parser = parse_scene(path.as_ref(), Some(samplerate))?;
+    Ok(Scene {
+        sources: parser.sources,
+        playlist: parser.playlist,
+    })
+}
+
+/// Checks the scene like `load_scene()`, but returns the paths of the audio files
+/// (in order of appearance, without duplicates) instead of loading them
+pub fn scene_files<P>(path: P) -> Result<Vec<PathBuf>, AsdfError>
+where
+    P: AsRef<Path>,
+{
+    Ok(parse_scene(path.as_ref(), None)?.files)
+}
+
+fn parse_scene(path: &Path, samplerate: Option<usize>) -> Result<ParseResult, AsdfError> {
+    let text = std::fs::read_to_string(path).map_err(|e| AsdfError {
+        path: path.into(),
+        position: None,
+        kind: AsdfErrorKind::Io(e),
+    })?;
+    let doc = Document::parse(&text).map_err(|e| AsdfError {
+        path: path.into(),
+        position: Some((e.pos().row, e.pos().col)),
+        kind: AsdfErrorKind::Xml(e),
+    })?;
+    let mut parser = Parser {
+        doc: &doc,
+        path,
+        base: path.parent().unwrap_or_else(|| Path::new("")),
+        samplerate,
+        result: ParseResult::default(),
+    };
+    parser.parse_root(doc.root_element())?;
+    Ok(parser.result)
+}
+
+#[derive(Default)]
+struct ParseResult {
+    sources: Vec<String>,
+    playlist: Vec<PlaylistEntry>,
+    files: Vec<PathBuf>,
+}
+
+struct Parser<'a, 'input> {
+    doc: &'a Document<'input>,
+    path: &'a Path,
+    /// Directory for relative file names
+    base: &'a Path,
+    /// If `None`, only the file names are collected (and time values are only checked)
+    samplerate: Option<usize>,
+    result: ParseResult,
+}
+
+impl<'a, 'input> Parser<'a, 'input> {
+    fn error(&self, position: usize, kind: AsdfErrorKind) -> AsdfError {
+        let position = self.doc.text_pos_at(position);
+        AsdfError {
+            path: self.path.into(),
+            position: Some((position.row, position.col)),
+            kind,
+        }
+    }
+
+    fn invalid<T>(&self, node: Node, message: String) -> Result<T, AsdfError> {
+        Err(self.error(node.range().start, AsdfErrorKind::Invalid(message)))
+    }
+
+    fn parse_root(&mut self, root: Node) -> Result<(), AsdfError> {
+        if root.tag_name().name() != "asdf" {
+            return self.invalid(root, "Root element must be <asdf>".into());
+        }
+        self.check_attributes(root, &["version"])?;
+        let mut body = None;
+        for child in elements(root) {
+            match child.tag_name().name() {
+                "head" if body.is_none() => self.parse_head(child)?,
+                "body" if body.is_none() => body = Some(child),
+                name => return self.invalid(child, format!("Unexpected element <{}>", name)),
+            }
+        }
+        match body {
+            Some(body) => {
+                self.check_attributes(body, &[])?;
+                self.parse_sequence(body, 0)?;
+                Ok(())
+            }
+            None => self.invalid(root, "Missing <body> element".into()),
+        }
+    }
+
+    fn parse_head(&mut self, head: Node) -> Result<(), AsdfError> {
+        self.check_attributes(head, &[])?;
+        for source in elements(head) {
+            if source.tag_name().name() != "source" {
+                let name = source.tag_name().name();
+                return self.invalid(source, format!("Unexpected element <{}> in <head>", name));
+            }
+            self.check_attributes(source, &["id", "name"])?;
+            let id = self.required_attribute(source, "id")?;
+            if self.result.sources.iter().any(|s| s == id) {
+                return self.invalid(source, format!("Duplicate source ID \"{}\"", id));
+            }
+            self.result.sources.push(id.into());
+        }
+        Ok(())
+    }
+
+    /// Returns the frame after the end of the element
+    fn parse_element(&mut self, node: Node, start: usize) -> Result<usize, AsdfError> {
+        match node.tag_name().name() {
+            "clip" => {
+                self.check_attributes(node, &["file", "begin", "duration"])?;
+                let start = start + self.time_attribute(node, "begin")?.unwrap_or(0);
+                self.parse_clip(node, start)
+            }
+            "seq" => {
+                self.check_attributes(node, &["begin"])?;
+                let start = start + self.time_attribute(node, "begin")?.unwrap_or(0);
+                self.parse_sequence(node, start)
+            }
+            "par" => {
+                self.check_attributes(node, &["begin"])?;
+                let start = start + self.time_attribute(node, "begin")?.unwrap_or(0);
+                let mut end = start;
+                for child in elements(node) {
+                    end = std::cmp::max(end, self.parse_element(child, start)?);
+                }
+                Ok(end)
+            }
+            name => self.invalid(node, format!("Unexpected element <{}>", name)),
+        }
+    }
+
+    fn parse_sequence(&mut self, node: Node, start: usize) -> Result<usize, AsdfError> {
+        let mut end = start;
+        for child in elements(node) {
+            end = self.parse_element(child, end)?;
+        }
+        Ok(end)
+    }
+
+    fn parse_clip(&mut self, clip: Node, start: usize) -> Result<usize, AsdfError> {
+        let file_name = self.required_attribute(clip, "file")?;
+        let duration = self.time_attribute(clip, "duration")?;
+        let mut channel_map = Vec::new();
+        for channel in elements(clip) {
+            if channel.tag_name().name() != "channel" {
+                let name = channel.tag_name().name();
+                return self.invalid(channel, format!("Unexpected element <{}> in <clip>", name));
+            }
+            self.check_attributes(channel, &["source"])?;
+            channel_map.push(match channel.attribute("source") {
+                Some(id) => match self.result.sources.iter().position(|s| s == id) {
+                    Some(output_channel) => Some(output_channel),
+                    None => return self.invalid(channel, format!("Unknown source \"{}\"", id)),
+                },
+                None => None,
+            });
+        }
+        let path = self.base.join(file_name);
+        if !self.result.files.contains(&path) {
+            self.result.files.push(path.clone());
+        }
+        let samplerate = match self.samplerate {
+            Some(samplerate) => samplerate,
+            // NB: Without a sample rate, all times are zero anyway
+            None => return Ok(start),
+        };
+        let file = load_audio_file(path, samplerate).map_err(|e| {
+            self.error(
+                clip.range().start,
+                AsdfErrorKind::AudioFile {
+                    file: file_name.into(),
+                    error: e,
+                },
+            )
+        })?;
+        if channel_map.len() != file.channels() {
+            return self.invalid(
+                clip,
+                format!(
+                    "\"{}\" has {} channel(s), but there are {} <channel> elements",
+                    file_name,
+                    file.channels(),
+                    channel_map.len()
+                ),
+            );
+        }
+        let end = start + duration.unwrap_or_else(|| file.frames());
+        let routing = Route::from_channel_map(&channel_map);
+        match PlaylistEntry::new(start, duration.map(|_| end), file, routing) {
+            Ok(entry) => self.result.playlist.push(entry),
+            Err(e) => return self.invalid(clip, e.to_string()),
+        }
+        Ok(end)
+    }
+
+    fn required_attribute(&self, node: Node<'a, 'input>, name: &str) -> Result<&'a str, AsdfError> {
+        match node.attribute(name) {
+            Some(value) => Ok(value),
+            None => self.invalid(node, format!("Missing attribute \"{}\"", name)),
+        }
+    }
+
+    /// Returns the time in frames
+    fn time_attribute(&self, node: Node, name: &str) -> Result<Option<usize>, AsdfError> {
+        match node.attribute_node(name) {
+            Some(attribute) => match parse_time(attribute.value()) {
+                Some(seconds) => {
+                    let samplerate = self.samplerate.unwrap_or(0);
+                    Ok(Some((seconds * samplerate as f64).round() as usize))
+                }
+                None => Err(self.error(
+                    attribute.range_value().start,
+                    AsdfErrorKind::Invalid(format!(
+                        "Invalid time value for \"{}\": \"{}\"",
+                        name,
+                        attribute.value()
+                    )),
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn check_attributes(&self, node: Node, allowed: &[&str]) -> Result<(), AsdfError> {
+        for attribute in node.attributes() {
+            if !allowed.contains(&attribute.name()) {
+                return Err(self.error(
+                    attribute.range().start,
+                    AsdfErrorKind::Invalid(format!(
+                        "Unexpected attribute \"{}\" in <{}>",
+                        attribute.name(),
+                        node.tag_name().name()
+                    )),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn elements<'a, 'input>(node: Node<'a, 'input>) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(|n| n.is_element())
+}
+
+/// Returns the time in seconds, `None` for invalid (or negative) values
+fn parse_time(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let seconds = if value.contains(':') {
+        // Clock value, e.g. "1:30" or "0:01:30.5"
+        let parts: Vec<_> = value.split(':').collect();
+        if parts.len() > 3 {
+            return None;
+        }
+        let mut seconds = 0.0;
+        for (i, part) in parts.iter().enumerate() {
+            let number: f64 = part.parse().ok()?;
+            if i < parts.len() - 1 && (number.fract() != 0.0 || number < 0.0) {
+                return None;
+            }
+            // Only the first part can have more than 59 (minutes or seconds)
+            if i > 0 && number >= 60.0 {
+                return None;
+            }
+            seconds = seconds * 60.0 + number;
+        }
+        seconds
+    } else if let Some(number) = value.strip_suffix("ms") {
+        number.trim().parse::<f64>().ok()? / 1000.0
+    } else if let Some(number) = value.strip_suffix("min") {
+        number.trim().parse::<f64>().ok()? * 60.0
+    } else if let Some(number) = value.strip_suffix('h') {
+        number.trim().parse::<f64>().ok()? * 3600.0
+    } else if let Some(number) = value.strip_suffix('s') {
+        number.trim().parse().ok()?
+    } else {
+        value.parse().ok()?
+    };
+    if seconds.is_finite() && seconds >= 0.0 {
+        Some(seconds)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLERATE: usize = 44_100;
+
+    /// Writes `text` to a temporary scene file and loads it
+    fn load(name: &str, text: &str) -> Result<Scene, AsdfError> {
+        let path = std::env::temp_dir().join(format!(
+            "disk-streaming-{}-{}.asdf",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, text).unwrap();
+        let result = load_scene(&path, SAMPLERATE);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    /// Mono, 114400 frames at 44.1 kHz
+    fn xmas() -> String {
+        concat!(env!("CARGO_MANIFEST_DIR"), "/examples/xmas.wav").into()
+    }
+
+    #[test]
+    fn valid_scene() {
+        let scene = load(
+            "valid",
+            &format!(
+                r#"<asdf version="0.4">
+  <head>
+    <source id="left"/>
+    <source id="right" name="Right"/>
+  </head>
+  <body>
+    <clip file="{0}">
+      <channel source="right"/>
+    </clip>
+    <par begin="1s">
+      <clip file="{0}" duration="0.5s">
+        <channel source="left"/>
+      </clip>
+      <seq begin="250ms">
+        <clip file="{0}" duration="1s"><channel/></clip>
+        <clip file="{0}" begin="0:01"><channel source="left"/></clip>
+      </seq>
+    </par>
+  </body>
+</asdf>"#,
+                xmas()
+            ),
+        )
+        .unwrap();
+        assert_eq!(scene.sources, ["left", "right"]);
+        let entries: Vec<_> = scene
+            .playlist
+            .iter()
+            .map(|entry| {
+                let outputs: Vec<_> = entry.routing().iter().map(|r| r.output_channel).collect();
+                (entry.start, entry.end, outputs)
+            })
+            .collect();
+        let par = 114_400 + 44_100;
+        let seq = par + 11_025;
+        assert_eq!(
+            entries,
+            [
+                (0, None, vec![1]),
+                (par, Some(par + 22_050), vec![0]),
+                (seq, Some(seq + 44_100), vec![]),
+                (seq + 2 * 44_100, None, vec![0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn files_of_scene() {
+        let text = r#"<asdf version="0.4">
+  <head><source id="one"/></head>
+  <body>
+    <clip file="b.wav" duration="1s"><channel source="one"/></clip>
+    <par>
+      <clip file="a.ogg"><channel/><channel/></clip>
+      <clip file="b.wav"><channel/></clip>
+    </par>
+  </body>
+</asdf>"#;
+        let path =
+            std::env::temp_dir().join(format!("disk-streaming-files-{}.asdf", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let files = scene_files(&path);
+        std::fs::remove_file(&path).unwrap();
+        let base = path.parent().unwrap();
+        assert_eq!(files.unwrap(), [base.join("b.wav"), base.join("a.ogg")]);
+    }
+
+    fn load_error(name: &str, body: &str) -> AsdfError {
+        let text = format!(
+            "<asdf version=\"0.4\">\n  <body>\n{}\n  </body>\n</asdf>",
+            body
+        );
+        load(name, &text).err().unwrap()
+    }
+
+    fn message(error: &AsdfError) -> String {
+        match error.kind {
+            AsdfErrorKind::Invalid(ref message) => message.clone(),
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn missing_attribute() {
+        let error = load_error("missing", "    <clip><channel/></clip>");
+        assert_eq!(message(&error), "Missing attribute \"file\"");
+        assert_eq!(error.position, Some((3, 5)));
+    }
+
+    #[test]
+    fn invalid_attributes() {
+        let error = load_error("invalid-time", r#"    <clip file="x.wav" begin="soon"/>"#);
+        assert_eq!(
+            message(&error),
+            "Invalid time value for \"begin\": \"soon\""
+        );
+        // NB: This is the position of the value
+        assert_eq!(error.position, Some((3, 31)));
+
+        let error = load_error("clock-value", r#"    <clip file="x.wav" duration="1:75"/>"#);
+        assert_eq!(
+            message(&error),
+            "Invalid time value for \"duration\": \"1:75\""
+        );
+
+        let error = load_error("unexpected", r#"    <clip file="x.wav" gain="2"/>"#);
+        assert_eq!(message(&error), "Unexpected attribute \"gain\" in <clip>");
+        assert_eq!(error.position, Some((3, 24)));
+
+        let error = load_error(
+            "source",
+            "    <clip file=\"x.wav\">\n      <channel source=\"center\"/>\n    </clip>",
+        );
+        assert_eq!(message(&error), "Unknown source \"center\"");
+        assert_eq!(error.position, Some((4, 7)));
+    }
+
+    #[test]
+    fn missing_audio_file() {
+        let error = load_error("audio", r#"    <clip file="does-not-exist.wav"/>"#);
+        match error.kind {
+            AsdfErrorKind::AudioFile { ref file, .. } => assert_eq!(file, "does-not-exist.wav"),
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn error_position_in_message() {
+        let error = load_error("position", "    <clip/>");
+        let path = error.path.display().to_string();
+        assert_eq!(
+            error.to_string(),
+            format!("{}:3:5: Missing attribute \"file\"", path)
+        );
+
+        let error = load("xml", "<asdf>\n  <body>\n</asdf>").err().unwrap();
+        assert_eq!(error.position.map(|(line, _)| line), Some(3));
+        assert!(error.to_string().contains(".asdf:3:"));
+
+        let error = load_scene("does-not-exist.asdf", SAMPLERATE).err().unwrap();
+        assert_eq!(error.position, None);
+        assert!(error.to_string().starts_with("does-not-exist.asdf: "));
+    }
+
+    #[test]
+    fn time_values() {
+        let time = |value| parse_time(value).map(|seconds| (seconds * 1000.0).round() as usize);
+        assert_eq!(time("1.5"), Some(1500));
+        assert_eq!(time("1.5s"), Some(1500));
+        assert_eq!(time("250ms"), Some(250));
+        assert_eq!(time("2min"), Some(120_000));
+        assert_eq!(time("1h"), Some(3_600_000));
+        assert_eq!(time("1:30"), Some(90_000));
+        assert_eq!(time("0:01:30.5"), Some(90_500));
+        assert_eq!(time("90:00"), Some(5_400_000));
+        assert_eq!(time("1:59.5"), Some(119_500));
+        assert_eq!(time("1:75"), None);
+        assert_eq!(time("0:00:99"), None);
+        assert_eq!(time("0:60:00"), None);
+        assert_eq!(time("-1s"), None);
+        assert_eq!(time("1.5:00"), None);
+        assert_eq!(time("1:2:3:4"), None);
+        assert_eq!(time("soon"), None);
+    }
+}
@@ -0,0 +1,329 @@
+//! Sample-accurate gain automation for playlist entries.
+//!
+//! Gain events are sent from the host to the reader thread through a lock-free queue.
+//! The reader thread applies them while filling blocks, which happens *ahead of time*:
+//! the reader thread is up to `FileStreamer::automation_latency()` frames ahead
+//! of the current playback position.
+//! Events with a time stamp before the frame that is currently being filled by the reader
+//! thread are applied immediately, i.e. too late.
+//! To be applied sample-accurately, an event has to be sent at least
+//! `automation_latency()` frames before its time stamp.
+//! All events are kept as breakpoints of the playlist entry,
+//! after seeking (and looping) the gain is re-computed from them.
+//! Events that don't change the gain (e.g. if the same gain is sent in every block)
+//! are not stored, so the memory grows only with the number of actual changes.
+
+use crossbeam::queue;
+
+/// A change of the (automated) gain of a playlist entry
+///
+/// The automated gain is applied on top of the gains of the routing matrix.
+/// It is initially `1.0`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GainEvent {
+    /// Index into the playlist
+    pub entry: usize,
+    /// Playlist frame where the change starts
+    pub frame: usize,
+    /// Length of a linear ramp from the current gain to `gain` (`0` means a jump)
+    pub ramp: usize,
+    pub gain: f32,
+}
+
+/// Sends gain events to the reader thread, see `FileStreamer::take_gain_automation()`
+///
+/// This is realtime-safe, it can be used from the audio thread.
+pub struct GainAutomation {
+    entries: usize,
+    producer: queue::spsc::Producer<GainEvent>,
+}
+
+impl GainAutomation {
+    /// Returns the event back if the queue is full or if the entry index is invalid
+    pub fn push(&mut self, event: GainEvent) -> Result<(), GainEvent> {
+        if event.entry >= self.entries {
+            return Err(event);
+        }
+        self.producer.push(event).map_err(|e| e.0)
+    }
+
+    pub fn set_gain(&mut self, entry: usize, frame: usize, gain: f32) -> Result<(), GainEvent> {
+        self.ramp_gain(entry, frame, 0, gain)
+    }
+
+    pub fn ramp_gain(
+        &mut self,
+        entry: usize,
+        frame: usize,
+        ramp: usize,
+        gain: f32,
+    ) -> Result<(), GainEvent> {
+        self.push(GainEvent {
+            entry,
+            frame,
+            ramp,
+            gain,
+        })
+    }
+}
+
+struct Ramp {
+    start: usize,
+    length: usize,
+    start_gain: f32,
+    end_gain: f32,
+}
+
+/// Gain state of one playlist entry
+struct Envelope {
+    /// All breakpoints, sorted by frame
+    events: Vec<GainEvent>,
+    /// Index of the first event that hasn't been applied yet
+    next: usize,
+    /// Frame after the one that has been computed last
+    position: usize,
+    gain: f32,
+    ramp: Option<Ramp>,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            events: Vec::new(),
+            next: 0,
+            position: 0,
+            gain: 1.0,
+            ramp: None,
+        }
+    }
+
+    /// The next call to `gain_at()` starts again from the beginning of the breakpoints
+    fn reset(&mut self) {
+        self.next = 0;
+        self.position = 0;
+        self.gain = 1.0;
+        self.ramp = None;
+    }
+
+    fn insert(&mut self, event: GainEvent) {
+        // NB: New events are typically later than all stored events
+        let index = self
+            .events
+            .iter()
+            .rposition(|e| e.frame <= event.frame)
+            .map_or(0, |i| i + 1);
+        // The gain at event.frame only depends on the event before it
+        let unchanged = match index.checked_sub(1).map(|i| self.events[i]) {
+            Some(before) => before.gain == event.gain && before.frame + before.ramp <= event.frame,
+            None => event.gain == 1.0,
+        };
+        if unchanged {
+            return;
+        }
+        self.events.insert(index, event);
+        if index < self.next {
+            // The frame has already been computed, the event is applied too late
+            self.next += 1;
+            self.start_ramp(self.position, event);
+        }
+    }
+
+    /// `frame` must not be smaller than in the previous call (unless `reset()` was called)
+    fn gain_at(&mut self, frame: usize) -> f32 {
+        while let Some(&event) = self.events.get(self.next) {
+            if event.frame > frame {
+                break;
+            }
+            self.start_ramp(event.frame, event);
+            self.next += 1;
+        }
+        self.position = frame + 1;
+        self.current_gain(frame)
+    }
+
+    fn start_ramp(&mut self, start: usize, event: GainEvent) {
+        self.ramp = Some(Ramp {
+            start,
+            length: event.ramp,
+            start_gain: self.current_gain(start),
+            end_gain: event.gain,
+        });
+    }
+
+    fn current_gain(&mut self, frame: usize) -> f32 {
+        if let Some(ref ramp) = self.ramp {
+            let elapsed = frame.saturating_sub(ramp.start);
+            if elapsed >= ramp.length {
+                self.gain = ramp.end_gain;
+                self.ramp = None;
+            } else {
+                return ramp.start_gain
+                    + (ramp.end_gain - ramp.start_gain) * elapsed as f32 / ramp.length as f32;
+            }
+        }
+        self.gain
+    }
+}
+
+/// Reader thread side of the gain automation
+pub(crate) struct GainEnvelopes {
+    consumer: queue::spsc::Consumer<GainEvent>,
+    envelopes: Box<[Envelope]>,
+}
+
+pub(crate) fn make_gain_automation(
+    entries: usize,
+    capacity: usize,
+) -> (GainAutomation, GainEnvelopes) {
+    let (producer, consumer) = queue::spsc::new(capacity);
+    (
+        GainAutomation { entries, producer },
+        GainEnvelopes {
+            consumer,
+            envelopes: (0..entries).map(|_| Envelope::new()).collect(),
+        },
+    )
+}
+
+impl GainEnvelopes {
+    /// Takes all events from the queue
+    pub(crate) fn update(&mut self) {
+        while let Ok(event) = self.consumer.pop() {
+            self.envelopes[event.entry].insert(event);
+        }
+    }
+
+    /// Must be called before filling gains at an earlier frame (e.g. after seeking or looping)
+    pub(crate) fn reset(&mut self) {
+        for envelope in self.envelopes.iter_mut() {
+            envelope.reset();
+        }
+    }
+
+    /// Writes the gains of the given `entry`, starting at the playlist frame `frame`
+    pub(crate) fn fill_gains(&mut self, entry: usize, frame: usize, gains: &mut [f32]) {
+        let envelope = &mut self.envelopes[entry];
+        for (i, gain) in gains.iter_mut().enumerate() {
+            *gain = envelope.gain_at(frame + i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gain automation for one playlist entry with the given events
+    fn envelopes(events: &[(usize, usize, f32)]) -> GainEnvelopes {
+        let (mut automation, mut envelopes) = make_gain_automation(1, 16);
+        for &(frame, ramp, gain) in events {
+            automation.ramp_gain(0, frame, ramp, gain).unwrap();
+        }
+        envelopes.update();
+        envelopes
+    }
+
+    fn fill(envelopes: &mut GainEnvelopes, frame: usize, frames: usize) -> Vec<f32> {
+        let mut gains = vec![0.0; frames];
+        envelopes.fill_gains(0, frame, &mut gains);
+        gains
+    }
+
+    const EVENTS: &[(usize, usize, f32)] = &[(10, 0, 0.5), (20, 10, 1.0), (40, 4, 0.0)];
+
+    #[test]
+    fn gains_at_breakpoints() {
+        let gains = fill(&mut envelopes(EVENTS), 0, 50);
+        assert_eq!(gains[9], 1.0);
+        assert_eq!(gains[10], 0.5);
+        assert_eq!(gains[20], 0.5);
+        assert_eq!(gains[25], 0.75);
+        assert_eq!(gains[30], 1.0);
+        assert_eq!(gains[40], 1.0);
+        assert_eq!(gains[42], 0.5);
+        assert_eq!(gains[44], 0.0);
+        assert_eq!(gains[49], 0.0);
+    }
+
+    #[test]
+    fn block_boundaries_dont_matter() {
+        let expected = fill(&mut envelopes(EVENTS), 0, 50);
+        for &blocksize in &[1, 3, 7, 16, 25] {
+            let mut envelopes = envelopes(EVENTS);
+            let mut gains = Vec::new();
+            while gains.len() < 50 {
+                gains.extend(fill(&mut envelopes, gains.len(), blocksize));
+            }
+            assert_eq!(&gains[..50], &expected[..], "blocksize: {}", blocksize);
+        }
+    }
+
+    #[test]
+    fn interrupted_ramp() {
+        // The second ramp starts from the current value of the first one
+        let gains = fill(&mut envelopes(&[(0, 10, 0.0), (5, 10, 1.0)]), 0, 20);
+        assert_eq!(gains[5], 0.5);
+        assert_eq!(gains[10], 0.75);
+        assert_eq!(gains[15], 1.0);
+    }
+
+    #[test]
+    fn reset_after_seeking() {
+        let expected = fill(&mut envelopes(EVENTS), 0, 50);
+        let mut envelopes = envelopes(EVENTS);
+        assert_eq!(fill(&mut envelopes, 0, 26)[25], 0.75);
+        // The gains are re-computed from all breakpoints
+        envelopes.reset();
+        assert_eq!(fill(&mut envelopes, 5, 45), &expected[5..]);
+        envelopes.reset();
+        assert_eq!(fill(&mut envelopes, 25, 25), &expected[25..]);
+        envelopes.reset();
+        assert_eq!(fill(&mut envelopes, 0, 50), expected);
+    }
+
+    #[test]
+    fn event_before_applied_events() {
+        let (mut automation, mut envelopes) = make_gain_automation(1, 16);
+        automation.set_gain(0, 10, 0.5).unwrap();
+        envelopes.update();
+        assert_eq!(fill(&mut envelopes, 0, 20)[15], 0.5);
+        // NB: This is applied too late
+        automation.set_gain(0, 5, 0.25).unwrap();
+        envelopes.update();
+        assert_eq!(fill(&mut envelopes, 20, 1), [0.25]);
+        // After seeking, it is applied at the right frame
+        envelopes.reset();
+        let gains = fill(&mut envelopes, 0, 20);
+        assert_eq!(gains[..5], [1.0; 5]);
+        assert_eq!(gains[5..10], [0.25; 5]);
+        assert_eq!(gains[10..], [0.5; 10]);
+    }
+
+    #[test]
+    fn unchanged_gains_are_not_stored() {
+        let (mut automation, mut envelopes) = make_gain_automation(1, 16);
+        let blocksize = 64;
+        let latency = 4 * blocksize;
+        let mut gains = vec![0.0; blocksize];
+        // The gain is sent in every block, but it only changes every 100 blocks
+        for block in 0..1000 {
+            let frame = block * blocksize;
+            let gain = (block / 100) as f32 / 10.0;
+            automation.set_gain(0, frame + latency, gain).unwrap();
+            envelopes.update();
+            envelopes.fill_gains(0, frame, &mut gains);
+            if block % 250 == 249 {
+                envelopes.reset();
+            }
+        }
+        assert_eq!(envelopes.envelopes[0].events.len(), 10);
+        assert_eq!(gains[0], 0.9);
+        // Sending the same gains again (e.g. after seeking) doesn't add any events
+        automation
+            .set_gain(0, 5 * 100 * blocksize + latency, 0.5)
+            .unwrap();
+        automation.ramp_gain(0, 0, 100, 1.0).unwrap();
+        envelopes.update();
+        assert_eq!(envelopes.envelopes[0].events.len(), 10);
+    }
+}
@@ -0,0 +1,255 @@
+//! Plays a playlist via JACK, following the JACK transport.
+//!
+//! Usage: disk-streaming-jack [--name NAME] [--connect] PLAYLIST
+//!
+//! The playlist is either a playlist description (`.json` or `.toml`) or an ASDF scene
+//! (`.asdf`), one output port is created for each playlist channel.
+//! With `--connect`, the output ports are connected to the system playback ports.
+//!
+//! This is a slow-sync client: when the transport is relocated,
+//! JACK waits until the data for the new position is available.
+//!
+//! It can be tried without an audio interface using JACK's dummy backend:
+//!
+//!     jackd -d dummy -r 44100 -p 1024 &
+//!     disk-streaming-jack examples/scene.asdf &
+//!     jack_transport  # "locate 44100", "play", "stop", ...
+
+use std::io::BufRead;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use failure::{bail, Error};
+
+use disk_streaming::asdf::load_scene;
+use disk_streaming::description::PlaylistDescription;
+use disk_streaming::streamer::{DataError, FileStreamer, PlaylistEntry, State};
+
+/// Counters for events in the process callback, reported by the main thread
+#[derive(Default)]
+struct Counters {
+    underruns: AtomicUsize,
+    /// The transport was rolling while the data wasn't ready (e.g. after a sync timeout)
+    not_ready: AtomicUsize,
+}
+
+struct Player {
+    streamer: FileStreamer,
+    ports: Vec<jack::Port<jack::AudioOut>>,
+    /// Pre-allocated to avoid allocations in the process callback
+    pointers: Vec<*mut f32>,
+    counters: Arc<Counters>,
+    /// New blocksize that couldn't be applied yet, see `buffer_size()`
+    pending_blocksize: Option<usize>,
+}
+
+// NB: The pointers are only used within the process callback
+unsafe impl Send for Player {}
+
+impl jack::ProcessHandler for Player {
+    const SLOW_SYNC: bool = true;
+
+    fn process(&mut self, client: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        // NB: Relocations are handled in sync()
+        let rolling = match client.transport().query_state() {
+            Ok(state) => state == jack::TransportState::Rolling,
+            Err(_) => false,
+        };
+        if let Some(blocksize) = self.pending_blocksize {
+            // NB: This allocates, but only once, while the transport is stopped
+            if !rolling && self.streamer.set_blocksize(blocksize) {
+                self.pending_blocksize = None;
+            }
+        }
+        self.pointers.clear();
+        for port in &mut self.ports {
+            self.pointers.push(port.as_mut_slice(ps).as_mut_ptr());
+        }
+        let frames = ps.n_frames() as usize;
+        if !unsafe { self.streamer.get_data(&self.pointers, frames, rolling) } {
+            match self.streamer.data_error() {
+                Some(DataError::Underrun) => {
+                    self.counters.underruns.fetch_add(1, Ordering::Relaxed);
+                }
+                Some(DataError::NotReady) if rolling => {
+                    self.counters.not_ready.fetch_add(1, Ordering::Relaxed);
+                }
+                Some(DataError::ReaderFailed) => return jack::Control::Quit,
+                _ => {}
+            }
+        }
+        jack::Control::Continue
+    }
+
+    /// NB: This is called on the process thread, but it doesn't have to be realtime-safe
+    /// (JACK doesn't call process() in the meantime), therefore `set_blocksize()`
+    /// is allowed to allocate the new data queue here.
+    ///
+    /// While the transport is rolling (or seeking), the blocksize cannot be changed.
+    /// The new size is applied in process() as soon as the transport has stopped,
+    /// until then, `get_data()` is called with the new number of frames
+    /// (which only works up to `max_frames()`).
+    fn buffer_size(&mut self, _: &jack::Client, size: jack::Frames) -> jack::Control {
+        let size = size as usize;
+        self.pending_blocksize = None;
+        if size == self.streamer.blocksize() || self.streamer.set_blocksize(size) {
+            return jack::Control::Continue;
+        }
+        if size <= self.streamer.max_frames() {
+            eprintln!(
+                "Warning: the blocksize will be changed to {} when the transport stops",
+                size
+            );
+            self.pending_blocksize = Some(size);
+            jack::Control::Continue
+        } else {
+            eprintln!(
+                "Error: the blocksize cannot be changed to {} while the transport is rolling \
+                 or seeking, and it is larger than the maximum of {} frames",
+                size,
+                self.streamer.max_frames()
+            );
+            jack::Control::Quit
+        }
+    }
+
+    /// Called while the transport is starting and after each relocation
+    fn sync(
+        &mut self,
+        _: &jack::Client,
+        _state: jack::TransportState,
+        pos: &jack::TransportPosition,
+    ) -> bool {
+        self.streamer.is_ready(pos.frame() as usize)
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let mut name = "disk-streaming".to_string();
+    let mut connect = false;
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => match args.next() {
+                Some(value) => name = value,
+                None => bail!("Missing value for --name"),
+            },
+            "--connect" => connect = true,
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 1 {
+        bail!("Usage: disk-streaming-jack [--name NAME] [--connect] PLAYLIST");
+    }
+
+    let (client, _status) = jack::Client::new(&name, jack::ClientOptions::NO_START_SERVER)?;
+    let samplerate = client.sample_rate();
+    let blocksize = client.buffer_size() as usize;
+    let (playlist, channels) = load_playlist(&paths[0], samplerate)?;
+    let mut ports = Vec::with_capacity(channels);
+    for i in 0..channels {
+        ports.push(client.register_port(&format!("out_{}", i + 1), jack::AudioOut)?);
+    }
+    let port_names: Vec<_> = ports
+        .iter()
+        .map(|port| port.name())
+        .collect::<Result<_, _>>()?;
+
+    let streamer = FileStreamer::new(playlist, blocksize, channels)?;
+    let transport = streamer.transport();
+    println!(
+        "{} channels, {} frames at {} Hz",
+        channels,
+        streamer.duration(),
+        samplerate
+    );
+    let counters = Arc::new(Counters::default());
+    let player = Player {
+        streamer,
+        ports,
+        pointers: Vec::with_capacity(channels),
+        counters: Arc::clone(&counters),
+        pending_blocksize: None,
+    };
+    let client = client.activate_async((), player)?;
+
+    if connect {
+        let playback = client.as_client().ports(
+            Some("system:playback_.*"),
+            None,
+            jack::PortFlags::IS_INPUT | jack::PortFlags::IS_PHYSICAL,
+        );
+        for (source, destination) in port_names.iter().zip(&playback) {
+            client
+                .as_client()
+                .connect_ports_by_name(source, destination)?;
+        }
+    }
+
+    // The main thread only reports what's going on
+    let (quit_sender, quit_receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        println!("Press Enter to quit");
+        let _ = std::io::stdin().lock().lines().next();
+        let _ = quit_sender.send(());
+    });
+    let mut previous_state = None;
+    let mut underruns = 0;
+    let mut not_ready = 0;
+    while quit_receiver
+        .recv_timeout(Duration::from_millis(100))
+        .is_err()
+    {
+        let state = transport.state();
+        if previous_state != Some(state) {
+            println!("{:?} at frame {}", state, transport.position());
+            previous_state = Some(state);
+        }
+        if state == State::Failed {
+            bail!(
+                "Reader failed: {}",
+                transport.reader_error().unwrap_or_default()
+            );
+        }
+        for warning in transport.take_warnings() {
+            eprintln!("Warning: {}", warning);
+        }
+        let count = counters.underruns.load(Ordering::Relaxed);
+        if count != underruns {
+            println!(
+                "{} underrun(s) at frame {}",
+                count - underruns,
+                transport.position()
+            );
+            underruns = count;
+        }
+        let count = counters.not_ready.load(Ordering::Relaxed);
+        if count != not_ready {
+            println!("Rolling but not ready (sync timeout?)");
+            not_ready = count;
+        }
+    }
+    client.deactivate()?;
+    Ok(())
+}
+
+/// Returns the playlist and the number of output channels
+fn load_playlist(path: &str, samplerate: usize) -> Result<(Vec<PlaylistEntry>, usize), Error> {
+    if path.ends_with(".asdf") {
+        let scene = load_scene(path, samplerate)?;
+        Ok((scene.playlist, scene.sources.len()))
+    } else {
+        let description = PlaylistDescription::load(path)?;
+        if description.samplerate != samplerate {
+            bail!(
+                "The playlist uses a sample rate of {} Hz, but JACK is running at {} Hz",
+                description.samplerate,
+                samplerate
+            );
+        }
+        Ok((description.to_playlist()?, description.channels))
+    }
+}
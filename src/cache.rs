@@ -0,0 +1,333 @@
+//! A shared cache of decoded audio data for short files.
+//!
+//! Files that are used many times in a playlist (e.g. short sound effects)
+//! are decoded only once and then served from memory.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use crate::file::memory;
+use crate::streamer::{load_audio_file, AudioFile};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of loads that were served from the cache
+    pub hits: usize,
+    /// Number of loads that were not served from the cache (including files that are too long)
+    pub misses: usize,
+    /// Number of cache entries that have been removed to make room for new ones
+    pub evictions: usize,
+    /// Current number of cache entries
+    pub entries: usize,
+    /// Current size of the cache entries in bytes
+    pub bytes: usize,
+}
+
+/// A size-bounded cache with least-recently-used eviction
+///
+/// Cloning the cache creates a new handle to the same shared cache.
+/// Evicted data stays in memory as long as it is still used by a playlist entry.
+#[derive(Clone)]
+pub struct AudioCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    max_bytes: usize,
+    max_frames: usize,
+    entries: HashMap<(PathBuf, usize), Entry>,
+    /// Incremented on each access, used for LRU eviction
+    clock: u64,
+    stats: CacheStats,
+}
+
+struct Entry {
+    data: Arc<memory::Data>,
+    last_used: u64,
+}
+
+impl AudioCache {
+    /// Files with up to `max_frames` frames (after sample rate conversion) are cached,
+    /// the total size of the cache is limited to `max_bytes`.
+    pub fn new(max_bytes: usize, max_frames: usize) -> AudioCache {
+        AudioCache {
+            inner: Arc::new(Mutex::new(Inner {
+                max_bytes,
+                max_frames,
+                entries: HashMap::new(),
+                clock: 0,
+                stats: CacheStats::default(),
+            })),
+        }
+    }
+
+    /// Like `load_audio_file()`, but short files are served from memory
+    pub fn load<P>(&self, path: P, samplerate: usize) -> Result<Box<dyn AudioFile + Send>, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let key = (path.as_ref().to_path_buf(), samplerate);
+        let (max_frames, max_bytes) = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(data) = inner.get(&key) {
+                return Ok(Box::new(memory::File::new(data)));
+            }
+            inner.stats.misses += 1;
+            (inner.max_frames, inner.max_bytes)
+        };
+        let mut file = load_audio_file(&path, samplerate)?;
+        if file.frames() > max_frames || size(&*file) > max_bytes {
+            return Ok(file);
+        }
+        // NB: The lock is not held while decoding
+        let data = Arc::new(memory::Data::decode(&mut *file)?);
+        self.inner.lock().unwrap().insert(key, Arc::clone(&data));
+        Ok(Box::new(memory::File::new(data)))
+    }
+
+    /// Number of bytes that loading the file would add to the cache
+    ///
+    /// Only the header of the file is read.
+    /// Returns `None` if the file is too long to be cached.
+    pub fn estimate<P>(&self, path: P, samplerate: usize) -> Result<Option<usize>, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let key = (path.as_ref().to_path_buf(), samplerate);
+        if self.inner.lock().unwrap().entries.contains_key(&key) {
+            return Ok(Some(0));
+        }
+        let file = load_audio_file(&path, samplerate)?;
+        let inner = self.inner.lock().unwrap();
+        let size = size(&*file);
+        if file.frames() > inner.max_frames || size > inner.max_bytes {
+            Ok(None)
+        } else {
+            Ok(Some(size))
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats
+    }
+
+    /// Removes all entries (statistics are kept)
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.stats.entries = 0;
+        inner.stats.bytes = 0;
+    }
+}
+
+impl Inner {
+    fn get(&mut self, key: &(PathBuf, usize)) -> Option<Arc<memory::Data>> {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                self.stats.hits += 1;
+                Some(Arc::clone(&entry.data))
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: (PathBuf, usize), data: Arc<memory::Data>) {
+        if self.entries.contains_key(&key) {
+            // Another thread was faster
+            return;
+        }
+        let size = data.size();
+        while self.stats.bytes + size > self.max_bytes {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+                .expect("The cache cannot be empty here");
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.stats.bytes -= entry.data.size();
+            self.stats.entries -= 1;
+            self.stats.evictions += 1;
+        }
+        self.clock += 1;
+        self.entries.insert(
+            key,
+            Entry {
+                data,
+                last_used: self.clock,
+            },
+        );
+        self.stats.bytes += size;
+        self.stats.entries += 1;
+    }
+}
+
+fn size(file: &dyn AudioFile) -> usize {
+    file.channels() * file.frames() * std::mem::size_of::<f32>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLERATE: usize = 44_100;
+
+    /// Mono WAV files in a temporary directory, which is removed on drop
+    struct Fixtures(PathBuf);
+
+    impl Fixtures {
+        fn new(name: &str) -> Fixtures {
+            let path = std::env::temp_dir().join(format!(
+                "disk-streaming-cache-{}-{}",
+                name,
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            Fixtures(path)
+        }
+
+        /// Each frame takes 4 bytes in the cache
+        fn file(&self, name: &str, frames: usize) -> PathBuf {
+            let path = self.0.join(name);
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate: SAMPLERATE as u32,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            for frame in 0..frames {
+                writer.write_sample(frame as i16).unwrap();
+            }
+            writer.finalize().unwrap();
+            path
+        }
+    }
+
+    impl Drop for Fixtures {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn stats(hits: usize, misses: usize, evictions: usize, entries: usize) -> CacheStats {
+        CacheStats {
+            hits,
+            misses,
+            evictions,
+            entries,
+            bytes: entries * 4000,
+        }
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let fixtures = Fixtures::new("hits");
+        let a = fixtures.file("a.wav", 1000);
+        let b = fixtures.file("b.wav", 1000);
+        let cache = AudioCache::new(100_000, 1000);
+        let mut file = cache.load(&a, SAMPLERATE).unwrap();
+        assert_eq!(file.frames(), 1000);
+        assert_eq!(cache.stats(), stats(0, 1, 0, 1));
+        cache.load(&a, SAMPLERATE).unwrap();
+        cache.load(&b, SAMPLERATE).unwrap();
+        cache.load(&a, SAMPLERATE).unwrap();
+        assert_eq!(cache.stats(), stats(2, 2, 0, 2));
+        // The cached data is decoded correctly
+        let data = memory::Data::decode(&mut *file).unwrap();
+        assert_eq!(data.channel(0)[999], 999.0 / i16::MAX as f32);
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let fixtures = Fixtures::new("lru");
+        let paths: Vec<_> = ["a.wav", "b.wav", "c.wav", "d.wav"]
+            .iter()
+            .map(|name| fixtures.file(name, 1000))
+            .collect();
+        let cache = AudioCache::new(3 * 4000, 1000);
+        for path in &paths[..3] {
+            cache.load(path, SAMPLERATE).unwrap();
+        }
+        // "a" becomes the most recently used entry
+        cache.load(&paths[0], SAMPLERATE).unwrap();
+        cache.load(&paths[3], SAMPLERATE).unwrap();
+        assert_eq!(cache.stats(), stats(1, 4, 1, 3));
+        // "b" was evicted, this evicts "c"
+        cache.load(&paths[1], SAMPLERATE).unwrap();
+        assert_eq!(cache.stats(), stats(1, 5, 2, 3));
+        cache.load(&paths[0], SAMPLERATE).unwrap();
+        cache.load(&paths[3], SAMPLERATE).unwrap();
+        assert_eq!(cache.stats(), stats(3, 5, 2, 3));
+        cache.load(&paths[2], SAMPLERATE).unwrap();
+        assert_eq!(cache.stats(), stats(3, 6, 3, 3));
+    }
+
+    #[test]
+    fn large_files_bypass_the_cache() {
+        let fixtures = Fixtures::new("bypass");
+        let long = fixtures.file("long.wav", 1001);
+        let short = fixtures.file("short.wav", 1000);
+        let cache = AudioCache::new(100_000, 1000);
+        for _ in 0..2 {
+            assert_eq!(cache.load(&long, SAMPLERATE).unwrap().frames(), 1001);
+        }
+        assert_eq!(cache.stats(), stats(0, 2, 0, 0));
+
+        let cache = AudioCache::new(3999, 1000);
+        for _ in 0..2 {
+            assert_eq!(cache.load(&short, SAMPLERATE).unwrap().frames(), 1000);
+        }
+        assert_eq!(cache.stats(), stats(0, 2, 0, 0));
+    }
+
+    #[test]
+    fn clear_keeps_statistics() {
+        let fixtures = Fixtures::new("clear");
+        let a = fixtures.file("a.wav", 1000);
+        let cache = AudioCache::new(100_000, 1000);
+        let handle = cache.clone();
+        cache.load(&a, SAMPLERATE).unwrap();
+        cache.load(&a, SAMPLERATE).unwrap();
+        handle.clear();
+        assert_eq!(cache.stats(), stats(1, 1, 0, 0));
+        cache.load(&a, SAMPLERATE).unwrap();
+        assert_eq!(cache.stats(), stats(1, 2, 0, 1));
+    }
+
+    #[test]
+    fn concurrent_insert_is_not_counted() {
+        let cache = AudioCache::new(100_000, 1000);
+        let data = Arc::new(memory::Data::from_channels(
+            SAMPLERATE,
+            vec![vec![0.0; 1000]],
+        ));
+        let key = (PathBuf::from("a.wav"), SAMPLERATE);
+        let mut inner = cache.inner.lock().unwrap();
+        inner.insert(key.clone(), Arc::clone(&data));
+        inner.insert(key, data);
+        assert_eq!(inner.stats, stats(0, 0, 0, 1));
+    }
+
+    #[test]
+    fn estimate() {
+        let fixtures = Fixtures::new("estimate");
+        let short = fixtures.file("short.wav", 1000);
+        let long = fixtures.file("long.wav", 1001);
+        let cache = AudioCache::new(100_000, 1000);
+        assert_eq!(cache.estimate(&short, SAMPLERATE).unwrap(), Some(4000));
+        assert_eq!(cache.estimate(&long, SAMPLERATE).unwrap(), None);
+        cache.load(&short, SAMPLERATE).unwrap();
+        assert_eq!(cache.estimate(&short, SAMPLERATE).unwrap(), Some(0));
+        assert!(cache
+            .estimate(fixtures.0.join("missing.wav"), SAMPLERATE)
+            .is_err());
+        // Estimating doesn't change the statistics
+        assert_eq!(cache.stats(), stats(0, 1, 0, 1));
+    }
+}
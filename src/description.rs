@@ -0,0 +1,670 @@
+//! A serializable playlist format (JSON or TOML).
+//!
+//! All frame numbers are given at the sample rate of the description,
+//! the audio files are converted to this sample rate.
+//!
+//! ```toml
+//! samplerate = 44100
+//! channels = 2
+//!
+//! [[entries]]
+//! path = "marimba.ogg"
+//! start = 0
+//! channel_map = [0, 1]
+//!
+//! [[entries]]
+//! path = "xmas.wav"
+//! start = 88200
+//! end = 176400
+//! file_offset = 44100
+//! channel_map = [1]
+//! gain = 0.5
+//! fade_in = 4410
+//! fade_out = 22050
+//! ```
+//!
+//! The channel map contains one output channel for each file channel,
+//! negative values mean that the file channel is not used.
+//! Entries that overlap on the same output channel are mixed.
+//! Relative paths are relative to the description file (see `load()`).
+
+// NB: For #[derive(Fail)], see src/streamer.rs
+#![allow(unknown_lints, non_local_definitions)]
+
+use std::path::{Path, PathBuf};
+
+use failure::{bail, Error, Fail};
+use serde::{Deserialize, Serialize};
+
+use crate::file::Route;
+use crate::streamer::{
+    load_audio_file, load_audio_file_native, AudioFile, FileStreamer, PlaylistEntry,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlaylistDescription {
+    pub samplerate: usize,
+    /// Number of output channels
+    pub channels: usize,
+    #[serde(default)]
+    pub entries: Vec<EntryDescription>,
+    /// Relative paths are relative to this directory
+    #[serde(skip)]
+    directory: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EntryDescription {
+    pub path: PathBuf,
+    pub start: usize,
+    /// Without `end`, the entry ends at the end of the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<usize>,
+    /// Frame of the (converted) file that is played at `start`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub file_offset: usize,
+    #[serde(with = "channel_map")]
+    pub channel_map: Vec<Option<usize>>,
+    #[serde(default = "unity", skip_serializing_if = "is_unity")]
+    pub gain: f32,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fade_in: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fade_out: usize,
+}
+
+/// Returned by `PlaylistDescription::validate()`
+#[derive(Debug)]
+pub struct ValidationError {
+    /// All problems that were found, there is at least one
+    pub problems: Vec<Problem>,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Invalid playlist description:")?;
+        for problem in &self.problems {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Fail for ValidationError {}
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum Problem {
+    #[fail(display = "The sample rate must not be zero")]
+    ZeroSamplerate,
+    #[fail(display = "There must be at least one output channel")]
+    NoChannels,
+    #[fail(display = "Entry {}: {}", entry, message)]
+    File { entry: usize, message: String },
+    #[fail(
+        display = "Entry {}: end ({}) must be after start ({})",
+        entry, end, start
+    )]
+    EndBeforeStart {
+        entry: usize,
+        start: usize,
+        end: usize,
+    },
+    #[fail(
+        display = "Entry {}: the channel map has {} channel(s), but the file has {}",
+        entry, map, file
+    )]
+    ChannelCount {
+        entry: usize,
+        map: usize,
+        file: usize,
+    },
+    #[fail(
+        display = "Entry {}: invalid output channel {} (there are {} channels)",
+        entry, channel, channels
+    )]
+    OutputChannel {
+        entry: usize,
+        channel: usize,
+        channels: usize,
+    },
+    #[fail(
+        display = "Entry {}: file offset {} is not before the end of the file ({} frames)",
+        entry, offset, frames
+    )]
+    FileOffset {
+        entry: usize,
+        offset: usize,
+        frames: usize,
+    },
+    #[fail(
+        display = "Entry {}: the fades ({} + {} frames) are longer than the entry ({} frames)",
+        entry, fade_in, fade_out, frames
+    )]
+    Fades {
+        entry: usize,
+        fade_in: usize,
+        fade_out: usize,
+        frames: usize,
+    },
+}
+
+/// Returned by `ValidPlaylist::overlaps()`
+///
+/// This is not an error, overlapping entries are mixed (e.g. for crossfades).
+#[derive(Clone, Debug, Fail, PartialEq)]
+#[fail(
+    display = "Entries {} and {} overlap on output channel {} (frames {} to {})",
+    first, second, channel, start, end
+)]
+pub struct Overlap {
+    pub first: usize,
+    pub second: usize,
+    pub channel: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Returned by `PlaylistDescription::validate()`, contains the loaded files
+pub struct ValidPlaylist<'a> {
+    entries: &'a [EntryDescription],
+    files: Vec<Box<dyn AudioFile + Send>>,
+    /// Playlist range of each entry
+    ranges: Vec<(usize, usize)>,
+}
+
+impl<'a> ValidPlaylist<'a> {
+    /// Returns entries that overlap on the same output channel
+    pub fn overlaps(&self) -> Vec<Overlap> {
+        let mut overlaps = Vec::new();
+        for (first, a) in self.entries.iter().enumerate() {
+            for (second, b) in self.entries.iter().enumerate().skip(first + 1) {
+                let (r1, r2) = (self.ranges[first], self.ranges[second]);
+                let (start, end) = (r1.0.max(r2.0), r1.1.min(r2.1));
+                if start >= end {
+                    continue;
+                }
+                let channel = a
+                    .channel_map
+                    .iter()
+                    .flatten()
+                    .find(|channel| b.channel_map.contains(&Some(**channel)));
+                if let Some(&channel) = channel {
+                    overlaps.push(Overlap {
+                        first,
+                        second,
+                        channel,
+                        start,
+                        end,
+                    });
+                }
+            }
+        }
+        overlaps
+    }
+
+    /// Creates playlist entries from the already loaded files
+    pub fn into_playlist(self) -> Result<Vec<PlaylistEntry>, Error> {
+        let mut playlist = Vec::with_capacity(self.entries.len());
+        for (entry, file) in self.entries.iter().zip(self.files) {
+            let routing = entry
+                .channel_map
+                .iter()
+                .enumerate()
+                .filter_map(|(file_channel, output_channel)| {
+                    output_channel
+                        .map(|output_channel| Route::new(file_channel, output_channel, entry.gain))
+                })
+                .collect();
+            let mut playlist_entry = PlaylistEntry::new(entry.start, entry.end, file, routing)?;
+            playlist_entry.file_offset = entry.file_offset;
+            playlist_entry.fade_in = entry.fade_in;
+            playlist_entry.fade_out = entry.fade_out;
+            playlist.push(playlist_entry);
+        }
+        Ok(playlist)
+    }
+}
+
+impl EntryDescription {
+    /// Without end, file offset and fades and with a gain of 1
+    pub fn new<P>(path: P, start: usize, channel_map: Vec<Option<usize>>) -> EntryDescription
+    where
+        P: Into<PathBuf>,
+    {
+        EntryDescription {
+            path: path.into(),
+            start,
+            end: None,
+            file_offset: 0,
+            channel_map,
+            gain: 1.0,
+            fade_in: 0,
+            fade_out: 0,
+        }
+    }
+}
+
+impl PlaylistDescription {
+    pub fn new(samplerate: usize, channels: usize) -> PlaylistDescription {
+        PlaylistDescription {
+            samplerate,
+            channels,
+            entries: Vec::new(),
+            directory: None,
+        }
+    }
+
+    pub fn from_json(text: &str) -> Result<PlaylistDescription, Error> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_toml(text: &str) -> Result<PlaylistDescription, Error> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String, Error> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// The format is chosen by the file extension (`.json` or `.toml`)
+    ///
+    /// Relative paths in the file are relative to the directory of `path`.
+    pub fn load<P>(path: P) -> Result<PlaylistDescription, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let mut description = match extension(path) {
+            Some("json") => PlaylistDescription::from_json(&text)?,
+            Some("toml") => PlaylistDescription::from_toml(&text)?,
+            _ => bail!("Unknown playlist format: {}", path.display()),
+        };
+        description.directory = path.parent().map(Into::into);
+        Ok(description)
+    }
+
+    /// The format is chosen by the file extension (`.json` or `.toml`)
+    pub fn save<P>(&self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let text = match extension(path) {
+            Some("json") => self.to_json()?,
+            Some("toml") => self.to_toml()?,
+            _ => bail!("Unknown playlist format: {}", path.display()),
+        };
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Checks the description, all files are loaded
+    ///
+    /// The loaded files are used by `ValidPlaylist::into_playlist()`, therefore each file
+    /// is only opened once.
+    /// Overlapping entries are allowed, see `ValidPlaylist::overlaps()`.
+    pub fn validate(&self) -> Result<ValidPlaylist, ValidationError> {
+        let mut problems = Vec::new();
+        if self.samplerate == 0 {
+            problems.push(Problem::ZeroSamplerate);
+        }
+        if self.channels == 0 {
+            problems.push(Problem::NoChannels);
+        }
+        let mut files = Vec::with_capacity(self.entries.len());
+        let mut ranges = Vec::with_capacity(self.entries.len());
+        for (index, entry) in self.entries.iter().enumerate() {
+            if let Some((file, range)) = self.check_entry(index, entry, &mut problems) {
+                files.push(file);
+                ranges.push(range);
+            }
+        }
+        if problems.is_empty() {
+            Ok(ValidPlaylist {
+                entries: &self.entries,
+                files,
+                ranges,
+            })
+        } else {
+            Err(ValidationError { problems })
+        }
+    }
+
+    /// Returns the loaded file and its start and end frame if the entry is valid
+    fn check_entry(
+        &self,
+        index: usize,
+        entry: &EntryDescription,
+        problems: &mut Vec<Problem>,
+    ) -> Option<(Box<dyn AudioFile + Send>, (usize, usize))> {
+        let problem_count = problems.len();
+        for &channel in entry.channel_map.iter().flatten() {
+            if channel >= self.channels {
+                problems.push(Problem::OutputChannel {
+                    entry: index,
+                    channel,
+                    channels: self.channels,
+                });
+            }
+        }
+        if let Some(end) = entry.end {
+            if end <= entry.start {
+                problems.push(Problem::EndBeforeStart {
+                    entry: index,
+                    start: entry.start,
+                    end,
+                });
+            }
+        }
+        let path = self.resolve(&entry.path);
+        // NB: Without a valid sample rate, the file is loaded without conversion
+        let file = if self.samplerate == 0 {
+            load_audio_file_native(&path)
+        } else {
+            load_audio_file(&path, self.samplerate)
+        };
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                problems.push(Problem::File {
+                    entry: index,
+                    message: format!("Cannot load \"{}\": {}", path.display(), e),
+                });
+                return None;
+            }
+        };
+        if entry.channel_map.len() != file.channels() {
+            problems.push(Problem::ChannelCount {
+                entry: index,
+                map: entry.channel_map.len(),
+                file: file.channels(),
+            });
+        }
+        let frames = file.frames();
+        if entry.file_offset >= frames {
+            problems.push(Problem::FileOffset {
+                entry: index,
+                offset: entry.file_offset,
+                frames,
+            });
+        }
+        let end = entry
+            .end
+            .unwrap_or_else(|| entry.start + frames.saturating_sub(entry.file_offset));
+        let length = end.saturating_sub(entry.start);
+        if entry.fade_in + entry.fade_out > length {
+            problems.push(Problem::Fades {
+                entry: index,
+                fade_in: entry.fade_in,
+                fade_out: entry.fade_out,
+                frames: length,
+            });
+        }
+        if problems.len() == problem_count {
+            Some((file, (entry.start, end)))
+        } else {
+            None
+        }
+    }
+
+    /// Validates the description and loads all files
+    pub fn to_playlist(&self) -> Result<Vec<PlaylistEntry>, Error> {
+        self.validate()?.into_playlist()
+    }
+
+    /// Like `to_playlist()`, but the playlist is used to create a `FileStreamer`
+    pub fn build(&self, blocksize: usize) -> Result<FileStreamer, Error> {
+        Ok(FileStreamer::new(
+            self.to_playlist()?,
+            blocksize,
+            self.channels,
+        )?)
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        match self.directory {
+            Some(ref directory) => directory.join(path),
+            None => path.into(),
+        }
+    }
+}
+
+fn extension(path: &Path) -> Option<&str> {
+    path.extension().and_then(|extension| extension.to_str())
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+fn unity() -> f32 {
+    1.0
+}
+
+fn is_unity(value: &f32) -> bool {
+    *value == 1.0
+}
+
+/// Unused file channels are written as `-1` (TOML doesn't have a null value)
+mod channel_map {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(map: &[Option<usize>], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter().map(|channel| match channel {
+            Some(channel) => *channel as i64,
+            None => -1,
+        }))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Option<usize>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let map = Vec::<i64>::deserialize(deserializer)?;
+        Ok(map
+            .into_iter()
+            .map(|channel| {
+                if channel < 0 {
+                    None
+                } else {
+                    Some(channel as usize)
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> PlaylistDescription {
+        let mut description = PlaylistDescription::new(44_100, 2);
+        description.directory = Some(concat!(env!("CARGO_MANIFEST_DIR"), "/examples").into());
+        description
+            .entries
+            .push(EntryDescription::new("xmas.wav", 0, vec![Some(1)]));
+        description.entries.push(EntryDescription {
+            end: Some(176_400),
+            file_offset: 44_100,
+            gain: 0.5,
+            fade_in: 4410,
+            fade_out: 22_050,
+            ..EntryDescription::new("xmas.wav", 88_200, vec![None])
+        });
+        description
+    }
+
+    fn check_round_trip(copy: &PlaylistDescription) {
+        let description = example();
+        assert_eq!(copy.samplerate, description.samplerate);
+        assert_eq!(copy.channels, description.channels);
+        assert_eq!(copy.entries, description.entries);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let text = example().to_json().unwrap();
+        assert!(text.contains("\"channel_map\": [\n        -1\n      ]"));
+        check_round_trip(&PlaylistDescription::from_json(&text).unwrap());
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let text = example().to_toml().unwrap();
+        assert!(!text.contains("fade_in = 0"));
+        check_round_trip(&PlaylistDescription::from_toml(&text).unwrap());
+    }
+
+    #[test]
+    fn defaults() {
+        let description = PlaylistDescription::from_toml(
+            "samplerate = 48000\nchannels = 1\n[[entries]]\npath = \"a.wav\"\nstart = 0\nchannel_map = [0]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            description.entries,
+            [EntryDescription::new("a.wav", 0, vec![Some(0)])]
+        );
+    }
+
+    #[test]
+    fn invalid_syntax() {
+        let entry = |fields: &str| {
+            format!(
+                "samplerate = 44100\nchannels = 2\n[[entries]]\npath = \"a.wav\"\n{}\n",
+                fields
+            )
+        };
+        let valid = entry("start = 0\nchannel_map = [0]");
+        assert!(PlaylistDescription::from_toml(&valid).is_ok());
+        for fields in &[
+            "start = 0\nchannel_map = [0]\nfile_offset = -1",
+            "start = -1\nchannel_map = [0]",
+            "start = 0\nchannel_map = [0]\nvolume = 1.0",
+            "start = 0",
+        ] {
+            assert!(PlaylistDescription::from_toml(&entry(fields)).is_err());
+        }
+        assert!(PlaylistDescription::from_json("{\"samplerate\": 44100}").is_err());
+    }
+
+    #[test]
+    fn valid_description() {
+        assert!(example().validate().is_ok());
+        assert_eq!(example().to_playlist().unwrap().len(), 2);
+    }
+
+    fn problems(description: &PlaylistDescription) -> Vec<Problem> {
+        match description.validate() {
+            Ok(_) => panic!("description should be invalid"),
+            Err(e) => e.problems,
+        }
+    }
+
+    #[test]
+    fn unknown_file_channel() {
+        let mut description = example();
+        description.entries[0].channel_map = vec![Some(0), Some(1)];
+        assert_eq!(
+            problems(&description),
+            [Problem::ChannelCount {
+                entry: 0,
+                map: 2,
+                file: 1
+            }]
+        );
+        assert!(description.to_playlist().is_err());
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let mut description = example();
+        description.entries[1].end = Some(88_200);
+        description.entries[1].file_offset = 200_000;
+        description.entries[1].fade_in = 0;
+        description.entries[1].fade_out = 0;
+        assert_eq!(
+            problems(&description),
+            [
+                Problem::EndBeforeStart {
+                    entry: 1,
+                    start: 88_200,
+                    end: 88_200
+                },
+                Problem::FileOffset {
+                    entry: 1,
+                    offset: 200_000,
+                    frames: 114_400
+                },
+            ]
+        );
+
+        let mut description = example();
+        description.entries[1].fade_out = 88_200;
+        assert_eq!(
+            problems(&description),
+            [Problem::Fades {
+                entry: 1,
+                fade_in: 4410,
+                fade_out: 88_200,
+                frames: 88_200
+            }]
+        );
+    }
+
+    #[test]
+    fn bad_routes() {
+        let mut description = example();
+        description.entries[0].channel_map = vec![Some(2)];
+        assert_eq!(
+            problems(&description),
+            [Problem::OutputChannel {
+                entry: 0,
+                channel: 2,
+                channels: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn overlaps_are_allowed() {
+        assert_eq!(example().validate().unwrap().overlaps(), []);
+        let mut description = example();
+        description.entries[1].channel_map = vec![Some(1)];
+        assert_eq!(
+            description.validate().unwrap().overlaps(),
+            [Overlap {
+                first: 0,
+                second: 1,
+                channel: 1,
+                start: 88_200,
+                end: 114_400
+            }]
+        );
+        assert_eq!(description.to_playlist().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn missing_file() {
+        let mut description = example();
+        description.samplerate = 0;
+        description.entries[0].path = "does-not-exist.wav".into();
+        let problems = problems(&description);
+        assert_eq!(problems[0], Problem::ZeroSamplerate);
+        match problems[1] {
+            Problem::File { entry: 0, .. } => {}
+            ref problem => panic!("unexpected problem: {}", problem),
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Reading audio files from disk.
+//!
+//! `DiskFile` replaces `BufReader<fs::File>` for the decoders.
+//! It tells the kernel about sequential access (and the data that will be needed next)
+//! and optionally bypasses the page cache with `O_DIRECT`.
+
+use std::alloc;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Alignment of buffers, offsets and read sizes for `O_DIRECT`
+pub(crate) const ALIGNMENT: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+    /// Number of bytes per `read()` call (rounded up to a multiple of 4096)
+    pub read_size: usize,
+    /// Number of bytes the kernel is asked to read ahead (`0` means no hints)
+    pub read_ahead: usize,
+    /// Bypass the page cache with `O_DIRECT` (Linux only), no read-ahead hints are given
+    pub direct: bool,
+    /// Use the memory-mapped reader for WAV/RF64 files (Unix only, other files are read as usual)
+    pub mmap: bool,
+    /// Read with `io_uring` instead of blocking `read()` calls
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub uring: Option<crate::uring::Uring>,
+}
+
+impl ReadOptions {
+    // NB: usize::div_ceil() would need Rust 1.73
+    #[allow(clippy::manual_div_ceil)]
+    pub(crate) fn aligned_read_size(&self) -> usize {
+        let read_size = std::cmp::max(self.read_size, 1);
+        (read_size + ALIGNMENT - 1) / ALIGNMENT * ALIGNMENT
+    }
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions {
+            read_size: 64 * 1024,
+            read_ahead: 1024 * 1024,
+            direct: false,
+            mmap: false,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            uring: None,
+        }
+    }
+}
+
+/// A buffered reader with read-ahead hints and optional `O_DIRECT`
+pub struct DiskFile {
+    file: Arc<fs::File>,
+    length: u64,
+    buffer: AlignedBuffer,
+    /// File offset of the first byte in `buffer`
+    buffer_start: u64,
+    /// Number of valid bytes in `buffer`
+    buffer_len: usize,
+    position: u64,
+    read_ahead: Option<ReadAhead>,
+    direct: bool,
+}
+
+impl DiskFile {
+    pub fn open<P>(path: P, options: &ReadOptions) -> io::Result<DiskFile>
+    where
+        P: AsRef<Path>,
+    {
+        let file = Arc::new(open(path.as_ref(), options)?);
+        let length = file.metadata()?.len();
+        Ok(DiskFile {
+            read_ahead: ReadAhead::new(&file, options),
+            file,
+            length,
+            buffer: AlignedBuffer::new(options.aligned_read_size()),
+            buffer_start: 0,
+            buffer_len: 0,
+            position: 0,
+            direct: options.direct,
+        })
+    }
+
+    /// Returns `None` if read-ahead hints are disabled (see `ReadOptions`)
+    pub fn read_ahead(&self) -> Option<ReadAhead> {
+        self.read_ahead.clone()
+    }
+
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        // NB: O_DIRECT needs aligned offsets, the buffer is re-used otherwise
+        let start = self.position - self.position % ALIGNMENT as u64;
+        self.buffer_start = start;
+        // NB: The buffer is invalid if reading fails
+        self.buffer_len = 0;
+        self.buffer_len = read_chunk(&self.file, &mut self.buffer, start, self.direct)?;
+        if let Some(ref read_ahead) = self.read_ahead {
+            let end = start + self.buffer_len as u64;
+            if end < self.length {
+                advise(&self.file, end, read_ahead.length as u64, Advice::WillNeed);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for DiskFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.length {
+            return Ok(0);
+        }
+        if self.position < self.buffer_start
+            || self.position >= self.buffer_start + self.buffer_len as u64
+        {
+            self.fill_buffer()?;
+        }
+        let offset = (self.position - self.buffer_start) as usize;
+        if offset >= self.buffer_len {
+            // The file has been truncated
+            return Ok(0);
+        }
+        let available = &self.buffer[offset..self.buffer_len];
+        let n = std::cmp::min(buf.len(), available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for DiskFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_offset(self.length, offset),
+            SeekFrom::Current(offset) => add_offset(self.position, offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                if let Some(ref read_ahead) = self.read_ahead {
+                    read_ahead.set_position(position);
+                }
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Fills `buffer` (or until the end of the file) with blocking reads
+///
+/// With `direct`, `buffer` and `offset` must be aligned.
+pub(crate) fn read_chunk(
+    file: &fs::File,
+    buffer: &mut [u8],
+    offset: u64,
+    direct: bool,
+) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match read_at(file, &mut buffer[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(n) => {
+                len += n;
+                if direct && n % ALIGNMENT != 0 {
+                    // End of file
+                    break;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(unix)]
+fn read_at(file: &fs::File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_at(buffer, offset)
+}
+
+#[cfg(not(unix))]
+fn read_at(mut file: &fs::File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    // NB: This changes the file position, which isn't used anywhere else
+    file.seek(SeekFrom::Start(offset))?;
+    file.read(buffer)
+}
+
+/// Opens with `O_DIRECT` or with a hint for sequential access
+pub(crate) fn open(path: &Path, options: &ReadOptions) -> io::Result<fs::File> {
+    if options.direct {
+        open_direct(path)
+    } else {
+        let file = fs::File::open(path)?;
+        advise(&file, 0, 0, Advice::Sequential);
+        Ok(file)
+    }
+}
+
+pub(crate) fn add_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    } else {
+        base.checked_add(offset as u64)
+    }
+}
+
+/// Asks the kernel to read the data at the current position of a file ahead of time
+///
+/// This is used for playlist entries that will start soon.
+/// It shares the file descriptor (and the position) with a `DiskFile` or `UringFile`.
+#[derive(Clone)]
+pub struct ReadAhead {
+    file: Arc<fs::File>,
+    /// Updated when seeking
+    position: Arc<AtomicU64>,
+    length: usize,
+}
+
+impl ReadAhead {
+    /// Returns `None` with `direct` or without `read_ahead`
+    pub(crate) fn new(file: &Arc<fs::File>, options: &ReadOptions) -> Option<ReadAhead> {
+        if options.direct || options.read_ahead == 0 {
+            return None;
+        }
+        Some(ReadAhead {
+            file: Arc::clone(file),
+            position: Arc::new(AtomicU64::new(0)),
+            length: options.read_ahead,
+        })
+    }
+
+    pub(crate) fn set_position(&self, position: u64) {
+        self.position.store(position, Ordering::Relaxed);
+    }
+
+    pub fn will_need(&self) {
+        let position = self.position.load(Ordering::Relaxed);
+        let start = position - position % ALIGNMENT as u64;
+        advise(&self.file, start, self.length as u64, Advice::WillNeed);
+    }
+}
+
+pub(crate) enum Advice {
+    Sequential,
+    WillNeed,
+}
+
+/// `length` of `0` means until the end of the file
+///
+/// This is only a hint, errors are ignored.
+#[cfg(target_os = "linux")]
+pub(crate) fn advise(file: &fs::File, offset: u64, length: u64, advice: Advice) {
+    use std::os::unix::io::AsRawFd;
+
+    let advice = match advice {
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+    };
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            length as libc::off_t,
+            advice,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn advise(_file: &fs::File, _offset: u64, _length: u64, _advice: Advice) {
+    // TODO: fcntl(F_RDADVISE) on macOS?
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_path: &Path) -> io::Result<fs::File> {
+    // TODO: fcntl(F_NOCACHE) on macOS?
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "O_DIRECT is only supported on Linux",
+    ))
+}
+
+/// A zero-initialized byte buffer that is aligned for `O_DIRECT`
+pub(crate) struct AlignedBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Panics if `len` is zero
+    pub(crate) fn new(len: usize) -> AlignedBuffer {
+        assert!(len > 0);
+        let ptr = unsafe { alloc::alloc_zeroed(AlignedBuffer::layout(len)) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(AlignedBuffer::layout(len));
+        }
+        AlignedBuffer { ptr, len }
+    }
+
+    fn layout(len: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(len, ALIGNMENT).unwrap()
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, AlignedBuffer::layout(self.len)) };
+    }
+}
+
+impl std::ops::Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl std::ops::DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_ahead_follows_seeks() {
+        let path = std::env::temp_dir().join(format!(
+            "disk-streaming-read-ahead-{}.raw",
+            std::process::id()
+        ));
+        fs::write(&path, vec![0; 100_000]).unwrap();
+        let mut file = DiskFile::open(&path, &Default::default()).unwrap();
+        let read_ahead = file.read_ahead().unwrap();
+        // NB: The file descriptor is shared
+        assert!(Arc::ptr_eq(&read_ahead.file, &file.file));
+        file.seek(SeekFrom::Start(50_000)).unwrap();
+        assert_eq!(read_ahead.position.load(Ordering::Relaxed), 50_000);
+        file.seek(SeekFrom::Current(-20_000)).unwrap();
+        assert_eq!(read_ahead.position.load(Ordering::Relaxed), 30_000);
+        read_ahead.will_need();
+
+        let options = ReadOptions {
+            read_ahead: 0,
+            ..Default::default()
+        };
+        assert!(DiskFile::open(&path, &options)
+            .unwrap()
+            .read_ahead()
+            .is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+//! Blocks with one buffer per channel, used by the WAV decoder.
+
+pub struct Block {
+    pub(crate) channels: Box<[Channel]>,
+    pub(crate) len_frames: usize,
+    pub(crate) capacity_frames: usize,
+}
+
+impl Block {
+    pub(crate) fn new(channels: usize, capacity_frames: usize) -> Block {
+        Block {
+            channels: (0..channels)
+                .map(|_| Channel {
+                    data: (0..capacity_frames).map(|_| 0.0f32).collect(),
+                    index: 0,
+                    stop: 0,
+                    selected: true,
+                })
+                .collect(),
+            len_frames: 0,
+            capacity_frames,
+        }
+    }
+
+    /// See `AudioFileBlocks::select_channels()`
+    pub(crate) fn select_channels(&mut self, channels: &[usize]) {
+        let capacity = self.capacity_frames;
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            channel.selected = channels.contains(&i);
+            // NB: Memory is only needed for selected channels
+            if !channel.selected {
+                channel.data = Box::new([]);
+            } else if channel.data.is_empty() {
+                channel.data = vec![0.0; capacity].into_boxed_slice();
+            }
+            channel.index = 0;
+            channel.stop = 0;
+        }
+    }
+}
+
+impl super::Block for Block {
+    type Channel = Channel;
+
+    fn channel_iterators(&mut self) -> &mut [Channel] {
+        &mut self.channels
+    }
+
+    fn frames(&self) -> usize {
+        self.len_frames
+    }
+}
+
+pub struct Channel {
+    pub(crate) data: Box<[f32]>,
+    pub(crate) index: usize,
+    pub(crate) stop: usize,
+    pub(crate) selected: bool,
+}
+
+impl Iterator for Channel {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.index == self.stop {
+            None
+        } else {
+            let value = self.data[self.index];
+            self.index += 1;
+            Some(value)
+        }
+    }
+
+    // TODO: size_hint()?
+}
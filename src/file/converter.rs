@@ -1,3 +1,6 @@
+// NB: For #[derive(Fail)], see src/streamer.rs
+#![allow(unknown_lints, non_local_definitions)]
+
 use std::ffi::CStr;
 use std::fmt;
 
@@ -18,6 +21,7 @@ where
     F: AudioFileBasics + AudioFileBlocks,
 {
     file: F,
+    converter_type: c_int,
     state: *mut libsamplerate_sys::SRC_STATE,
     // http://www.mega-nerd.com/SRC/api_misc.html#SRC_DATA
     data: libsamplerate_sys::SRC_DATA,
@@ -35,7 +39,7 @@ where
 {
     pub fn new(file: F, samplerate: usize) -> Result<Converter<F>, LibSamplerateError> {
         // TODO: specify type of converter
-        let converter_type = SRC_SINC_BEST_QUALITY;
+        let converter_type = SRC_SINC_BEST_QUALITY as c_int;
         // TODO: specify buffer size?
         let buffer_size = 2048;
 
@@ -44,7 +48,7 @@ where
 
         let state = unsafe {
             // http://www.mega-nerd.com/SRC/api_full.html#Init
-            libsamplerate_sys::src_new(converter_type as c_int, channels as c_int, &mut error)
+            libsamplerate_sys::src_new(converter_type, channels as c_int, &mut error)
         };
         if state.is_null() {
             return Err(LibSamplerateError(error));
@@ -71,6 +75,7 @@ where
                 src_ratio: samplerate as f64 / file.samplerate() as f64,
             },
             file,
+            converter_type,
             state,
             samplerate,
             current_block: Block {
@@ -83,6 +88,7 @@ where
                         len: 0,
                     })
                     .collect(),
+                selected: (0..channels).collect(),
             },
             // NB: Data will stay at the same memory address:
             buffer_in: buffer_in.into_boxed_slice(),
@@ -103,7 +109,7 @@ impl fmt::Display for LibSamplerateError {
         if msg.is_null() {
             write!(f, "Invalid error code: {}", self.0)
         } else {
-            write!(f, "{}", unsafe { CStr::from_ptr(msg).to_str().unwrap() })
+            write!(f, "{}", unsafe { CStr::from_ptr(msg).to_string_lossy() })
         }
     }
 }
@@ -123,14 +129,21 @@ pub struct Block {
     ptr: *const f32,
     frames: usize,
     channels: Box<[Channel]>,
+    /// The file channels that are resampled (in this order)
+    selected: Box<[usize]>,
 }
 
 impl super::Block for Block {
     type Channel = Channel;
 
     fn channel_iterators(&mut self) -> &mut [Channel] {
-        for (i, channel) in self.channels.iter_mut().enumerate() {
+        for channel in self.channels.iter_mut() {
+            channel.len = 0;
+        }
+        for (i, &selected) in self.selected.iter().enumerate() {
+            let channel = &mut self.channels[selected];
             channel.ptr = unsafe { self.ptr.add(i) };
+            channel.stride = self.selected.len();
             channel.len = self.frames;
         }
         &mut self.channels
@@ -180,9 +193,11 @@ where
     }
 
     fn seek(&mut self, frame: usize) -> Result<(), Error> {
-        // TODO: is this correct? what about rounding errors?
+        // NB: This is not sample-exact: the filter history is lost (see src_reset() below)
+        // and the output is shifted by up to half an input frame.
+        // TODO: seek to an earlier input frame and discard output frames?
         self.file
-            .seek((frame as f64 / self.data.src_ratio) as usize)?;
+            .seek((frame as f64 / self.data.src_ratio).round() as usize)?;
         // http://www.mega-nerd.com/SRC/api_full.html#Reset
         let result = unsafe { libsamplerate_sys::src_reset(self.state) };
         if result != 0 {
@@ -192,6 +207,10 @@ where
         self.data.end_of_input = 0;
         Ok(())
     }
+
+    fn prefetch(&mut self) {
+        self.file.prefetch()
+    }
 }
 
 impl<F> AudioFileBlocks for Converter<F>
@@ -201,18 +220,23 @@ where
     type Block = Block;
 
     fn next_block(&mut self, max_frames: usize) -> Result<&mut Block, Error> {
-        let channels = self.file.channels();
+        let buffer_frames = self.buffer_in.len() / self.file.channels();
+        let channels = self.current_block.selected.len();
 
         // We might have to call src_process() multiple times to get some data out
         loop {
             // Get new input data (and append to already existing input data)
 
-            let buffer = &mut self.buffer_in[(self.data.input_frames as usize * channels)..];
+            let buffer = &mut self.buffer_in
+                [(self.data.input_frames as usize * channels)..(buffer_frames * channels)];
             if !buffer.is_empty() {
                 let requested_frames = buffer.len() / channels;
-                let copied_frames = self
-                    .file
-                    .copy_block_to_interleaved(requested_frames, buffer)?;
+                let copied_frames = copy_selected_channels(
+                    &mut self.file,
+                    &self.current_block.selected,
+                    requested_frames,
+                    buffer,
+                )?;
                 if copied_frames == 0 {
                     self.data.end_of_input = 1;
                 } else {
@@ -222,8 +246,9 @@ where
 
             // Call libsamplerate to get new output data
 
-            self.data.output_frames =
-                std::cmp::min(self.buffer_out.len() / channels, max_frames) as c_long;
+            // NB: The buffers have space for all channels, only the selected ones are used
+            let output_frames = self.buffer_out.len() / self.file.channels();
+            self.data.output_frames = std::cmp::min(output_frames, max_frames) as c_long;
             // http://www.mega-nerd.com/SRC/api_full.html#Process
             let result = unsafe { libsamplerate_sys::src_process(self.state, &mut self.data) };
             if result != 0 {
@@ -256,4 +281,60 @@ where
             }
         }
     }
+
+    /// Returns an error if libsamplerate cannot be re-initialized (then all channels are resampled)
+    fn select_channels(&mut self, channels: &[usize]) -> Result<(), Error> {
+        let mut selected: Vec<_> = channels
+            .iter()
+            .cloned()
+            .filter(|&channel| channel < self.file.channels())
+            .collect();
+        selected.sort();
+        selected.dedup();
+        if selected.is_empty() {
+            // libsamplerate needs at least one channel
+            selected.push(0);
+        }
+        let mut error: c_int = 0;
+        let state = unsafe {
+            libsamplerate_sys::src_new(self.converter_type, selected.len() as c_int, &mut error)
+        };
+        if state.is_null() {
+            return Err(LibSamplerateError(error).into());
+        }
+        unsafe {
+            libsamplerate_sys::src_delete(self.state);
+        }
+        self.state = state;
+        self.data.input_frames = 0;
+        self.data.end_of_input = 0;
+        // NB: If this fails, the file decodes more channels than needed, which is fine
+        let result = self.file.select_channels(&selected);
+        self.current_block.selected = selected.into_boxed_slice();
+        result
+    }
+}
+
+/// Like `AudioFileBlocks::copy_block_to_interleaved()`, but only with the `selected` channels
+fn copy_selected_channels<F>(
+    file: &mut F,
+    selected: &[usize],
+    max_frames: usize,
+    buffer: &mut [f32],
+) -> Result<usize, Error>
+where
+    F: AudioFileBlocks,
+{
+    use super::Block;
+
+    let block = file.next_block(max_frames)?;
+    let frames = block.frames();
+    let iterators = block.channel_iterators();
+    for (i, &channel) in selected.iter().enumerate() {
+        let target = buffer[i..].iter_mut().step_by(selected.len());
+        for (a, b) in (&mut iterators[channel]).zip(target) {
+            *b = a;
+        }
+    }
+    Ok(frames)
 }
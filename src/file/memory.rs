@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use failure::Error;
+
+use super::{AudioFileBasics, Route};
+use crate::streamer::AudioFile;
+
+/// Decoded audio data, shared between any number of `File`s
+pub struct Data {
+    samplerate: usize,
+    frames: usize,
+    channels: Box<[Box<[f32]>]>,
+}
+
+impl Data {
+    /// Decodes the whole file (from the beginning)
+    pub fn decode(file: &mut dyn AudioFile) -> Result<Data, Error> {
+        let frames = file.frames();
+        Data::decode_part(file, 0, frames)
+    }
+
+    /// Decodes (up to) `frames` frames, starting at `frame`
+    pub fn decode_part(
+        file: &mut dyn AudioFile,
+        frame: usize,
+        frames: usize,
+    ) -> Result<Data, Error> {
+        let frames = std::cmp::min(frames, file.frames().saturating_sub(frame));
+        let routing: Vec<_> = (0..file.channels())
+            .map(|c| Route::new(c, c, 1.0))
+            .collect();
+        let gains = vec![1.0; frames];
+        let mut channels: Box<[Box<[f32]>]> = (0..file.channels())
+            .map(|_| vec![0.0; frames].into_boxed_slice())
+            .collect();
+        file.seek(frame)?;
+        let filled = file.fill_channels(&routing, &gains, frames, 0, &mut channels)?;
+        Ok(Data {
+            samplerate: file.samplerate(),
+            // NB: The file might be shorter than announced
+            frames: filled.frames,
+            channels,
+        })
+    }
+
+    /// Uses the given samples, e.g. for generated test signals
+    ///
+    /// Panics if the channels don't have the same length.
+    pub fn from_channels(samplerate: usize, channels: Vec<Vec<f32>>) -> Data {
+        let frames = channels.first().map_or(0, |channel| channel.len());
+        assert!(
+            channels.iter().all(|channel| channel.len() == frames),
+            "All channels must have the same length"
+        );
+        Data {
+            samplerate,
+            frames,
+            channels: channels
+                .into_iter()
+                .map(|channel| channel.into_boxed_slice())
+                .collect(),
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Panics if `channel` is out of range
+    pub fn channel(&self, channel: usize) -> &[f32] {
+        &self.channels[channel][..self.frames]
+    }
+
+    /// Number of bytes used for the samples
+    pub fn size(&self) -> usize {
+        self.channels.len() * self.frames * std::mem::size_of::<f32>()
+    }
+}
+
+/// An audio file that has been decoded into memory
+pub struct File {
+    data: Arc<Data>,
+    position: usize,
+    current_block: Block,
+}
+
+impl File {
+    pub fn new(data: Arc<Data>) -> File {
+        File {
+            current_block: Block {
+                channels: (0..data.channels.len())
+                    .map(|channel| Channel {
+                        data: Arc::clone(&data),
+                        channel,
+                        index: 0,
+                        stop: 0,
+                    })
+                    .collect(),
+                frames: 0,
+            },
+            data,
+            position: 0,
+        }
+    }
+}
+
+impl AudioFileBasics for File {
+    fn channels(&self) -> usize {
+        self.data.channels.len()
+    }
+
+    fn frames(&self) -> usize {
+        self.data.frames
+    }
+
+    fn samplerate(&self) -> usize {
+        self.data.samplerate
+    }
+
+    fn seek(&mut self, frame: usize) -> Result<(), Error> {
+        self.position = std::cmp::min(frame, self.data.frames);
+        Ok(())
+    }
+}
+
+impl super::AudioFileBlocks for File {
+    type Block = Block;
+
+    fn next_block(&mut self, max_frames: usize) -> Result<&mut Block, Error> {
+        let frames = std::cmp::min(max_frames, self.data.frames - self.position);
+        for channel in self.current_block.channels.iter_mut() {
+            channel.index = self.position;
+            channel.stop = self.position + frames;
+        }
+        self.current_block.frames = frames;
+        self.position += frames;
+        Ok(&mut self.current_block)
+    }
+}
+
+pub struct Block {
+    channels: Box<[Channel]>,
+    frames: usize,
+}
+
+impl super::Block for Block {
+    type Channel = Channel;
+
+    fn channel_iterators(&mut self) -> &mut [Channel] {
+        &mut self.channels
+    }
+
+    fn frames(&self) -> usize {
+        self.frames
+    }
+}
+
+pub struct Channel {
+    data: Arc<Data>,
+    channel: usize,
+    index: usize,
+    stop: usize,
+}
+
+impl Iterator for Channel {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.index == self.stop {
+            None
+        } else {
+            let value = self.data.channels[self.channel][self.index];
+            self.index += 1;
+            Some(value)
+        }
+    }
+}
@@ -0,0 +1,453 @@
+//! Memory-mapped WAV and RF64 files.
+//!
+//! Interleaved frames are converted directly from the mapping into one buffer per channel.
+//! The pages ahead of the current position are faulted in by the thread that reads the blocks
+//! (i.e. the reader thread), the kernel is asked to read even further ahead.
+//!
+//! NB: Truncating a file while it is mapped leads to `SIGBUS`.
+
+// NB: For #[derive(Fail)], see src/streamer.rs
+#![allow(unknown_lints, non_local_definitions)]
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use failure::{Error, Fail};
+
+pub use super::buffer::{Block, Channel};
+
+/// Number of bytes that are faulted in ahead of the current position
+const PREFAULT_BYTES: usize = 256 * 1024;
+
+#[derive(Debug, Fail)]
+pub enum OpenError {
+    Io(#[cause] io::Error),
+    NotWav,
+    Invalid(&'static str),
+    Unsupported { format_tag: u16, bits: u16 },
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error opening WAV file: ")?;
+        use OpenError::*;
+        match self {
+            Io(e) => e.fmt(f),
+            NotWav => write!(f, "Not a RIFF/RF64 WAVE file"),
+            Invalid(msg) => write!(f, "Invalid header ({})", msg),
+            Unsupported { format_tag, bits } => write!(
+                f,
+                "Unsupported sample format (format tag {}, {} bits)",
+                format_tag, bits
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for OpenError {
+    fn from(e: io::Error) -> OpenError {
+        OpenError::Io(e)
+    }
+}
+
+/// A read-only mapping of a whole file
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(file: &fs::File) -> Result<Mapping, OpenError> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(OpenError::NotWav);
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    /// This is only a hint, errors are ignored.
+    fn advise(&self, start: usize, end: usize, advice: libc::c_int) {
+        let end = std::cmp::min(end, self.len);
+        // NB: The address has to be page-aligned
+        let start = start - start % page_size();
+        if start < end {
+            unsafe {
+                libc::madvise(
+                    (self.ptr as *mut u8).add(start) as *mut libc::c_void,
+                    end - start,
+                    advice,
+                );
+            }
+        }
+    }
+
+    /// Touches one byte per page to make sure the pages are in memory
+    fn prefault(&self, start: usize, end: usize) {
+        let data = self.data();
+        let end = std::cmp::min(end, data.len());
+        let mut offset = start;
+        while offset < end {
+            unsafe { std::ptr::read_volatile(&data[offset]) };
+            offset += page_size();
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+fn page_size() -> usize {
+    // TODO: cache this?
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[derive(Clone, Copy)]
+enum SampleFormat {
+    U8,
+    I16,
+    I24,
+    I32,
+    F32,
+    F64,
+}
+
+impl SampleFormat {
+    fn bytes(self) -> usize {
+        use SampleFormat::*;
+        match self {
+            U8 => 1,
+            I16 => 2,
+            I24 => 3,
+            I32 | F32 => 4,
+            F64 => 8,
+        }
+    }
+
+    /// Returns a function that converts one channel of interleaved frames
+    fn converter(self) -> fn(&[u8], usize, usize, &mut [f32]) {
+        use SampleFormat::*;
+        match self {
+            U8 => convert::<U8Sample>,
+            I16 => convert::<I16Sample>,
+            I24 => convert::<I24Sample>,
+            I32 => convert::<I32Sample>,
+            F32 => convert::<F32Sample>,
+            F64 => convert::<F64Sample>,
+        }
+    }
+}
+
+trait Sample {
+    const BYTES: usize;
+    fn to_f32(bytes: &[u8]) -> f32;
+}
+
+struct U8Sample;
+
+impl Sample for U8Sample {
+    const BYTES: usize = 1;
+    fn to_f32(bytes: &[u8]) -> f32 {
+        (bytes[0] as f32 - 128.0) / 127.0
+    }
+}
+
+struct I16Sample;
+
+impl Sample for I16Sample {
+    const BYTES: usize = 2;
+    fn to_f32(bytes: &[u8]) -> f32 {
+        // NB: Same scaling as in the hound-based WAV reader
+        i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / i16::MAX as f32
+    }
+}
+
+struct I24Sample;
+
+impl Sample for I24Sample {
+    const BYTES: usize = 3;
+    fn to_f32(bytes: &[u8]) -> f32 {
+        // Sign extension by shifting the 24 bits to the top
+        let value = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8;
+        value as f32 / 8_388_607.0
+    }
+}
+
+struct I32Sample;
+
+impl Sample for I32Sample {
+    const BYTES: usize = 4;
+    fn to_f32(bytes: &[u8]) -> f32 {
+        let value = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        value as f32 / i32::MAX as f32
+    }
+}
+
+struct F32Sample;
+
+impl Sample for F32Sample {
+    const BYTES: usize = 4;
+    fn to_f32(bytes: &[u8]) -> f32 {
+        f32::from_bits(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+struct F64Sample;
+
+impl Sample for F64Sample {
+    const BYTES: usize = 8;
+    fn to_f32(bytes: &[u8]) -> f32 {
+        let mut array = [0; 8];
+        array.copy_from_slice(&bytes[..8]);
+        f64::from_bits(u64::from_le_bytes(array)) as f32
+    }
+}
+
+/// Converts channel number `channel` of the interleaved `frames` into `target`
+fn convert<S: Sample>(frames: &[u8], frame_bytes: usize, channel: usize, target: &mut [f32]) {
+    let offset = channel * S::BYTES;
+    for (sample, frame) in target.iter_mut().zip(frames.chunks_exact(frame_bytes)) {
+        *sample = S::to_f32(&frame[offset..offset + S::BYTES]);
+    }
+}
+
+struct Header {
+    format: SampleFormat,
+    channels: usize,
+    samplerate: usize,
+    data_offset: usize,
+    /// Size of the data chunk according to the header
+    data_len: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut array = [0; 4];
+    array.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(array)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut array = [0; 8];
+    array.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(array)
+}
+
+fn parse_header(data: &[u8]) -> Result<Header, OpenError> {
+    if data.len() < 12 || &data[8..12] != b"WAVE" {
+        return Err(OpenError::NotWav);
+    }
+    let rf64 = match &data[0..4] {
+        b"RIFF" => false,
+        b"RF64" => true,
+        _ => return Err(OpenError::NotWav),
+    };
+    let mut rf64_data_len = None;
+    let mut format = None;
+    let mut position = 12;
+    while position + 8 <= data.len() {
+        let id = &data[position..position + 4];
+        let size = read_u32(data, position + 4) as usize;
+        let body = position + 8;
+        match id {
+            b"ds64" => {
+                if size < 16 || body + 16 > data.len() {
+                    return Err(OpenError::Invalid("ds64 chunk too short"));
+                }
+                rf64_data_len = Some(read_u64(data, body + 8) as usize);
+            }
+            b"fmt " => {
+                // NB: `body + size` could overflow on 32-bit targets
+                if size < 16 || size > data.len() - body {
+                    return Err(OpenError::Invalid("fmt chunk too short"));
+                }
+                let mut format_tag = read_u16(data, body);
+                let channels = read_u16(data, body + 2) as usize;
+                let samplerate = read_u32(data, body + 4) as usize;
+                let block_align = read_u16(data, body + 12) as usize;
+                let bits = read_u16(data, body + 14);
+                if format_tag == 0xFFFE && size >= 40 {
+                    // WAVE_FORMAT_EXTENSIBLE: the format is at the start of the SubFormat GUID
+                    format_tag = read_u16(data, body + 24);
+                }
+                let sample_format = match (format_tag, bits) {
+                    (1, 8) => SampleFormat::U8,
+                    (1, 16) => SampleFormat::I16,
+                    (1, 24) => SampleFormat::I24,
+                    (1, 32) => SampleFormat::I32,
+                    (3, 32) => SampleFormat::F32,
+                    (3, 64) => SampleFormat::F64,
+                    _ => return Err(OpenError::Unsupported { format_tag, bits }),
+                };
+                if channels == 0 {
+                    return Err(OpenError::Invalid("zero channels"));
+                }
+                if block_align != channels * sample_format.bytes() {
+                    return Err(OpenError::Invalid("unexpected block alignment"));
+                }
+                format = Some((sample_format, channels, samplerate));
+            }
+            b"data" => {
+                let (format, channels, samplerate) =
+                    format.ok_or(OpenError::Invalid("data chunk before fmt chunk"))?;
+                let size = match rf64_data_len {
+                    Some(len) if rf64 && size == 0xFFFF_FFFF => len,
+                    _ => size,
+                };
+                return Ok(Header {
+                    format,
+                    channels,
+                    samplerate,
+                    data_offset: body,
+                    data_len: size,
+                });
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even number of bytes
+        position = body
+            .checked_add(size)
+            .and_then(|end| end.checked_add(size & 1))
+            .ok_or(OpenError::Invalid("chunk size too large"))?;
+    }
+    Err(OpenError::Invalid("no data chunk"))
+}
+
+pub struct File {
+    mapping: Mapping,
+    header: Header,
+    frames: usize,
+    /// This is less than `frames` if the file has been truncated
+    available_frames: usize,
+    frame_bytes: usize,
+    convert: fn(&[u8], usize, usize, &mut [f32]),
+    position: usize,
+    /// Byte offset up to which pages have been faulted in
+    prefaulted: usize,
+    current_block: Block,
+}
+
+impl File {
+    pub fn open<P>(path: P) -> Result<File, OpenError>
+    where
+        P: AsRef<Path>,
+    {
+        // TODO: same buffer size as Converter?
+        let buffer_size = 2048;
+
+        let file = fs::File::open(path)?;
+        let mapping = Mapping::new(&file)?;
+        let header = parse_header(mapping.data())?;
+        mapping.advise(header.data_offset, mapping.len, libc::MADV_SEQUENTIAL);
+        let frame_bytes = header.channels * header.format.bytes();
+        let available_bytes = mapping.len - header.data_offset;
+        Ok(File {
+            frames: header.data_len / frame_bytes,
+            available_frames: std::cmp::min(header.data_len, available_bytes) / frame_bytes,
+            frame_bytes,
+            convert: header.format.converter(),
+            position: 0,
+            prefaulted: header.data_offset,
+            current_block: Block::new(header.channels, buffer_size),
+            header,
+            mapping,
+        })
+    }
+
+    /// Faults in the pages needed for the current block (and some more)
+    fn prefault(&mut self, end: usize) {
+        let end = end + PREFAULT_BYTES;
+        if self.prefaulted < end {
+            // The kernel can start reading the next part in the meantime
+            self.mapping
+                .advise(self.prefaulted, end + PREFAULT_BYTES, libc::MADV_WILLNEED);
+            self.mapping.prefault(self.prefaulted, end);
+            self.prefaulted = end;
+        }
+    }
+}
+
+impl super::AudioFileBasics for File {
+    fn channels(&self) -> usize {
+        self.header.channels
+    }
+
+    fn frames(&self) -> usize {
+        self.frames
+    }
+
+    fn samplerate(&self) -> usize {
+        self.header.samplerate
+    }
+
+    fn seek(&mut self, frame: usize) -> Result<(), Error> {
+        self.position = std::cmp::min(frame, self.available_frames);
+        self.prefaulted = self.header.data_offset + self.position * self.frame_bytes;
+        Ok(())
+    }
+
+    fn prefetch(&mut self) {
+        let start = self.header.data_offset + self.position * self.frame_bytes;
+        self.mapping
+            .advise(start, start + PREFAULT_BYTES, libc::MADV_WILLNEED);
+    }
+}
+
+impl super::AudioFileBlocks for File {
+    type Block = Block;
+
+    fn next_block(&mut self, max_frames: usize) -> Result<&mut Block, Error> {
+        let block = &mut self.current_block;
+        let frames = std::cmp::min(max_frames, block.capacity_frames);
+        let frames = std::cmp::min(frames, self.available_frames - self.position);
+        let start = self.header.data_offset + self.position * self.frame_bytes;
+        let end = start + frames * self.frame_bytes;
+        self.prefault(end);
+        let block = &mut self.current_block;
+        let data = &self.mapping.data()[start..end];
+        for (i, channel) in block.channels.iter_mut().enumerate() {
+            channel.index = 0;
+            if channel.selected {
+                (self.convert)(data, self.frame_bytes, i, &mut channel.data[..frames]);
+                channel.stop = frames;
+            } else {
+                channel.stop = 0;
+            }
+        }
+        block.len_frames = frames;
+        self.position += frames;
+        Ok(block)
+    }
+
+    fn select_channels(&mut self, channels: &[usize]) -> Result<(), Error> {
+        self.current_block.select_channels(channels);
+        Ok(())
+    }
+}
@@ -1,16 +1,66 @@
-use std::ops::IndexMut;
-
 use failure::Error;
 
+mod buffer;
 pub mod converter;
+pub mod memory;
+#[cfg(unix)]
+pub mod mmap;
 pub mod vorbis;
 pub mod wav;
 
+/// An entry of a sparse routing matrix
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Route {
+    pub file_channel: usize,
+    pub output_channel: usize,
+    pub gain: f32,
+}
+
+impl Route {
+    pub fn new(file_channel: usize, output_channel: usize, gain: f32) -> Route {
+        Route {
+            file_channel,
+            output_channel,
+            gain,
+        }
+    }
+
+    /// Routes each file channel to (at most) one output channel, without changing the gain
+    pub fn from_channel_map(channel_map: &[Option<usize>]) -> Vec<Route> {
+        channel_map
+            .iter()
+            .enumerate()
+            .filter_map(|(file_channel, &output_channel)| {
+                output_channel.map(|output_channel| Route::new(file_channel, output_channel, 1.0))
+            })
+            .collect()
+    }
+
+    /// Sorts by file channel (keeping the order of routes with the same file channel)
+    ///
+    /// This groups the routes of each file channel, as needed by `fill_channels()`.
+    pub fn sort(routing: &mut [Route]) {
+        routing.sort_by_key(|route| route.file_channel);
+    }
+}
+
+/// Return value of `fill_channels()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Filled {
+    /// Number of frames that have been written (starting at `offset`)
+    pub frames: usize,
+    /// The end of the file has been reached before `blocksize`
+    pub end_of_file: bool,
+}
+
 pub trait AudioFileBasics {
     fn channels(&self) -> usize;
     fn frames(&self) -> usize;
     fn samplerate(&self) -> usize;
     fn seek(&mut self, frame: usize) -> Result<(), Error>;
+
+    /// Hints that the data at the current position will be needed soon (the default does nothing)
+    fn prefetch(&mut self) {}
 }
 
 pub trait AudioFileBlocks {
@@ -18,6 +68,18 @@ pub trait AudioFileBlocks {
 
     fn next_block(&mut self, max_frames: usize) -> Result<&mut Self::Block, Error>;
 
+    /// Only the file channels in `channels` have to be decoded (the default decodes all channels)
+    ///
+    /// The iterators of all other channels may be empty, but the channel numbers don't change.
+    /// Invalid channel numbers are ignored.
+    /// This should be called before reading any blocks.
+    /// After an error, the file can still be used (but more channels than necessary are decoded).
+    fn select_channels(&mut self, _channels: &[usize]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Channels that are not selected (see `select_channels()`) are filled with zeros.
+    ///
     /// Panics if `buffer` is not long enough.
     fn copy_block_to_interleaved(
         &mut self,
@@ -30,7 +92,7 @@ pub trait AudioFileBlocks {
         let channels = iterators.len();
         for frame in 0..frames {
             for channel in 0..channels {
-                buffer[frame * channels + channel] = iterators[channel].next().unwrap();
+                buffer[frame * channels + channel] = iterators[channel].next().unwrap_or(0.0);
             }
         }
         // TODO: benchmark alternative implementation
@@ -45,38 +107,67 @@ pub trait AudioFileBlocks {
         Ok(frames)
     }
 
+    /// The file channels are mixed into `channels` (from `offset` up to `blocksize`)
+    /// according to `routing`.
+    /// Additionally, each frame is multiplied by the corresponding value in `gains`.
+    /// If the file ends early, the remaining frames are left unchanged.
+    ///
+    /// `routing` has to be sorted by file channel, see `Route::sort()`.
+    ///
+    /// Panics if `routing` contains invalid channel numbers or if `gains` is too short.
     fn fill_channels<D>(
         &mut self,
-        channel_map: &[Option<usize>],
+        routing: &[Route],
+        gains: &[f32],
         blocksize: usize,
         offset: usize,
         channels: &mut [D],
-    ) -> Result<(), Error>
+    ) -> Result<Filled, Error>
     where
         D: std::ops::DerefMut<Target = [f32]>,
     {
+        debug_assert!(
+            routing
+                .windows(2)
+                .all(|pair| pair[0].file_channel <= pair[1].file_channel),
+            "routing is not sorted"
+        );
+        let start = offset;
         let mut offset = offset;
+        let mut end_of_file = false;
         while offset < blocksize {
             let file_block = self.next_block(blocksize - offset)?;
             if file_block.is_empty() {
+                end_of_file = true;
                 break;
             }
+            let frames = file_block.frames();
             let iterators = file_block.channel_iterators();
-            // TODO: check channel_map for validity?
-            for (i, &channel) in channel_map.iter().enumerate() {
-                if let Some(channel) = channel {
-                    // TODO: use iterators[i]?
-                    for (a, b) in
-                        IndexMut::index_mut(iterators, i).zip(&mut channels[channel][offset..])
-                    {
-                        *b = a;
+            // NB: The routes of each file channel are contiguous
+            let mut remaining = routing;
+            for (file_channel, iterator) in iterators.iter_mut().enumerate() {
+                let count = remaining
+                    .iter()
+                    .take_while(|route| route.file_channel == file_channel)
+                    .count();
+                let (routes, rest) = remaining.split_at(count);
+                remaining = rest;
+                if routes.is_empty() {
+                    continue;
+                }
+                for (i, a) in iterator.enumerate() {
+                    for route in routes {
+                        channels[route.output_channel][offset + i] +=
+                            a * route.gain * gains[offset + i];
                     }
                 }
             }
-            offset += file_block.frames();
+            offset += frames;
         }
-        // TODO: return number of frames?
-        Ok(())
+        Ok(Filled {
+            frames: offset - start,
+            end_of_file,
+        })
     }
 }
 
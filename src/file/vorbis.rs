@@ -1,3 +1,6 @@
+// NB: For #[derive(Fail)], see src/streamer.rs
+#![allow(unknown_lints, non_local_definitions)]
+
 use std::fmt;
 use std::io::{self, Read, Seek};
 
@@ -50,7 +53,7 @@ where
     let ptr = ptr as *mut u8;
     assert!(!datasource.is_null());
     let reader = unsafe { &mut *(datasource as *mut R) };
-    let buffer = unsafe { std::slice::from_raw_parts_mut(ptr, (size * nmemb) as usize) };
+    let buffer = unsafe { std::slice::from_raw_parts_mut(ptr, size * nmemb) };
     loop {
         match reader.read(buffer) {
             Ok(bytes) => return bytes,
@@ -73,7 +76,8 @@ where
         libc::SEEK_SET => reader.seek(io::SeekFrom::Start(offset as u64)),
         libc::SEEK_CUR => reader.seek(io::SeekFrom::Current(offset)),
         libc::SEEK_END => reader.seek(io::SeekFrom::End(offset)),
-        w => panic!("Invalid value for \"whence\": {}", w),
+        // NB: Panicking is not an option in a callback from C
+        _ => return -1,
     };
     result.map(|v| v as c_int).unwrap_or(-1)
 }
@@ -84,10 +88,7 @@ where
 {
     assert!(!datasource.is_null());
     let reader = unsafe { &mut *(datasource as *mut R) };
-    reader
-        .seek(io::SeekFrom::Current(0))
-        .map(|v| v as c_long)
-        .unwrap_or(-1)
+    reader.stream_position().map(|v| v as c_long).unwrap_or(-1)
 }
 
 extern "C" fn close_func(_datasource: *mut c_void) -> c_int {
@@ -118,7 +119,7 @@ impl fmt::Display for LibVorbisError {
                 }
                 OV_EBADLINK => "Invalid stream section, or the requested link is corrupt",
                 OV_ENOSEEK => "Bitstream is not seekable",
-                e => panic!("Unknown Vorbis error code: {}", e),
+                e => return write!(f, "Vorbis error: Unknown error code {}", e),
             }
         )
     }
@@ -155,27 +156,21 @@ impl<R> File<R>
 where
     R: Read + Seek,
 {
-    // TODO: allow using only a subset of all channels!
-
     pub fn new(reader: R) -> Result<File<R>, OpenError> {
         // https://xiph.org/vorbis/doc/vorbisfile/ov_callbacks.html
         let callbacks = vorbisfile_sys::ov_callbacks {
             read_func: read_func::<R>,
             seek_func: seek_func::<R>,
-            close_func: close_func,
+            close_func,
             tell_func: tell_func::<R>,
         };
-        // TODO: use MaybeUninit::uninit()?
-        // TODO: or use zeroed()?
-        let mut ov_struct: vorbisfile_sys::OggVorbis_File = unsafe { std::mem::uninitialized() };
-        //let mut ov_struct: vorbisfile_sys::OggVorbis_File = unsafe {
-        //    std::mem::MaybeUninit::uninit() };
+        let mut ov_struct = std::mem::MaybeUninit::<vorbisfile_sys::OggVorbis_File>::uninit();
         let mut reader = Box::new(reader);
         let result: c_int = unsafe {
             // https://xiph.org/vorbis/doc/vorbisfile/ov_open_callbacks.html
             vorbisfile_sys::ov_open_callbacks(
                 &mut *reader as *mut R as *mut c_void,
-                &mut ov_struct,
+                ov_struct.as_mut_ptr(),
                 std::ptr::null(),
                 0,
                 callbacks,
@@ -184,7 +179,8 @@ where
         if result != 0 {
             return Err(OpenError::Vorbis(LibVorbisError(result)));
         }
-        // TODO: use MaybeUninit::assume_init()?
+        // NB: ov_open_callbacks() has initialized the struct
+        let mut ov_struct = unsafe { ov_struct.assume_init() };
         assert!(ov_struct.links > 0);
         let info = unsafe { &*ov_struct.vi };
         let rate = info.rate;
@@ -215,6 +211,7 @@ where
                         .map(|_| Channel {
                             ptr: std::ptr::null_mut(),
                             len: 0,
+                            selected: true,
                         })
                         .collect(),
                 },
@@ -228,6 +225,8 @@ where
     R: Read + Seek,
 {
     fn seek(&mut self, frame: usize) -> Result<(), Error> {
+        // NB: Seeking past the end would be an error
+        let frame = std::cmp::min(frame, self.frames);
         // https://xiph.org/vorbis/doc/vorbisfile/ov_pcm_seek.html
         let result =
             unsafe { vorbisfile_sys::ov_pcm_seek(&mut self.ov_struct, frame as ogg_int64_t) };
@@ -274,6 +273,14 @@ where
         self.current_block.frames = result as usize;
         Ok(&mut self.current_block)
     }
+
+    // NB: libvorbis always decodes all channels, but the others are not copied
+    fn select_channels(&mut self, channels: &[usize]) -> Result<(), Error> {
+        for (i, channel) in self.current_block.channels.iter_mut().enumerate() {
+            channel.selected = channels.contains(&i);
+        }
+        Ok(())
+    }
 }
 
 pub struct Block {
@@ -289,7 +296,7 @@ impl super::Block for Block {
         for i in 0..self.channels.len() {
             let channel = &mut self.channels[i];
             channel.ptr = unsafe { *self.ptr.add(i) };
-            channel.len = self.frames;
+            channel.len = if channel.selected { self.frames } else { 0 };
         }
         &mut self.channels
     }
@@ -302,6 +309,7 @@ impl super::Block for Block {
 pub struct Channel {
     ptr: *mut f32,
     len: usize,
+    selected: bool,
 }
 
 impl Iterator for Channel {
@@ -1,7 +1,9 @@
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, SeekFrom};
 
 use failure::Error;
 
+pub use super::buffer::{Block, Channel};
+
 pub struct File<R>
 where
     R: Read + Seek,
@@ -10,6 +12,9 @@ where
     // NB: No dynamic memory is allocated when using zero-sized types (which we do)
     block_reader: Box<dyn BlockReader<R>>,
     current_block: Block,
+    /// The file might be shorter than announced in its header
+    available_frames: usize,
+    position: usize,
 }
 
 unsafe impl<R: Read + Seek + Send> Send for File<R> {}
@@ -18,40 +23,56 @@ impl<R> File<R>
 where
     R: Read + Seek,
 {
-    pub fn new(reader: R) -> Result<File<R>, hound::Error> {
+    pub fn new(mut reader: R) -> Result<File<R>, hound::Error> {
         // TODO: same buffer size as Converter?
         let buffer_size = 2048;
 
-        // TODO: channel selection?
+        // NB: hound doesn't report the end of a truncated file as a distinct error,
+        // therefore the available data is determined before reading.
+        // After parsing the header, the reader is positioned at the start of the data.
+        let start = reader.stream_position()?;
+        let mut reader = hound::WavReader::new(reader)?.into_inner();
+        let data_offset = reader.stream_position()?;
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(start))?;
 
         let reader = hound::WavReader::new(reader)?;
         let spec = reader.spec();
+        let block_reader: Box<dyn BlockReader<R>> = {
+            use hound::SampleFormat::{Float, Int};
+            match (spec.sample_format, spec.bits_per_sample) {
+                (Float, 32) => Box::new(FloatFormat),
+                (Int, 8) => Box::new(Pcm8Format),
+                (Int, 16) => Box::new(Pcm16Format),
+                (Int, 24) => Box::new(Pcm24Format),
+                (Int, 32) => Box::new(Pcm32Format),
+                _ => return Err(hound::Error::Unsupported),
+            }
+        };
+        let frame_bytes = spec.channels as u64 * (spec.bits_per_sample / 8) as u64;
+        let available_frames = (file_len - data_offset)
+            .checked_div(frame_bytes)
+            .unwrap_or(0)
+            .min(reader.duration() as u64) as usize;
         Ok(File {
             reader,
-            block_reader: {
-                use hound::SampleFormat::{Float, Int};
-                match (spec.sample_format, spec.bits_per_sample) {
-                    (Float, 32) => Box::new(FloatFormat),
-                    (Int, 16) => Box::new(Pcm16Format),
-                    // TODO:
-                    _ => unimplemented!(),
-                }
-            },
-            current_block: Block {
-                channels: (0..spec.channels)
-                    .map(|_| Channel {
-                        data: (0..buffer_size).map(|_| 0.0f32).collect(),
-                        index: 0,
-                        stop: 0,
-                    })
-                    .collect(),
-                len_frames: 0,
-                capacity_frames: buffer_size,
-            },
+            block_reader,
+            current_block: Block::new(spec.channels as usize, buffer_size),
+            available_frames,
+            position: 0,
         })
     }
 }
 
+impl<R> File<R>
+where
+    R: Read + Seek,
+{
+    pub fn spec(&self) -> hound::WavSpec {
+        self.reader.spec()
+    }
+}
+
 impl<R> super::AudioFileBasics for File<R>
 where
     R: Read + Seek,
@@ -69,7 +90,11 @@ where
     }
 
     fn seek(&mut self, frame: usize) -> Result<(), Error> {
-        Ok(self.reader.seek(frame as u32)?)
+        // NB: Clamping also avoids truncation to u32
+        let frame = std::cmp::min(frame, self.frames());
+        self.reader.seek(frame as u32)?;
+        self.position = frame;
+        Ok(())
     }
 }
 
@@ -81,17 +106,20 @@ where
 
     fn fill_block(
         &self,
-        mut reader: &mut hound::WavReader<R>,
+        reader: &mut hound::WavReader<R>,
         block: &mut Block,
         max_frames: usize,
     ) -> hound::Result<()> {
-        // TODO: channel selection
         let max_frames = std::cmp::min(max_frames, block.capacity_frames);
         let mut frame = 0;
         'outer: while frame < max_frames {
             for channel in block.channels.iter_mut() {
-                if let Some(sample) = self.next_sample(&mut reader) {
-                    channel.data[frame] = sample?;
+                if let Some(sample) = self.next_sample(reader) {
+                    // NB: Samples of all channels have to be read
+                    let sample = sample?;
+                    if channel.selected {
+                        channel.data[frame] = sample;
+                    }
                 } else {
                     // This should only ever happen in the first channel, but we don't check this!
                     break 'outer;
@@ -101,7 +129,7 @@ where
         }
         for channel in block.channels.iter_mut() {
             channel.index = 0;
-            channel.stop = frame;
+            channel.stop = if channel.selected { frame } else { 0 };
         }
         block.len_frames = frame;
         Ok(())
@@ -122,9 +150,23 @@ where
     }
 }
 
-// TODO: Pcm8Format
-// TODO: Pcm24Format
-// TODO: Pcm32Format
+/// NB: `hound` converts unsigned 8-bit samples to `i8`
+struct Pcm8Format;
+
+impl<R> BlockReader<R> for Pcm8Format
+where
+    R: Read + Seek,
+{
+    fn next_sample(&self, reader: &mut hound::WavReader<R>) -> Option<hound::Result<f32>>
+    where
+        R: Read,
+    {
+        reader
+            .samples::<i8>()
+            .next()
+            .map(|result| result.map(|sample| sample as f32 / i8::MAX as f32))
+    }
+}
 
 struct Pcm16Format;
 
@@ -140,7 +182,42 @@ where
         reader
             .samples::<i16>()
             .next()
-            .map(|result| result.map(|sample| sample as f32 / i16::max_value() as f32))
+            .map(|result| result.map(|sample| sample as f32 / i16::MAX as f32))
+    }
+}
+
+struct Pcm24Format;
+
+impl<R> BlockReader<R> for Pcm24Format
+where
+    R: Read + Seek,
+{
+    fn next_sample(&self, reader: &mut hound::WavReader<R>) -> Option<hound::Result<f32>>
+    where
+        R: Read,
+    {
+        reader
+            .samples::<i32>()
+            .next()
+            .map(|result| result.map(|sample| sample as f32 / 8_388_607.0))
+    }
+}
+
+/// NB: Only the upper 24 bits are kept in `f32`
+struct Pcm32Format;
+
+impl<R> BlockReader<R> for Pcm32Format
+where
+    R: Read + Seek,
+{
+    fn next_sample(&self, reader: &mut hound::WavReader<R>) -> Option<hound::Result<f32>>
+    where
+        R: Read,
+    {
+        reader
+            .samples::<i32>()
+            .next()
+            .map(|result| result.map(|sample| sample as f32 / i32::MAX as f32))
     }
 }
 
@@ -151,52 +228,19 @@ where
     type Block = Block;
 
     fn next_block(&mut self, max_frames: usize) -> Result<&mut Block, Error> {
+        let max_frames = std::cmp::min(
+            max_frames,
+            self.available_frames.saturating_sub(self.position),
+        );
         // Dynamic dispatch based on sample format (FloatFormat, Pcm16Format, etc.):
         self.block_reader
             .fill_block(&mut self.reader, &mut self.current_block, max_frames)?;
-
-        // TODO: channel selection?
-
+        self.position += self.current_block.len_frames;
         Ok(&mut self.current_block)
     }
-}
-
-pub struct Block {
-    channels: Box<[Channel]>,
-    len_frames: usize,
-    capacity_frames: usize,
-}
-
-impl super::Block for Block {
-    type Channel = Channel;
-
-    fn channel_iterators(&mut self) -> &mut [Channel] {
-        &mut self.channels
-    }
-
-    fn frames(&self) -> usize {
-        self.len_frames
-    }
-}
-
-pub struct Channel {
-    data: Box<[f32]>,
-    index: usize,
-    stop: usize,
-}
 
-impl Iterator for Channel {
-    type Item = f32;
-
-    fn next(&mut self) -> Option<f32> {
-        if self.index == self.stop {
-            None
-        } else {
-            let value = self.data[self.index];
-            self.index += 1;
-            Some(value)
-        }
+    fn select_channels(&mut self, channels: &[usize]) -> Result<(), Error> {
+        self.current_block.select_channels(channels);
+        Ok(())
     }
-
-    // TODO: size_hint()?
 }
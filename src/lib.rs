@@ -1,2 +1,12 @@
+pub mod asdf;
+pub mod automation;
+pub mod cache;
+pub mod description;
+pub mod disk;
 pub mod file;
+pub mod pool;
+pub mod sample;
+pub mod scheduling;
 pub mod streamer;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
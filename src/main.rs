@@ -0,0 +1,448 @@
+//! Command-line tool for inspecting audio files and streaming playlists.
+//!
+//! Usage:
+//!
+//!     disk-streaming info FILE...
+//!     disk-streaming render [--blocksize N] [--samplerate HZ] [--bits 16|32] PLAYLIST OUTPUT.wav
+//!     disk-streaming bench [--blocksize N] [--samplerate HZ] PLAYLIST
+//!     disk-streaming verify FILE|PLAYLIST...
+//!
+//! A playlist is either a playlist description (`.json` or `.toml`, see the
+//! `description` module) or an ASDF scene (`.asdf`, see the `asdf` module).
+//! The sample rate option is only used for ASDF scenes (default: 44100),
+//! playlist descriptions specify their own sample rate (a different one is an error).
+//! Options that are not used by the given command are rejected.
+//!
+//! `render` writes 32-bit float WAV files by default.
+
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::{bail, format_err, Error};
+
+use disk_streaming::asdf::{load_scene, scene_files};
+use disk_streaming::description::PlaylistDescription;
+use disk_streaming::sample::Sample;
+use disk_streaming::streamer::{
+    load_audio_file_native, load_audio_file_with_format, DataError, FileStreamer, PlaylistEntry,
+    Route, State,
+};
+
+const USAGE: &str = "Usage:
+    disk-streaming info FILE...
+    disk-streaming render [--blocksize N] [--samplerate HZ] [--bits 16|32] PLAYLIST OUTPUT.wav
+    disk-streaming bench [--blocksize N] [--samplerate HZ] PLAYLIST
+    disk-streaming verify FILE|PLAYLIST...";
+
+struct Options {
+    blocksize: usize,
+    /// Only for ASDF scenes
+    samplerate: Option<usize>,
+    bits: u16,
+    paths: Vec<String>,
+    /// Options that were given explicitly
+    given: Vec<String>,
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        for cause in e.iter_causes() {
+            eprintln!("  caused by: {}", cause);
+        }
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Error> {
+    let mut args = std::env::args().skip(1);
+    let command = match args.next() {
+        Some(command) => command,
+        None => bail!("No command given\n{}", USAGE),
+    };
+    let mut options = Options {
+        blocksize: 1024,
+        samplerate: None,
+        bits: 32,
+        paths: Vec::new(),
+        given: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--blocksize" => options.blocksize = parse_next(&mut args, &arg)?,
+            "--samplerate" => options.samplerate = Some(parse_next(&mut args, &arg)?),
+            "--bits" => options.bits = parse_next(&mut args, &arg)?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if arg.starts_with("--") => bail!("Unknown option: {}\n{}", arg, USAGE),
+            _ => {
+                options.paths.push(arg);
+                continue;
+            }
+        }
+        options.given.push(arg);
+    }
+    if options.blocksize == 0 {
+        bail!("Invalid blocksize: 0");
+    }
+    let unused: &[&str] = match command.as_str() {
+        "info" | "verify" => &["--blocksize", "--samplerate", "--bits"],
+        "bench" => &["--bits"],
+        _ => &[],
+    };
+    if let Some(option) = options.given.iter().find(|o| unused.contains(&o.as_str())) {
+        bail!("{} cannot be used with \"{}\"\n{}", option, command, USAGE);
+    }
+    match (command.as_str(), options.paths.len()) {
+        ("info", n) if n > 0 => info(&options.paths),
+        ("render", 2) => render(&options),
+        ("bench", 1) => bench(&options),
+        ("verify", n) if n > 0 => verify(&options.paths),
+        ("-h", _) | ("--help", _) | ("help", _) => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        ("info", _) | ("render", _) | ("bench", _) | ("verify", _) => {
+            bail!("Wrong number of arguments\n{}", USAGE)
+        }
+        _ => bail!("Unknown command: {}\n{}", command, USAGE),
+    }
+}
+
+fn info(paths: &[String]) -> Result<(), Error> {
+    for path in paths {
+        let (file, format) = load_audio_file_with_format(path)
+            .map_err(|e| format_err!("Cannot load \"{}\": {}", path, e))?;
+        println!("{}:", path);
+        println!("  format:     {}", format);
+        println!("  channels:   {}", file.channels());
+        println!(
+            "  frames:     {} ({:.3} seconds)",
+            file.frames(),
+            file.frames() as f64 / file.samplerate() as f64
+        );
+        println!("  samplerate: {}", file.samplerate());
+    }
+    Ok(())
+}
+
+/// Returns the playlist, the number of output channels and the sample rate
+fn load_playlist(
+    path: &str,
+    samplerate: Option<usize>,
+) -> Result<(Vec<PlaylistEntry>, usize, usize), Error> {
+    if is_scene(path) {
+        let samplerate = samplerate.unwrap_or(44_100);
+        let scene = load_scene(path, samplerate)?;
+        Ok((scene.playlist, scene.sources.len(), samplerate))
+    } else {
+        let description = PlaylistDescription::load(path)?;
+        match samplerate {
+            Some(samplerate) if samplerate != description.samplerate => bail!(
+                "--samplerate {} cannot be used with \"{}\" (it specifies {})",
+                samplerate,
+                path,
+                description.samplerate
+            ),
+            _ => {}
+        }
+        let valid = description.validate()?;
+        for overlap in valid.overlaps() {
+            eprintln!("Warning: {}", overlap);
+        }
+        let playlist = valid.into_playlist()?;
+        Ok((playlist, description.channels, description.samplerate))
+    }
+}
+
+fn is_scene(path: &str) -> bool {
+    path.ends_with(".asdf")
+}
+
+fn render(options: &Options) -> Result<(), Error> {
+    let (playlist, channels, samplerate) = load_playlist(&options.paths[0], options.samplerate)?;
+    let mut streamer = FileStreamer::new(playlist, options.blocksize, channels)?;
+    let spec = hound::WavSpec {
+        channels: channels as u16,
+        sample_rate: samplerate as u32,
+        bits_per_sample: options.bits,
+        sample_format: match options.bits {
+            16 => hound::SampleFormat::Int,
+            32 => hound::SampleFormat::Float,
+            bits => bail!("Unsupported number of bits: {} (only 16 and 32)", bits),
+        },
+    };
+    let mut writer = hound::WavWriter::create(&options.paths[1], spec)?;
+    let start = Instant::now();
+    let underruns = match options.bits {
+        16 => stream::<i16, _>(&mut streamer, |samples| {
+            for &sample in samples {
+                writer.write_sample(sample)?;
+            }
+            Ok(())
+        })?,
+        _ => stream::<f32, _>(&mut streamer, |samples| {
+            for &sample in samples {
+                writer.write_sample(sample)?;
+            }
+            Ok(())
+        })?,
+    };
+    writer.finalize()?;
+    println!(
+        "rendered {} frames ({:.3} seconds) in {:.3} seconds, waited for the reader {} times",
+        streamer.duration(),
+        streamer.duration() as f64 / samplerate as f64,
+        start.elapsed().as_secs_f64(),
+        underruns,
+    );
+    Ok(())
+}
+
+fn bench(options: &Options) -> Result<(), Error> {
+    let (playlist, channels, samplerate) = load_playlist(&options.paths[0], options.samplerate)?;
+    let mut streamer = FileStreamer::new(playlist, options.blocksize, channels)?;
+    let start = Instant::now();
+    let underruns = stream::<f32, _>(&mut streamer, |_| Ok(()))?;
+    let seconds = start.elapsed().as_secs_f64();
+    let duration = streamer.duration() as f64 / samplerate as f64;
+    // NB: usize::div_ceil() would need Rust 1.73
+    #[allow(clippy::manual_div_ceil)]
+    let blocks = (streamer.duration() + options.blocksize - 1) / options.blocksize;
+    let factor = duration / seconds;
+    println!(
+        "streamed {:.3} seconds ({} blocks of {} frames) in {:.3} seconds",
+        duration, blocks, options.blocksize, seconds
+    );
+    println!("{:.1}x real time", factor);
+    // NB: When streaming faster than real time, the reader can't always keep up
+    println!(
+        "underruns: {} (in {:.1}% of the blocks)",
+        underruns,
+        underruns as f64 * 100.0 / blocks.max(1) as f64
+    );
+    if factor >= 1.0 {
+        println!("headroom: {:.1}%", (1.0 - 1.0 / factor) * 100.0);
+    } else {
+        println!("headroom: none, the reader is too slow for real-time playback");
+    }
+    Ok(())
+}
+
+/// Plays the whole playlist as fast as possible, `write` is called with interleaved samples
+///
+/// Returns the number of underruns (an underrun can span several calls to `get_data()`).
+fn stream<S, F>(streamer: &mut FileStreamer, mut write: F) -> Result<usize, Error>
+where
+    S: Sample,
+    F: FnMut(&[S]) -> Result<(), Error>,
+{
+    let channels = streamer.channels();
+    let duration = streamer.duration();
+    let mut buffer = vec![S::from_f32(0.0); streamer.blocksize() * channels];
+    while !streamer.seek(0) {
+        if streamer.state() == State::Failed {
+            return Err(reader_failed(streamer));
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    // NB: Starting to roll with zero frames avoids the fade-in
+    if !streamer.get_data_interleaved::<S>(&mut [], true) {
+        return Err(data_error(streamer));
+    }
+    let mut position = 0;
+    let mut underruns = 0;
+    let mut waiting = false;
+    while position < duration {
+        let success = streamer.get_data_interleaved(&mut buffer, true);
+        // NB: After an underrun, only the available part of the buffer is valid
+        let next_position = streamer.position();
+        let frames = next_position.min(duration) - position;
+        write(&buffer[..frames * channels])?;
+        position = next_position;
+        if !success {
+            if streamer.data_error() != Some(DataError::Underrun) {
+                return Err(data_error(streamer));
+            }
+            if !waiting {
+                underruns += 1;
+            }
+            waiting = true;
+            thread::yield_now();
+        } else {
+            waiting = false;
+        }
+    }
+    Ok(underruns)
+}
+
+fn data_error(streamer: &FileStreamer) -> Error {
+    match streamer.data_error() {
+        Some(DataError::ReaderFailed) => reader_failed(streamer),
+        Some(e) => e.into(),
+        None => format_err!("Unknown error"),
+    }
+}
+
+fn reader_failed(streamer: &FileStreamer) -> Error {
+    match streamer.reader_error() {
+        Some(message) => format_err!("{}: {}", DataError::ReaderFailed, message),
+        None => DataError::ReaderFailed.into(),
+    }
+}
+
+/// Number of frames that are compared after each seek
+const VERIFY_FRAMES: usize = 1000;
+
+fn verify(paths: &[String]) -> Result<(), Error> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for path in paths {
+        if path.ends_with(".json") || path.ends_with(".toml") {
+            let description = PlaylistDescription::load(path)?;
+            let directory = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+            for entry in &description.entries {
+                let file = directory.join(&entry.path);
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        } else if is_scene(path) {
+            for file in scene_files(path)? {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        } else {
+            files.push(path.into());
+        }
+    }
+    let mut failed = 0;
+    for file in &files {
+        match verify_file(file) {
+            Ok(frames) => println!("OK: {} ({} frames)", file.display(), frames),
+            Err(e) => {
+                println!("FAILED: {}: {}", file.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} files failed verification", failed, files.len());
+    }
+    Ok(())
+}
+
+/// Decodes the whole file, compares the length and seeks back to a few positions
+///
+/// Returns the number of frames.
+fn verify_file(path: &Path) -> Result<usize, Error> {
+    let mut file = load_audio_file_native(path)?;
+    let frames = file.frames();
+    let channels = file.channels();
+    let routing: Vec<_> = (0..channels).map(|c| Route::new(c, c, 1.0)).collect();
+    let blocksize = 1024;
+    let gains = vec![1.0; blocksize];
+    let mut buffer: Vec<Box<[f32]>> = (0..channels)
+        .map(|_| vec![0.0; blocksize].into_boxed_slice())
+        .collect();
+
+    let mut seek_points = vec![
+        0,
+        frames / 3,
+        frames / 2,
+        frames * 2 / 3,
+        frames.saturating_sub(VERIFY_FRAMES),
+    ];
+    seek_points.dedup();
+    // Frames after each seek point, recorded while decoding sequentially
+    let mut expected: Vec<Vec<Vec<f32>>> = seek_points
+        .iter()
+        .map(|_| vec![Vec::new(); channels])
+        .collect();
+
+    let mut decoded = 0;
+    loop {
+        for channel in buffer.iter_mut() {
+            for sample in channel.iter_mut() {
+                *sample = 0.0;
+            }
+        }
+        let filled = file.fill_channels(&routing, &gains, blocksize, 0, &mut buffer)?;
+        for (&point, expected) in seek_points.iter().zip(expected.iter_mut()) {
+            let start = point.max(decoded);
+            let end = (point + VERIFY_FRAMES).min(decoded + filled.frames);
+            if start < end {
+                for (channel, data) in expected.iter_mut().enumerate() {
+                    data.extend_from_slice(&buffer[channel][start - decoded..end - decoded]);
+                }
+            }
+        }
+        decoded += filled.frames;
+        if filled.end_of_file || filled.frames == 0 {
+            break;
+        }
+        if decoded > frames {
+            bail!("More than the reported {} frames could be decoded", frames);
+        }
+    }
+    if decoded != frames {
+        bail!(
+            "Reported {} frames, but {} could be decoded",
+            frames,
+            decoded
+        );
+    }
+
+    // NB: Seeking backwards first
+    for (&point, expected) in seek_points.iter().zip(expected.iter()).rev() {
+        file.seek(point)?;
+        let length = expected.first().map_or(0, |data| data.len());
+        let mut offset = 0;
+        while offset < length {
+            for channel in buffer.iter_mut() {
+                for sample in channel.iter_mut() {
+                    *sample = 0.0;
+                }
+            }
+            let frames = (length - offset).min(blocksize);
+            let filled = file.fill_channels(&routing, &gains, frames, 0, &mut buffer)?;
+            if filled.frames != frames {
+                bail!(
+                    "After seeking to frame {}, only {} frames could be decoded",
+                    point,
+                    offset + filled.frames
+                );
+            }
+            for (channel, data) in expected.iter().enumerate() {
+                let actual = &buffer[channel][..frames];
+                let expected = &data[offset..offset + frames];
+                if let Some(i) = (0..frames).find(|&i| (actual[i] - expected[i]).abs() > 1e-6) {
+                    bail!(
+                        "After seeking to frame {}, frame {} (channel {}) is {} instead of {}",
+                        point,
+                        point + offset + i,
+                        channel,
+                        actual[i],
+                        expected[i]
+                    );
+                }
+            }
+            offset += frames;
+        }
+    }
+    Ok(frames)
+}
+
+fn parse_next<I, T>(args: &mut I, name: &str) -> Result<T, Error>
+where
+    I: Iterator<Item = String>,
+    T: std::str::FromStr,
+{
+    match args.next().and_then(|value| value.parse().ok()) {
+        Some(value) => Ok(value),
+        None => bail!("Invalid value for {}", name),
+    }
+}
@@ -0,0 +1,267 @@
+//! Reader threads that are shared between multiple `FileStreamer`s.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::thread;
+use std::time::Duration;
+
+use failure::Error;
+
+use crate::scheduling::{self, ThreadOptions, ThreadWarning};
+use crate::streamer::Reader;
+
+/// A fixed number of worker threads serving any number of `FileStreamer`s
+///
+/// See `FileStreamer::with_pool()`.
+///
+/// Each worker thread repeatedly writes a block for the streamer whose queue
+/// is closest to running dry.
+/// The pool must outlive its streamers, they don't get any new data
+/// once the pool is dropped.
+pub struct ReaderPool {
+    shared: Arc<PoolShared>,
+    workers: Vec<thread::JoinHandle<()>>,
+    thread_warnings: Vec<ThreadWarning>,
+}
+
+pub(crate) struct PoolShared {
+    readers: Mutex<Vec<Arc<Mutex<PooledReader>>>>,
+    keep_running: AtomicBool,
+}
+
+pub(crate) struct PooledReader {
+    reader: Reader,
+    /// After an error, the reader is not used anymore
+    error: Option<Error>,
+}
+
+impl PooledReader {
+    fn step(&mut self) -> bool {
+        match self.reader.guarded_step() {
+            Ok(result) => result,
+            Err(e) => {
+                self.error = Some(e);
+                false
+            }
+        }
+    }
+}
+
+impl ReaderPool {
+    /// Panics if `workers` is zero.
+    pub fn new(workers: usize) -> ReaderPool {
+        ReaderPool::with_thread_options(workers, &Default::default())
+    }
+
+    /// Like `new()`, but with scheduling options for all worker threads.
+    ///
+    /// The worker number is appended to the thread name.
+    /// Options that cannot be applied are reported by `thread_warnings()`.
+    pub fn with_thread_options(workers: usize, options: &ThreadOptions) -> ReaderPool {
+        assert!(workers > 0, "At least one worker thread is needed");
+        let shared = Arc::new(PoolShared {
+            readers: Mutex::new(Vec::new()),
+            keep_running: AtomicBool::new(true),
+        });
+        let mut thread_warnings = Vec::new();
+        let workers = (0..workers)
+            .map(|i| {
+                let shared = Arc::clone(&shared);
+                let (worker, warnings) =
+                    scheduling::spawn(options, &format!("-{}", i), move || work(&shared));
+                thread_warnings.extend(warnings);
+                worker
+            })
+            .collect();
+        ReaderPool {
+            shared,
+            workers,
+            thread_warnings,
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Scheduling options that couldn't be applied to the worker threads
+    pub fn thread_warnings(&self) -> &[ThreadWarning] {
+        &self.thread_warnings
+    }
+
+    pub(crate) fn add(&self, reader: Reader) -> (Arc<PoolShared>, Arc<Mutex<PooledReader>>) {
+        let reader = Arc::new(Mutex::new(PooledReader {
+            reader,
+            error: None,
+        }));
+        self.shared
+            .readers
+            .lock()
+            .unwrap()
+            .push(Arc::clone(&reader));
+        (Arc::clone(&self.shared), reader)
+    }
+}
+
+impl Drop for ReaderPool {
+    fn drop(&mut self) {
+        self.shared.keep_running.store(false, Ordering::Release);
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+impl PoolShared {
+    /// Returns the error that has stopped the reader (if any)
+    pub(crate) fn remove(&self, reader: &Arc<Mutex<PooledReader>>) -> Result<(), Error> {
+        self.readers
+            .lock()
+            .unwrap()
+            .retain(|r| !Arc::ptr_eq(r, reader));
+        // NB: This waits until no worker thread is using the reader anymore
+        match reader.lock().unwrap().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+fn work(shared: &PoolShared) {
+    while shared.keep_running.load(Ordering::Acquire) {
+        if !work_once(shared) {
+            // TODO: configurable sleep time?
+            thread::sleep(Duration::from_micros(1_000));
+        }
+    }
+}
+
+/// Writes one block for the most urgent reader.
+///
+/// Return value of `false` means that there was nothing to do.
+fn work_once(shared: &PoolShared) -> bool {
+    let most_urgent = {
+        let readers = shared.readers.lock().unwrap();
+        let mut most_urgent: Option<(usize, &Arc<Mutex<PooledReader>>)> = None;
+        for reader in readers.iter() {
+            // Readers that are locked are currently used by another worker
+            if let Ok(guard) = reader.try_lock() {
+                if guard.error.is_some() {
+                    continue;
+                }
+                if let Some(urgency) = guard.reader.urgency() {
+                    if !matches!(most_urgent, Some((u, _)) if u <= urgency) {
+                        most_urgent = Some((urgency, reader));
+                    }
+                }
+            }
+        }
+        most_urgent.map(|(_, reader)| Arc::clone(reader))
+    };
+    match most_urgent {
+        Some(reader) => match reader.try_lock() {
+            Ok(mut guard) => guard.step(),
+            // Another worker was faster
+            Err(_) => true,
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::file::{memory, Route};
+    use crate::streamer::{FileStreamer, PlaylistEntry};
+
+    const BLOCKSIZE: usize = 1024;
+
+    /// Work has to be done explicitly with `work_once()`
+    fn pool_without_workers() -> ReaderPool {
+        ReaderPool {
+            shared: Arc::new(PoolShared {
+                readers: Mutex::new(Vec::new()),
+                keep_running: AtomicBool::new(true),
+            }),
+            workers: Vec::new(),
+            thread_warnings: Vec::new(),
+        }
+    }
+
+    fn streamer(pool: &ReaderPool) -> FileStreamer {
+        let data = memory::Data::from_channels(44_100, vec![vec![0.5; 100 * BLOCKSIZE]]);
+        let file = Box::new(memory::File::new(Arc::new(data)));
+        let entry = PlaylistEntry::new(0, None, file, Route::from_channel_map(&[Some(0)])).unwrap();
+        FileStreamer::with_pool(vec![entry], BLOCKSIZE, 1, pool).unwrap()
+    }
+
+    /// In the order the streamers were created
+    fn urgencies(pool: &ReaderPool) -> Vec<Option<usize>> {
+        pool.shared
+            .readers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|reader| reader.lock().unwrap().reader.urgency())
+            .collect()
+    }
+
+    fn consume(streamer: &mut FileStreamer, blocks: usize) {
+        let mut buffer = vec![0.0f32; BLOCKSIZE];
+        for _ in 0..blocks {
+            assert!(streamer.get_data_interleaved(&mut buffer, true));
+        }
+    }
+
+    #[test]
+    fn most_urgent_reader_first() {
+        let pool = pool_without_workers();
+        let mut streamers: Vec<_> = (0..3).map(|_| streamer(&pool)).collect();
+        while work_once(&pool.shared) {}
+        assert_eq!(urgencies(&pool), [None, None, None]);
+        for streamer in &mut streamers {
+            assert!(streamer.is_ready(0));
+        }
+        consume(&mut streamers[1], 2);
+        consume(&mut streamers[2], 4);
+        let mut order = Vec::new();
+        loop {
+            let before = urgencies(&pool);
+            // NB: On ties, the first one is used
+            let expected = (0..before.len())
+                .filter(|&i| before[i].is_some())
+                .min_by_key(|&i| before[i]);
+            if !work_once(&pool.shared) {
+                assert_eq!(expected, None);
+                break;
+            }
+            let after = urgencies(&pool);
+            let changed: Vec<_> = (0..before.len())
+                .filter(|&i| before[i] != after[i])
+                .collect();
+            assert_eq!(changed, [expected.unwrap()]);
+            order.push(changed[0]);
+        }
+        assert_eq!(order, [2, 2, 1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn drop_while_work_is_queued() {
+        let pool = ReaderPool::new(2);
+        let mut streamers: Vec<_> = (0..4).map(|_| streamer(&pool)).collect();
+        drop(pool);
+        // The workers have stopped, new requests are not served anymore
+        for streamer in &mut streamers {
+            assert!(!streamer.is_ready(50 * BLOCKSIZE));
+        }
+        thread::sleep(Duration::from_millis(20));
+        for streamer in &mut streamers {
+            assert!(!streamer.is_ready(50 * BLOCKSIZE));
+        }
+        // NB: Dropping the streamers must not block
+        drop(streamers);
+    }
+}
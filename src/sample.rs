@@ -0,0 +1,85 @@
+//! Sample types for the output of `FileStreamer::get_data()`.
+//!
+//! Decoding and mixing is always done with `f32`,
+//! other sample types are converted when the data is written to the output buffers.
+//! Integers use the same scaling as the WAV decoders,
+//! i.e. 16-bit sources (to `i16`) and 24-bit sources (to `i32`) pass through unchanged
+//! if they are not mixed and their gain is `1.0`
+//! (except for the most negative value, which is clipped).
+//! `i32` output contains 24-bit values in its upper bits:
+//! `f32` cannot represent more than 24 bits, therefore the lowest 8 bits of
+//! 32-bit integer sources are lost.
+
+pub trait Sample: Copy {
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Sample for f32 {
+    fn from_f32(value: f32) -> f32 {
+        value
+    }
+}
+
+impl Sample for f64 {
+    fn from_f32(value: f32) -> f64 {
+        value as f64
+    }
+}
+
+/// Values outside of `-1.0..=1.0` are clipped
+impl Sample for i16 {
+    fn from_f32(value: f32) -> i16 {
+        (value.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+    }
+}
+
+/// Values outside of `-1.0..=1.0` are clipped, the lowest 8 bits are always zero
+impl Sample for i32 {
+    fn from_f32(value: f32) -> i32 {
+        // NB: Rounding to 24 bits first keeps 24-bit sources exact
+        ((value.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32) << 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn i16_round_trip() {
+        // Same scaling as in the WAV decoders
+        for sample in -32_767..=i16::MAX {
+            assert_eq!(i16::from_f32(sample as f32 / 32_767.0), sample);
+        }
+        assert_eq!(i16::from_f32(i16::MIN as f32 / 32_767.0), -32_767);
+    }
+
+    #[test]
+    fn i32_round_trip() {
+        for sample in -8_388_607..=8_388_607 {
+            assert_eq!(i32::from_f32(sample as f32 / 8_388_607.0), sample << 8);
+        }
+    }
+
+    #[test]
+    fn full_scale() {
+        assert_eq!(i16::from_f32(1.0), i16::MAX);
+        assert_eq!(i16::from_f32(-1.0), -i16::MAX);
+        assert_eq!(i32::from_f32(1.0), 0x7FFF_FF00);
+        assert_eq!(i32::from_f32(-1.0), -0x7FFF_FF00);
+        assert_eq!(i16::from_f32(0.0), 0);
+        assert_eq!(i32::from_f32(0.0), 0);
+    }
+
+    #[test]
+    fn clipping() {
+        for &value in &[1.0001, 2.0, 1e10, f32::INFINITY] {
+            assert_eq!(i16::from_f32(value), i16::MAX);
+            assert_eq!(i16::from_f32(-value), -i16::MAX);
+            assert_eq!(i32::from_f32(value), 0x7FFF_FF00);
+            assert_eq!(i32::from_f32(-value), -0x7FFF_FF00);
+        }
+        assert_eq!(f64::from_f32(2.0), 2.0);
+        assert_eq!(f32::from_f32(-2.0), -2.0);
+    }
+}
@@ -0,0 +1,334 @@
+//! Scheduling options for reader threads.
+//!
+//! Failing to apply an option (e.g. because of missing permissions for realtime scheduling)
+//! doesn't prevent the thread from being started, a `ThreadWarning` is reported instead.
+
+// NB: For #[derive(Fail)], see src/streamer.rs
+#![allow(unknown_lints, non_local_definitions)]
+
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+use failure::Fail;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RealtimePolicy {
+    /// `SCHED_FIFO`
+    Fifo,
+    /// `SCHED_RR`
+    RoundRobin,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ThreadOptions {
+    /// Thread name (Linux only shows the first 15 bytes)
+    pub name: Option<String>,
+    /// Realtime scheduling policy and priority
+    pub realtime: Option<(RealtimePolicy, i32)>,
+    /// Only used if realtime scheduling is not requested or cannot be set
+    pub nice: Option<i32>,
+    /// CPU numbers the thread is allowed to run on (Linux only)
+    pub cpu_affinity: Option<Vec<usize>>,
+}
+
+#[derive(Debug, Fail)]
+pub enum ThreadWarning {
+    #[fail(display = "Invalid thread name (it contains a null byte): {:?}", _0)]
+    Name(String),
+    #[fail(display = "Could not set realtime scheduling: {}", _0)]
+    Realtime(#[cause] io::Error),
+    #[fail(display = "Could not set nice level: {}", _0)]
+    Nice(#[cause] io::Error),
+    #[fail(display = "Could not set CPU affinity: {}", _0)]
+    CpuAffinity(#[cause] io::Error),
+}
+
+/// Like `std::thread::spawn()`, but applies `options` to the new thread
+///
+/// `suffix` is appended to the thread name (if there is one).
+///
+/// Panics if the thread cannot be created (just like `std::thread::spawn()`).
+pub(crate) fn spawn<F, T>(
+    options: &ThreadOptions,
+    suffix: &str,
+    f: F,
+) -> (thread::JoinHandle<T>, Vec<ThreadWarning>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let mut builder = thread::Builder::new();
+    let mut name_warning = None;
+    if let Some(ref name) = options.name {
+        let name = format!("{}{}", name, suffix);
+        // NB: std::thread::Builder::spawn() would panic
+        if name.contains('\0') {
+            name_warning = Some(ThreadWarning::Name(name));
+        } else {
+            builder = builder.name(name);
+        }
+    }
+    let options = options.clone();
+    let (sender, receiver) = mpsc::channel();
+    let handle = builder
+        .spawn(move || {
+            // The receiving end is waiting, this cannot fail
+            sender.send(apply(&options)).unwrap();
+            f()
+        })
+        .unwrap();
+    let mut warnings: Vec<_> = name_warning.into_iter().collect();
+    warnings.extend(receiver.recv().unwrap());
+    (handle, warnings)
+}
+
+/// The system calls used by `apply()` (replaced in tests)
+trait Scheduler {
+    fn set_realtime(&self, policy: RealtimePolicy, priority: i32) -> io::Result<()>;
+    fn set_nice(&self, nice: i32) -> io::Result<()>;
+    fn set_cpu_affinity(&self, cpus: &[usize]) -> io::Result<()>;
+}
+
+struct System;
+
+impl Scheduler for System {
+    fn set_realtime(&self, policy: RealtimePolicy, priority: i32) -> io::Result<()> {
+        set_realtime(policy, priority)
+    }
+
+    fn set_nice(&self, nice: i32) -> io::Result<()> {
+        set_nice(nice)
+    }
+
+    fn set_cpu_affinity(&self, cpus: &[usize]) -> io::Result<()> {
+        set_cpu_affinity(cpus)
+    }
+}
+
+/// Applies `options` to the current thread
+fn apply(options: &ThreadOptions) -> Vec<ThreadWarning> {
+    apply_with(options, &System)
+}
+
+fn apply_with(options: &ThreadOptions, scheduler: &dyn Scheduler) -> Vec<ThreadWarning> {
+    let mut warnings = Vec::new();
+    let mut realtime = false;
+    if let Some((policy, priority)) = options.realtime {
+        match scheduler.set_realtime(policy, priority) {
+            Ok(()) => realtime = true,
+            Err(e) => warnings.push(ThreadWarning::Realtime(e)),
+        }
+    }
+    if let Some(nice) = options.nice {
+        // NB: The nice level doesn't affect realtime threads
+        if !realtime {
+            if let Err(e) = scheduler.set_nice(nice) {
+                warnings.push(ThreadWarning::Nice(e));
+            }
+        }
+    }
+    if let Some(ref cpus) = options.cpu_affinity {
+        if let Err(e) = scheduler.set_cpu_affinity(cpus) {
+            warnings.push(ThreadWarning::CpuAffinity(e));
+        }
+    }
+    warnings
+}
+
+fn set_realtime(policy: RealtimePolicy, priority: i32) -> io::Result<()> {
+    let policy = match policy {
+        RealtimePolicy::Fifo => libc::SCHED_FIFO,
+        RealtimePolicy::RoundRobin => libc::SCHED_RR,
+    };
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    // NB: The error code is returned, errno is not set
+    let result = unsafe { libc::pthread_setschedparam(libc::pthread_self(), policy, &param) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(result))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) -> io::Result<()> {
+    // NB: On Linux, this only affects the current thread
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS as _, tid as libc::id_t, nice) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_nice(_nice: i32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Per-thread nice levels are only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_cpu_affinity(cpus: &[usize]) -> io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= 8 * std::mem::size_of::<libc::cpu_set_t>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid CPU number: {}", cpu),
+            ));
+        }
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    // NB: 0 means the current thread
+    let result =
+        unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_cpu_affinity(_cpus: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "CPU affinity is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+
+    /// Records all calls, fails the ones that are listed in `failing`
+    #[derive(Default)]
+    struct Fake {
+        failing: Vec<&'static str>,
+        calls: RefCell<Vec<&'static str>>,
+    }
+
+    impl Fake {
+        fn call(&self, name: &'static str) -> io::Result<()> {
+            self.calls.borrow_mut().push(name);
+            if self.failing.contains(&name) {
+                Err(io::Error::from_raw_os_error(libc::EPERM))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl Scheduler for Fake {
+        fn set_realtime(&self, _policy: RealtimePolicy, _priority: i32) -> io::Result<()> {
+            self.call("realtime")
+        }
+
+        fn set_nice(&self, _nice: i32) -> io::Result<()> {
+            self.call("nice")
+        }
+
+        fn set_cpu_affinity(&self, _cpus: &[usize]) -> io::Result<()> {
+            self.call("affinity")
+        }
+    }
+
+    fn all_options() -> ThreadOptions {
+        ThreadOptions {
+            name: None,
+            realtime: Some((RealtimePolicy::Fifo, 10)),
+            nice: Some(-5),
+            cpu_affinity: Some(vec![0]),
+        }
+    }
+
+    #[test]
+    fn nice_is_skipped_with_realtime() {
+        let fake = Fake::default();
+        assert!(apply_with(&all_options(), &fake).is_empty());
+        assert_eq!(*fake.calls.borrow(), ["realtime", "affinity"]);
+    }
+
+    #[test]
+    fn nice_is_used_if_realtime_fails() {
+        let fake = Fake {
+            failing: vec!["realtime"],
+            ..Default::default()
+        };
+        let warnings = apply_with(&all_options(), &fake);
+        assert!(matches!(warnings[..], [ThreadWarning::Realtime(_)]));
+        assert_eq!(*fake.calls.borrow(), ["realtime", "nice", "affinity"]);
+    }
+
+    #[test]
+    fn all_errors_are_reported() {
+        let fake = Fake {
+            failing: vec!["realtime", "nice", "affinity"],
+            ..Default::default()
+        };
+        let warnings = apply_with(&all_options(), &fake);
+        assert!(matches!(
+            warnings[..],
+            [
+                ThreadWarning::Realtime(_),
+                ThreadWarning::Nice(_),
+                ThreadWarning::CpuAffinity(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn without_options() {
+        let fake = Fake::default();
+        assert!(apply_with(&Default::default(), &fake).is_empty());
+        assert!(fake.calls.borrow().is_empty());
+    }
+
+    // The following tests use the actual system calls, they don't need any privileges
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn name_and_lower_priority() {
+        let options = ThreadOptions {
+            name: Some("test".into()),
+            nice: Some(19),
+            ..Default::default()
+        };
+        let (handle, warnings) = spawn(&options, "-1", || {
+            thread::current().name().map(String::from)
+        });
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(handle.join().unwrap().as_deref(), Some("test-1"));
+    }
+
+    #[test]
+    fn invalid_name_is_reported() {
+        let options = ThreadOptions {
+            name: Some("a\0b".into()),
+            ..Default::default()
+        };
+        let (handle, warnings) = spawn(&options, "", || thread::current().name().is_none());
+        assert!(matches!(warnings[..], [ThreadWarning::Name(_)]));
+        assert!(handle.join().unwrap());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn invalid_cpu_is_reported() {
+        let options = ThreadOptions {
+            cpu_affinity: Some(vec![usize::MAX]),
+            ..Default::default()
+        };
+        let (handle, warnings) = spawn(&options, "", || ());
+        assert!(matches!(warnings[..], [ThreadWarning::CpuAffinity(_)]));
+        handle.join().unwrap();
+    }
+}
@@ -1,17 +1,34 @@
-use std::fs;
-use std::io;
+// NB: The impls generated by #[derive(Fail)] trigger this lint on newer compilers,
+//     an #[allow] on the derived items doesn't reach them
+#![allow(unknown_lints, non_local_definitions)]
+
+use std::cell::Cell;
+use std::fmt;
+use std::io::{Read, Seek};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
 };
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crossbeam::queue;
 use failure::{Error, Fail};
 
-use crate::file::{converter, vorbis, wav, AudioFileBasics, AudioFileBlocks};
+use crate::automation::{make_gain_automation, GainAutomation, GainEnvelopes};
+use crate::disk::{DiskFile, ReadAhead, ReadOptions};
+#[cfg(unix)]
+use crate::file::mmap;
+use crate::file::{converter, memory, vorbis, wav, AudioFileBasics, AudioFileBlocks};
+use crate::pool::{PoolShared, PooledReader, ReaderPool};
+use crate::sample::Sample;
+use crate::scheduling::{self, ThreadOptions, ThreadWarning};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::{PendingRead, Uring, UringFile};
+
+pub use crate::file::{Filled, Route};
 
 enum Fade {
     In,
@@ -23,11 +40,29 @@ enum Fade {
 pub trait AudioFile: AudioFileBasics {
     fn fill_channels(
         &mut self,
-        channel_map: &[Option<usize>],
+        routing: &[Route],
+        gains: &[f32],
         blocksize: usize,
         offset: usize,
         channels: &mut [Box<[f32]>],
-    ) -> Result<(), Error>;
+    ) -> Result<Filled, Error>;
+
+    /// See `AudioFileBlocks::select_channels()`
+    fn select_channels(&mut self, channels: &[usize]) -> Result<(), Error>;
+
+    /// Starts reading the data for the next `fill_channels()` call (the default does nothing)
+    ///
+    /// This is called for all active playlist entries before any of them is filled.
+    fn submit_reads(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Data for the next `fill_channels()` call might still be on its way (the default is `false`)
+    ///
+    /// Such files are filled after all others, they are only waited for if nothing else is left.
+    fn reads_pending(&mut self) -> bool {
+        false
+    }
 }
 
 impl<B, F> AudioFile for F
@@ -38,53 +73,239 @@ where
     // This is a non-generic version of AudioFileBlocks::fill_channels():
     fn fill_channels(
         &mut self,
-        channel_map: &[Option<usize>],
+        routing: &[Route],
+        gains: &[f32],
         blocksize: usize,
         offset: usize,
         channels: &mut [Box<[f32]>],
-    ) -> Result<(), Error> {
-        self.fill_channels(channel_map, blocksize, offset, channels)
+    ) -> Result<Filled, Error> {
+        self.fill_channels(routing, gains, blocksize, offset, channels)
+    }
+
+    fn select_channels(&mut self, channels: &[usize]) -> Result<(), Error> {
+        AudioFileBlocks::select_channels(self, channels)
     }
 }
 
+/// None of the decoders could open the file
 #[fail(display = "Could not load audio file:
 Vorbis: {}
 WAV: {}", vorbis_error, wav_error)]
 #[derive(Debug, Fail)]
-struct LoadError {
+pub struct LoadError {
     vorbis_error: vorbis::OpenError,
     wav_error: hound::Error,
 }
 
+/// The decoder that was used for a file, see `load_audio_file_with_format()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    Vorbis,
+    Wav { bits: u16, float: bool },
+}
+
+impl fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileFormat::Vorbis => write!(f, "Ogg Vorbis"),
+            FileFormat::Wav { bits, float } => {
+                let format = if float { "float" } else { "PCM" };
+                write!(f, "WAV ({}-bit {})", bits, format)
+            }
+        }
+    }
+}
+
+/// Passes read-ahead hints to the kernel and submits batched reads
+struct DiskAudioFile {
+    file: Box<dyn AudioFile + Send>,
+    read_ahead: Option<ReadAhead>,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: Option<Uring>,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pending_read: Option<PendingRead>,
+}
+
+impl AudioFileBasics for DiskAudioFile {
+    fn channels(&self) -> usize {
+        self.file.channels()
+    }
+
+    fn frames(&self) -> usize {
+        self.file.frames()
+    }
+
+    fn samplerate(&self) -> usize {
+        self.file.samplerate()
+    }
+
+    fn seek(&mut self, frame: usize) -> Result<(), Error> {
+        self.file.seek(frame)
+    }
+
+    fn prefetch(&mut self) {
+        if let Some(ref read_ahead) = self.read_ahead {
+            read_ahead.will_need();
+        }
+    }
+}
+
+impl AudioFile for DiskAudioFile {
+    fn fill_channels(
+        &mut self,
+        routing: &[Route],
+        gains: &[f32],
+        blocksize: usize,
+        offset: usize,
+        channels: &mut [Box<[f32]>],
+    ) -> Result<Filled, Error> {
+        self.file
+            .fill_channels(routing, gains, blocksize, offset, channels)
+    }
+
+    fn select_channels(&mut self, channels: &[usize]) -> Result<(), Error> {
+        self.file.select_channels(channels)
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn submit_reads(&mut self) -> Result<(), Error> {
+        if let Some(ref uring) = self.uring {
+            // NB: Only the first call per batch actually submits something
+            uring.submit()?;
+        }
+        Ok(())
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn reads_pending(&mut self) -> bool {
+        match self.pending_read {
+            Some(ref pending_read) => pending_read.is_pending(),
+            None => false,
+        }
+    }
+}
+
 // TODO: loop/repeat, skip, duration ...
 
 pub fn load_audio_file<P>(path: P, samplerate: usize) -> Result<Box<dyn AudioFile + Send>, Error>
 where
     P: AsRef<Path>,
 {
-    let file = fs::File::open(&path)?;
-    let vorbis_error = match vorbis::File::new(file) {
-        Ok(file) => {
-            if file.samplerate() == samplerate {
-                return Ok(Box::new(file));
-            } else {
-                return Ok(Box::new(converter::Converter::new(file, samplerate)?));
+    load_audio_file_with_options(path, samplerate, &Default::default())
+}
+
+/// Like `load_audio_file()`, but with options for reading from disk
+pub fn load_audio_file_with_options<P>(
+    path: P,
+    samplerate: usize,
+    options: &ReadOptions,
+) -> Result<Box<dyn AudioFile + Send>, Error>
+where
+    P: AsRef<Path>,
+{
+    load(path, Some(samplerate), options)
+}
+
+/// Like `load_audio_file()`, but the file keeps its own sample rate
+pub fn load_audio_file_native<P>(path: P) -> Result<Box<dyn AudioFile + Send>, Error>
+where
+    P: AsRef<Path>,
+{
+    load(path, None, &Default::default())
+}
+
+/// Like `load_audio_file_native()`, but also returns which decoder was used
+pub fn load_audio_file_with_format<P>(
+    path: P,
+) -> Result<(Box<dyn AudioFile + Send>, FileFormat), Error>
+where
+    P: AsRef<Path>,
+{
+    decode_audio_file(|| std::fs::File::open(&path), None)
+}
+
+/// Without `samplerate`, there is no sample rate conversion
+fn load<P>(
+    path: P,
+    samplerate: Option<usize>,
+    options: &ReadOptions,
+) -> Result<Box<dyn AudioFile + Send>, Error>
+where
+    P: AsRef<Path>,
+{
+    // NB: Without mmap support, the option is ignored
+    #[cfg(unix)]
+    {
+        if options.mmap {
+            // If it's not a WAV file (or an unsupported one), the other decoders are tried
+            if let Ok(file) = mmap::File::open(&path) {
+                return convert(file, samplerate);
             }
         }
+    }
+    // NB: The file that is opened last is the one used by the decoder
+    let read_ahead = Cell::new(None);
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    let pending_read = Cell::new(None);
+    let open_disk_file = || {
+        let file = DiskFile::open(&path, options)?;
+        read_ahead.set(file.read_ahead());
+        Ok(file)
+    };
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    let file = match options.uring {
+        Some(ref uring) => {
+            decode_audio_file(
+                || {
+                    let file = UringFile::open(&path, options, uring)?;
+                    read_ahead.set(file.read_ahead());
+                    pending_read.set(Some(file.pending_read()));
+                    Ok(file)
+                },
+                samplerate,
+            )?
+            .0
+        }
+        None => decode_audio_file(open_disk_file, samplerate)?.0,
+    };
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+    let file = decode_audio_file(open_disk_file, samplerate)?.0;
+    Ok(Box::new(DiskAudioFile {
+        file,
+        read_ahead: read_ahead.into_inner(),
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        uring: options.uring.clone(),
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        pending_read: pending_read.into_inner(),
+    }))
+}
+
+/// `open` is called once for each file type that is tried
+fn decode_audio_file<R, F>(
+    open: F,
+    samplerate: Option<usize>,
+) -> Result<(Box<dyn AudioFile + Send>, FileFormat), Error>
+where
+    R: Read + Seek + Send + 'static,
+    F: Fn() -> std::io::Result<R>,
+{
+    let file = open()?;
+    let vorbis_error = match vorbis::File::new(file) {
+        Ok(file) => return Ok((convert(file, samplerate)?, FileFormat::Vorbis)),
         Err(e) => e,
     };
 
-    let file = fs::File::open(&path)?;
-    let reader = io::BufReader::new(file);
-    let wav_error = match wav::File::new(reader) {
+    let file = open()?;
+    let wav_error = match wav::File::new(file) {
         Ok(file) => {
-            if file.samplerate() == samplerate {
-                return Ok(Box::new(file));
-            } else {
-                return Ok(Box::new(converter::Converter::new(file, samplerate)?));
-            }
+            let spec = file.spec();
+            let format = FileFormat::Wav {
+                bits: spec.bits_per_sample,
+                float: spec.sample_format == hound::SampleFormat::Float,
+            };
+            return Ok((convert(file, samplerate)?, format));
         }
-        Err(e) => e
+        Err(e) => e,
     };
 
     // TODO: try more file types (FLAC, mp3, ...)
@@ -95,8 +316,26 @@ where
     })?
 }
 
+/// Adds sample rate conversion if `samplerate` is different from the one of `file`
+fn convert<F>(file: F, samplerate: Option<usize>) -> Result<Box<dyn AudioFile + Send>, Error>
+where
+    F: AudioFileBasics + AudioFileBlocks + Send + 'static,
+{
+    match samplerate {
+        Some(samplerate) if samplerate != file.samplerate() => {
+            Ok(Box::new(converter::Converter::new(file, samplerate)?))
+        }
+        _ => Ok(Box::new(file)),
+    }
+}
+
 struct Block {
     channels: Box<[Box<[f32]>]>,
+    frames: usize,
+    /// Playlist frame of the first frame of the block
+    frame: usize,
+    /// Offset within the block where the playlist starts over (when looping)
+    loop_offset: Option<usize>,
 }
 
 impl Block {
@@ -105,25 +344,46 @@ impl Block {
             channels: (0..channels)
                 .map(|_| (0..frames).map(|_| 0.0f32).collect())
                 .collect(),
+            frames,
+            frame: 0,
+            loop_offset: None,
+        }
+    }
+
+    /// Playlist frame at the given offset within the block
+    fn frame_at(&self, offset: usize, duration: usize) -> usize {
+        match self.loop_offset {
+            // NB: The playlist might be shorter than a block
+            Some(loop_offset) if offset >= loop_offset => (offset - loop_offset) % duration,
+            _ => self.frame + offset,
         }
     }
 }
 
 struct DataProducer {
+    blocksize: usize,
+    capacity: usize,
+    /// Number of blocks in the data queue
+    queued: Arc<AtomicUsize>,
     data_producer: queue::spsc::Producer<Block>,
     recycling_consumer: queue::spsc::Consumer<Block>,
 }
 
 struct DataConsumer {
-    blocksize: usize,
+    duration: usize,
+    queued: Arc<AtomicUsize>,
     data_consumer: queue::spsc::Consumer<Block>,
     recycling_producer: queue::spsc::Producer<Block>,
+    /// Block that has only partially been written to the output buffers
+    current_block: Option<Block>,
+    current_offset: usize,
 }
 
 fn make_data_queue(
     capacity: usize,
     blocksize: usize,
     channels: usize,
+    duration: usize,
 ) -> (DataProducer, DataConsumer) {
     let (data_producer, data_consumer) = queue::spsc::new(capacity);
     let (recycling_producer, recycling_consumer) = queue::spsc::new(capacity);
@@ -132,15 +392,22 @@ fn make_data_queue(
             .push(Block::new(blocksize, channels))
             .unwrap();
     }
+    let queued = Arc::new(AtomicUsize::new(0));
     (
         DataProducer {
+            blocksize,
+            capacity,
+            queued: Arc::clone(&queued),
             data_producer,
             recycling_consumer,
         },
         DataConsumer {
-            blocksize,
+            duration,
+            queued,
             data_consumer,
             recycling_producer,
+            current_block: None,
+            current_offset: 0,
         },
     )
 }
@@ -149,12 +416,14 @@ struct WriteBlock<'b> {
     // NB: Option in order to be able to move Block in drop()
     block: Option<Block>,
     queue: &'b mut queue::spsc::Producer<Block>,
+    queued: &'b AtomicUsize,
 }
 
 impl<'b> Drop for WriteBlock<'b> {
     fn drop(&mut self) {
         if let Some(block) = self.block.take() {
             self.queue.push(block).unwrap();
+            self.queued.fetch_add(1, Ordering::AcqRel);
         }
     }
 }
@@ -163,10 +432,16 @@ impl<'b> WriteBlock<'b> {
     fn channels(&mut self) -> &mut [Box<[f32]>] {
         &mut self.block.as_mut().unwrap().channels
     }
+
+    fn set_position(&mut self, frame: usize, loop_offset: Option<usize>) {
+        let block = self.block.as_mut().unwrap();
+        block.frame = frame;
+        block.loop_offset = loop_offset;
+    }
 }
 
 impl DataProducer {
-    fn write_block(&mut self) -> Option<WriteBlock> {
+    fn write_block(&mut self) -> Option<WriteBlock<'_>> {
         let mut block = match self.recycling_consumer.pop() {
             Ok(block) => block,
             _ => return None,
@@ -181,84 +456,573 @@ impl DataProducer {
         Some(WriteBlock {
             block: Some(block),
             queue: &mut self.data_producer,
+            queued: &self.queued,
         })
     }
 }
 
 impl DataConsumer {
     fn clear(&mut self) {
+        if let Some(block) = self.current_block.take() {
+            self.recycling_producer.push(block).unwrap()
+        }
         while let Ok(data) = self.data_consumer.pop() {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
             self.recycling_producer.push(data).unwrap()
         }
     }
 
+    /// Writes `frames` frames, taking as many blocks (or parts of blocks) as necessary.
+    ///
+    /// `position` is updated to the playlist frame following the consumed data.
+    ///
     /// Return value of `false` means un-recoverable error (but output buffer is still filled)
     #[must_use]
-    unsafe fn write_channel_ptrs(&mut self, target: &[*mut f32], fade: Fade) -> bool {
-        if let Ok(block) = self.data_consumer.pop() {
-            for (source, &target) in block.channels.iter().zip(target) {
+    unsafe fn write_output<O: Output>(
+        &mut self,
+        target: &O,
+        frames: usize,
+        fade: Fade,
+        position: &mut usize,
+    ) -> bool {
+        let mut written = 0;
+        while written < frames {
+            if self.current_block.is_none() {
+                if let Ok(block) = self.data_consumer.pop() {
+                    self.queued.fetch_sub(1, Ordering::AcqRel);
+                    self.current_block = Some(block);
+                    self.current_offset = 0;
+                } else {
+                    fill_with_zeros(target, written, frames - written);
+                    return false;
+                }
+            }
+            let block = self.current_block.as_ref().unwrap();
+            let offset = self.current_offset;
+            let chunk = std::cmp::min(block.frames - offset, frames - written);
+            for (channel, source) in block.channels.iter().enumerate() {
+                if channel >= target.channels() {
+                    break;
+                }
+                let source = &source[offset..offset + chunk];
                 match fade {
                     Fade::In => {
-                        for i in 0..self.blocksize {
-                            *target.add(i) = source[i] * (i + 1) as f32 / self.blocksize as f32;
+                        for (i, a) in source.iter().enumerate() {
+                            let value = a * (written + i + 1) as f32 / frames as f32;
+                            target.write(channel, written + i, value);
                         }
                     }
                     Fade::Out => {
-                        for i in 0..self.blocksize {
-                            *target.add(i) =
-                                source[i] * (self.blocksize - i) as f32 / self.blocksize as f32;
+                        for (i, a) in source.iter().enumerate() {
+                            let value = a * (frames - written - i) as f32 / frames as f32;
+                            target.write(channel, written + i, value);
                         }
                     }
                     Fade::None => {
-                        let target = std::slice::from_raw_parts_mut(target, self.blocksize);
-                        target.copy_from_slice(source);
+                        for (i, a) in source.iter().enumerate() {
+                            target.write(channel, written + i, *a);
+                        }
                     }
                 }
             }
-            self.recycling_producer.push(block).unwrap();
-            true
+            // Additional channels in the target are silent
+            for channel in block.channels.len()..target.channels() {
+                for frame in written..written + chunk {
+                    target.write(channel, frame, 0.0);
+                }
+            }
+            written += chunk;
+            self.current_offset += chunk;
+            *position = block.frame_at(self.current_offset, self.duration);
+            if self.current_offset == block.frames {
+                let block = self.current_block.take().unwrap();
+                self.recycling_producer.push(block).unwrap();
+            }
+        }
+        true
+    }
+}
+
+/// Transport state as seen from the audio thread
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Not rolling, no data available yet and no seek requested
+    Stopped,
+    /// A seek was requested but the data is not yet available
+    Seeking,
+    /// Not rolling, data for the current position is available
+    Ready,
+    Rolling,
+    /// The current position is at (or after) the end of the playlist
+    Ended,
+    /// The reader has stopped because of an error (or a panic), see `reader_error()`
+    Failed,
+}
+
+impl State {
+    fn from_usize(value: usize) -> State {
+        match value {
+            0 => State::Stopped,
+            1 => State::Seeking,
+            2 => State::Ready,
+            3 => State::Rolling,
+            4 => State::Ended,
+            _ => State::Failed,
+        }
+    }
+}
+
+/// What happens when the end of the playlist is reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndAction {
+    /// Keep rolling (producing silence)
+    Continue,
+    /// Fade out and stop consuming data until the next seek
+    Stop,
+    /// Continue seamlessly at the beginning of the playlist
+    Loop,
+}
+
+impl EndAction {
+    fn from_usize(value: usize) -> EndAction {
+        match value {
+            0 => EndAction::Continue,
+            1 => EndAction::Stop,
+            _ => EndAction::Loop,
+        }
+    }
+}
+
+/// Transport information shared between the audio thread, the reader thread
+/// and any number of `TransportHandle`s
+struct SharedTransport {
+    position: AtomicUsize,
+    state: AtomicUsize,
+    end_action: AtomicUsize,
+    duration: usize,
+    /// Set before a message is sent to the reader, cleared when it is received
+    seek_requested: AtomicBool,
+    warnings: Mutex<Vec<StreamWarning>>,
+    /// Set by the reader when it stops because of an error
+    failed: AtomicBool,
+    reader_error: Mutex<Option<String>>,
+}
+
+impl SharedTransport {
+    fn end_action(&self) -> EndAction {
+        EndAction::from_usize(self.end_action.load(Ordering::Acquire))
+    }
+
+    fn state(&self) -> State {
+        if self.failed.load(Ordering::Acquire) {
+            State::Failed
         } else {
-            fill_with_zeros(target, self.blocksize);
-            false
+            State::from_usize(self.state.load(Ordering::Acquire))
+        }
+    }
+
+    fn fail(&self, error: &Error) {
+        let mut reader_error = self.reader_error.lock().unwrap_or_else(|e| e.into_inner());
+        *reader_error = Some(error.to_string());
+        self.failed.store(true, Ordering::Release);
+    }
+
+    fn warn(&self, warning: StreamWarning) {
+        let mut warnings = self.warnings.lock().unwrap();
+        // NB: Further warnings are dropped until they are taken
+        if warnings.len() < MAX_WARNINGS {
+            warnings.push(warning);
         }
     }
 }
 
+/// Can be used to query the transport state from other threads (e.g. a UI thread)
+#[derive(Clone)]
+pub struct TransportHandle {
+    shared: Arc<SharedTransport>,
+}
+
+impl TransportHandle {
+    /// Playlist frame of the next frame to be played
+    ///
+    /// While seeking, this is the target frame.
+    pub fn position(&self) -> usize {
+        self.shared.position.load(Ordering::Acquire)
+    }
+
+    pub fn state(&self) -> State {
+        self.shared.state()
+    }
+
+    /// The error that has stopped the reader (if `state()` is `Failed`)
+    pub fn reader_error(&self) -> Option<String> {
+        let reader_error = self.shared.reader_error.lock();
+        reader_error.unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Duration of the playlist in frames, i.e. the end of the last entry
+    pub fn duration(&self) -> usize {
+        self.shared.duration
+    }
+
+    pub fn end_action(&self) -> EndAction {
+        self.shared.end_action()
+    }
+
+    /// NB: The reader thread is typically ahead of playback, already buffered
+    /// data is not affected.
+    pub fn set_end_action(&self, action: EndAction) {
+        self.shared
+            .end_action
+            .store(action as usize, Ordering::Release);
+    }
+
+    /// Returns (and removes) the warnings reported by the reader thread
+    ///
+    /// This allocates memory, it should not be called from the audio thread.
+    pub fn take_warnings(&self) -> Vec<StreamWarning> {
+        std::mem::take(&mut *self.shared.warnings.lock().unwrap())
+    }
+}
+
 pub struct FileStreamer {
     ready_consumer: queue::spsc::Consumer<(usize, DataConsumer)>,
-    seek_producer: queue::spsc::Producer<(usize, DataConsumer)>,
+    seek_producer: queue::spsc::Producer<(usize, DataConsumer, Option<DataProducer>)>,
     data_consumer: Option<DataConsumer>,
-    reader_thread: Option<thread::JoinHandle<Result<(), Error>>>,
-    reader_thread_keep_reading: Arc<AtomicBool>,
+    reader: Option<ReaderHandle>,
     channels: usize,
     blocksize: usize,
+    capacity: usize,
     previously_rolling: bool,
     seek_frame: Option<usize>,
+    seek_pending: bool,
+    position: usize,
+    locate: Option<Locate>,
+    locate_timeout: Option<Duration>,
+    /// Used for locate timeouts, see `set_locate_clock()`
+    locate_clock: Box<dyn Fn() -> Instant + Send>,
+    transport: TransportHandle,
+    gain_automation: Option<GainAutomation>,
+    thread_warnings: Vec<ThreadWarning>,
+    data_error: Option<DataError>,
+}
+
+/// Progress of the most recent `FileStreamer::request_locate()`
+///
+/// Transitions:
+///
+/// * `Idle` -> `Seeking`: `request_locate()` while not rolling
+/// * `Idle` -> `Ready`: `request_locate()` of the current (available) position
+/// * `Idle` -> `Deferred`: `request_locate()` while rolling
+/// * `Deferred` -> `Seeking`: `get_data()` with `rolling == false`
+/// * `Seeking` -> `Ready`: the reader has finished seeking
+/// * `Deferred`/`Seeking` -> `TimedOut`: see `FileStreamer::set_locate_timeout()`
+/// * `TimedOut` -> `Seeking`/`Ready`: like `Deferred`/`Seeking`
+/// * `Ready` -> `Idle`: `get_data()` with `rolling == true`
+/// * any -> another frame: `request_locate()` (or `is_ready()`) with a different frame
+/// * any -> `Idle`: `seek()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocateState {
+    /// No locate has been requested (or the transport has started rolling afterwards)
+    Idle,
+    /// Requested while rolling, seeking starts when the transport is stopped
+    Deferred(usize),
+    Seeking(usize),
+    /// Still deferred or seeking after the timeout
+    TimedOut(usize),
+    /// The data is available, the transport can start rolling
+    Ready(usize),
+}
+
+struct Locate {
+    frame: usize,
+    requested: Instant,
 }
 
-// TODO: make less public
 pub struct PlaylistEntry {
     pub start: usize,
     pub end: Option<usize>,
-    pub file: Box<AudioFile + Send>,
-    pub channels: Box<[Option<usize>]>,
+    /// File frame that is played at `start`
+    pub file_offset: usize,
+    /// Length of the linear fade-in after `start`
+    pub fade_in: usize,
+    /// Length of the linear fade-out before `end_frame()`
+    pub fade_out: usize,
+    file: Box<dyn AudioFile + Send>,
+    routing: Box<[Route]>,
+    /// Since the last seek, the entry has been moved to `file_offset`
+    /// and `AudioFileBasics::prefetch()` has been called (unless it's preloaded)
+    prefetched: bool,
+    preloads: Vec<Preload>,
+    /// Index into `preloads` and frame within the preloaded data
+    preload_position: Option<(usize, usize)>,
+    /// The file has ended early (and this has been reported) since the last seek
+    ended_early: bool,
+}
+
+/// Decoded data that is kept in memory to avoid decoding after seeking
+struct Preload {
+    /// File frame of the first preloaded frame
+    frame: usize,
+    data: memory::Data,
+}
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "Invalid file channel {} in routing (the file has {} channels)",
+    channel, channels
+)]
+pub struct RoutingError {
+    pub channel: usize,
+    pub channels: usize,
+}
+
+/// A playlist entry refers to an output channel that doesn't exist
+#[derive(Debug, Fail)]
+#[fail(
+    display = "Playlist entry {}: invalid output channel {} in routing (there are {} channels)",
+    entry, channel, channels
+)]
+pub struct OutputChannelError {
+    /// Index of the playlist entry
+    pub entry: usize,
+    pub channel: usize,
+    pub channels: usize,
+}
+
+/// Reason why `FileStreamer::get_data()` has returned `false`
+#[derive(Clone, Copy, Debug, Fail, PartialEq, Eq)]
+pub enum DataError {
+    #[fail(display = "More frames requested than allowed by the blocksize")]
+    TooManyFrames,
+    #[fail(display = "Still seeking, no data available yet")]
+    NotReady,
+    #[fail(display = "Buffer underrun, the reader thread is too slow")]
+    Underrun,
+    #[fail(display = "Seeking while rolling is not supported")]
+    SeekWhileRolling,
+    #[fail(display = "The reader has stopped because of an error")]
+    ReaderFailed,
+}
+
+/// The reader has panicked, see `State::Failed`
+#[derive(Debug, Fail)]
+#[fail(display = "Panic in reader: {}", _0)]
+pub struct ReaderPanic(pub String);
+
+/// Problems that don't stop the streaming, see `TransportHandle::take_warnings()`
+#[derive(Clone, Debug, Fail, PartialEq, Eq)]
+pub enum StreamWarning {
+    #[fail(
+        display = "Playlist entry {}: file shorter than expected (ended at frame {} of {})",
+        entry, frame, frames
+    )]
+    FileTooShort {
+        /// Index of the playlist entry
+        entry: usize,
+        /// File frame where the file has ended
+        frame: usize,
+        /// Number of frames announced by the file
+        frames: usize,
+    },
+}
+
+impl PlaylistEntry {
+    /// Returns a `RoutingError` if `routing` refers to channels that don't exist in `file`
+    ///
+    /// Only the file channels that are used in `routing` are decoded.
+    /// Failing to select them (see `AudioFile::select_channels()`) is also an error.
+    pub fn new(
+        start: usize,
+        end: Option<usize>,
+        mut file: Box<dyn AudioFile + Send>,
+        mut routing: Vec<Route>,
+    ) -> Result<PlaylistEntry, Error> {
+        let channels = file.channels();
+        if let Some(route) = routing.iter().find(|route| route.file_channel >= channels) {
+            Err(RoutingError {
+                channel: route.file_channel,
+                channels,
+            })?;
+        }
+        // NB: The routes are grouped once, not for every block
+        Route::sort(&mut routing);
+        let used_channels: Vec<_> = routing.iter().map(|route| route.file_channel).collect();
+        file.select_channels(&used_channels)?;
+        Ok(PlaylistEntry {
+            start,
+            end,
+            file_offset: 0,
+            fade_in: 0,
+            fade_out: 0,
+            file,
+            routing: routing.into_boxed_slice(),
+            prefetched: false,
+            preloads: Vec::new(),
+            preload_position: None,
+            ended_early: false,
+        })
+    }
+
+    pub fn file(&self) -> &(dyn AudioFile + Send) {
+        &*self.file
+    }
+
+    /// Sorted by file channel
+    pub fn routing(&self) -> &[Route] {
+        &self.routing
+    }
+
+    /// Playlist frame after the end of the entry (`end` or the end of the file)
+    pub fn end_frame(&self) -> usize {
+        self.end
+            .unwrap_or_else(|| self.start + self.file.frames().saturating_sub(self.file_offset))
+    }
+
+    /// Decodes `frames` frames starting at the file frame `frame` and keeps them in memory
+    ///
+    /// Seeking into this range doesn't have to wait for the file to be decoded.
+    /// Returns the number of bytes that are used.
+    /// See also `preload_playlist()`.
+    pub fn preload(&mut self, frame: usize, frames: usize) -> Result<usize, Error> {
+        let data = memory::Data::decode_part(&mut *self.file, frame, frames)?;
+        self.preload_position = None;
+        if data.frames() == 0 {
+            return Ok(0);
+        }
+        let size = data.size();
+        self.preloads.push(Preload { frame, data });
+        Ok(size)
+    }
+
+    /// Seeks to the file frame `frame`, the file itself is not touched if the frame is preloaded
+    fn seek(&mut self, frame: usize) -> Result<(), Error> {
+        // NB: Preloads can overlap, the one that lasts longest is used
+        self.preload_position = self
+            .preloads
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.frame <= frame && frame < p.frame + p.data.frames())
+            .max_by_key(|(_, p)| p.frame + p.data.frames())
+            .map(|(index, p)| (index, frame - p.frame));
+        self.ended_early = false;
+        if self.preload_position.is_none() {
+            self.file.seek(frame)?;
+        }
+        Ok(())
+    }
+
+    /// Applies `fade_in` and `fade_out` to `gains`, which start at the playlist frame `frame`
+    fn apply_fades(&self, frame: usize, gains: &mut [f32]) {
+        if self.fade_in == 0 && self.fade_out == 0 {
+            return;
+        }
+        let end = self.end_frame();
+        for (i, gain) in gains.iter_mut().enumerate() {
+            let elapsed = (frame + i).saturating_sub(self.start);
+            if elapsed < self.fade_in {
+                *gain *= (elapsed + 1) as f32 / self.fade_in as f32;
+            }
+            let remaining = end.saturating_sub(frame + i);
+            if remaining <= self.fade_out {
+                *gain *= remaining as f32 / self.fade_out as f32;
+            }
+        }
+    }
+
+    /// Preloaded data is never pending, see `AudioFile::reads_pending()`
+    fn reads_pending(&mut self) -> bool {
+        self.preload_position.is_none() && self.file.reads_pending()
+    }
+
+    /// Like `AudioFile::fill_channels()`, but preloaded data is used first
+    fn fill_channels(
+        &mut self,
+        gains: &[f32],
+        blocksize: usize,
+        offset: usize,
+        channels: &mut [Box<[f32]>],
+    ) -> Result<Filled, Error> {
+        let start = offset;
+        let mut offset = offset;
+        if let Some((index, position)) = self.preload_position {
+            let preload = &self.preloads[index];
+            let frames = std::cmp::min(blocksize - offset, preload.data.frames() - position);
+            for route in self.routing.iter() {
+                let source = &preload.data.channel(route.file_channel)[position..position + frames];
+                let target = &mut channels[route.output_channel][offset..offset + frames];
+                for ((a, b), gain) in source.iter().zip(target).zip(&gains[offset..]) {
+                    *b += a * route.gain * gain;
+                }
+            }
+            offset += frames;
+            if position + frames < preload.data.frames() {
+                self.preload_position = Some((index, position + frames));
+            } else {
+                // The preloaded data is used up, the file has to catch up
+                self.preload_position = None;
+                self.file.seek(preload.frame + preload.data.frames())?;
+            }
+        }
+        let mut end_of_file = false;
+        if offset < blocksize {
+            let filled =
+                self.file
+                    .fill_channels(&self.routing, gains, blocksize, offset, channels)?;
+            offset += filled.frames;
+            end_of_file = filled.end_of_file;
+        }
+        Ok(Filled {
+            frames: offset - start,
+            end_of_file,
+        })
+    }
+}
+
+/// Preloads the beginning of each entry and the data at each cue point
+///
+/// For each entry start and each (playlist) frame in `cue_points`,
+/// `frames` frames of all entries that are active at that frame are preloaded,
+/// which means that `FileStreamer::seek()` to these frames is fast.
+/// To hand over the data queue before any file has to be decoded,
+/// `frames` should not be less than `FileStreamer::max_frames()`.
+/// Returns the total number of bytes that are used.
+pub fn preload_playlist(
+    playlist: &mut [PlaylistEntry],
+    frames: usize,
+    cue_points: &[usize],
+) -> Result<usize, Error> {
+    let mut seek_points: Vec<_> = playlist.iter().map(|entry| entry.start).collect();
+    seek_points.extend_from_slice(cue_points);
+    seek_points.sort();
+    seek_points.dedup();
+    let mut size = 0;
+    for entry in playlist.iter_mut() {
+        for &point in &seek_points {
+            if entry.start <= point && !matches!(entry.end, Some(end) if end <= point) {
+                size += entry.preload(entry.file_offset + point - entry.start, frames)?;
+            }
+        }
+    }
+    Ok(size)
 }
 
 struct ActiveIter<'a> {
     block_start: usize,
     block_end: usize,
-    inner: std::slice::IterMut<'a, PlaylistEntry>,
+    inner: std::iter::Enumerate<std::slice::IterMut<'a, PlaylistEntry>>,
 }
 
 impl<'a> Iterator for ActiveIter<'a> {
-    type Item = &'a mut PlaylistEntry;
+    /// Index and entry
+    type Item = (usize, &'a mut PlaylistEntry);
 
-    fn next(&mut self) -> Option<&'a mut PlaylistEntry> {
-        while let Some(entry) = self.inner.next() {
+    fn next(&mut self) -> Option<(usize, &'a mut PlaylistEntry)> {
+        for (index, entry) in self.inner.by_ref() {
             if entry.start < self.block_end
                 && (entry.end.is_none() || self.block_start < entry.end.unwrap())
             {
-                return Some(entry);
+                return Some((index, entry));
             }
         }
         None
@@ -268,102 +1032,536 @@ impl<'a> Iterator for ActiveIter<'a> {
 // TODO: different API?
 // new(), add_file(), add_file, ..., start_streaming()?
 
-impl FileStreamer {
-    pub fn new(playlist: Vec<PlaylistEntry>, blocksize: usize, channels: usize) -> FileStreamer {
-        let mut playlist = playlist.into_boxed_slice();
+/// Reads data from the playlist and writes it into the data queue of a `FileStreamer`.
+///
+/// A `Reader` is driven either by a dedicated thread (see `FileStreamer::new()`)
+/// or by a `ReaderPool`.
+pub(crate) struct Reader {
+    playlist: Box<[PlaylistEntry]>,
+    gain_envelopes: GainEnvelopes,
+    gains: Vec<f32>,
+    transport: Arc<SharedTransport>,
+    seek_consumer: queue::spsc::Consumer<(usize, DataConsumer, Option<DataProducer>)>,
+    ready_producer: queue::spsc::Producer<(usize, DataConsumer)>,
+    data_producer: DataProducer,
+    data_consumer: Option<DataConsumer>,
+    current_frame: usize,
+    seek_frame: usize,
+    buffered_frames: usize,
+    /// Files have to be seeked after seeking and when looping
+    discontinuity: bool,
+    /// Playlist entries that are waiting for data, see `fill_segment()`
+    pending: Vec<usize>,
+}
 
-        // TODO: provide min_buffer_duration in seconds?
-        let min_frames = 4096;
-        // TODO: convert max_buffer_duration into queue capacity
-        let capacity = 100;
+impl Reader {
+    /// Like `step()`, but errors (and panics) are reported as `State::Failed`.
+    ///
+    /// After an error, the reader must not be used anymore.
+    pub(crate) fn guarded_step(&mut self) -> Result<bool, Error> {
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| self.step())) {
+            Ok(result) => result,
+            Err(payload) => Err(ReaderPanic(panic_message(&*payload)).into()),
+        };
+        if let Err(ref e) = result {
+            self.transport.fail(e);
+        }
+        result
+    }
 
-        let (ready_producer, ready_consumer) = queue::spsc::new(1);
-        let (seek_producer, seek_consumer) = queue::spsc::new::<(usize, DataConsumer)>(1);
-        let (mut data_producer, data_consumer) = make_data_queue(capacity, blocksize, channels);
-
-        let reader_thread_keep_reading = Arc::new(AtomicBool::new(true));
-        let keep_reading = Arc::clone(&reader_thread_keep_reading);
-
-        let reader_thread = thread::spawn(move || -> Result<(), Error> {
-            let mut data_consumer = Some(data_consumer);
-            let mut current_frame = 0;
-            let mut seek_frame = 0;
-
-            while keep_reading.load(Ordering::Acquire) {
-                if let Ok((frame, mut queue)) = seek_consumer.pop() {
-                    queue.clear();
-                    data_consumer = Some(queue);
-                    current_frame = frame;
-                    seek_frame = frame;
+    /// Writes one block (if there is space in the queue).
+    ///
+    /// Return value of `false` means that there was nothing to do.
+    pub(crate) fn step(&mut self) -> Result<bool, Error> {
+        if let Ok((frame, mut queue, producer)) = self.seek_consumer.pop() {
+            self.transport
+                .seek_requested
+                .store(false, Ordering::Release);
+            if let Some(producer) = producer {
+                // The blocksize has changed
+                self.data_producer = producer;
+            }
+            queue.clear();
+            self.data_consumer = Some(queue);
+            self.current_frame = frame;
+            self.seek_frame = frame;
+            self.buffered_frames = 0;
+            self.discontinuity = true;
+            self.gain_envelopes.reset();
+            for entry in self.playlist.iter_mut() {
+                entry.prefetched = false;
+            }
+        }
+        let blocksize = self.data_producer.blocksize;
+        self.gains.resize(blocksize, 1.0);
+        self.gain_envelopes.update();
+        let mut block = match self.data_producer.write_block() {
+            Some(block) => block,
+            None => return Ok(false),
+        };
+        let duration = self.transport.duration;
+        let looping = duration > 0 && self.transport.end_action() == EndAction::Loop;
+        let mut block_frame = self.current_frame;
+        let mut loop_offset = None;
+        let mut offset = 0;
+        while offset < blocksize {
+            if looping && self.current_frame >= duration {
+                self.current_frame = 0;
+                self.discontinuity = true;
+                self.gain_envelopes.reset();
+                for entry in self.playlist.iter_mut() {
+                    entry.prefetched = false;
                 }
-                let mut block = match data_producer.write_block() {
-                    Some(block) => block,
-                    None => {
+                if offset == 0 {
+                    block_frame = 0;
+                } else if loop_offset.is_none() {
+                    loop_offset = Some(offset);
+                }
+            }
+            let mut frames = blocksize - offset;
+            if looping {
+                frames = std::cmp::min(frames, duration - self.current_frame);
+            }
+            fill_segment(
+                &mut self.playlist,
+                &mut self.gain_envelopes,
+                &mut self.gains,
+                block.channels(),
+                offset,
+                frames,
+                self.current_frame,
+                self.discontinuity,
+                &self.transport,
+                &mut self.pending,
+            )?;
+            self.discontinuity = false;
+            offset += frames;
+            self.current_frame += frames;
+        }
+        block.set_position(block_frame, loop_offset);
+        self.buffered_frames += blocksize;
+
+        let horizon = self.current_frame + prefetch_frames(blocksize);
+        // TODO: When looping, prefetch entries at the beginning of the playlist
+        for entry in self.playlist.iter_mut() {
+            if !entry.prefetched && entry.start >= self.current_frame && entry.start < horizon {
+                // NB: The position is kept until the entry starts
+                entry.seek(entry.file_offset)?;
+                if entry.preload_position.is_none() {
+                    entry.file.prefetch();
+                }
+                entry.prefetched = true;
+            }
+        }
+
+        // Make sure the block is queued before data_consumer is sent
+        drop(block);
+
+        // TODO: get this information from the queue itself?
+        if self.buffered_frames >= min_buffer_frames(blocksize) {
+            if let Some(data_consumer) = self.data_consumer.take() {
+                // There is only one data queue, push() will always succeed
+                self.ready_producer
+                    .push((self.seek_frame, data_consumer))
+                    .unwrap();
+            }
+        }
+        Ok(true)
+    }
+
+    /// Number of frames available in the data queue (lower is more urgent).
+    ///
+    /// Return value of `None` means that the queue is full.
+    pub(crate) fn urgency(&self) -> Option<usize> {
+        if self.transport.seek_requested.load(Ordering::Acquire) {
+            return Some(0);
+        }
+        let producer = &self.data_producer;
+        let queued = producer.queued.load(Ordering::Acquire);
+        if queued < producer.capacity {
+            Some(queued * producer.blocksize)
+        } else {
+            None
+        }
+    }
+}
+
+enum ReaderHandle {
+    Thread {
+        thread: thread::JoinHandle<Result<(), Error>>,
+        keep_reading: Arc<AtomicBool>,
+    },
+    Pool {
+        pool: Arc<PoolShared>,
+        reader: Arc<Mutex<PooledReader>>,
+    },
+    /// See `ManualReader`
+    Manual,
+}
+
+/// A reader without a thread, see `FileStreamer::with_manual_reader()`
+pub struct ManualReader {
+    reader: Reader,
+}
+
+impl ManualReader {
+    /// Handles a pending seek (if any) and writes one block (if there is space in the queue).
+    ///
+    /// Return value of `false` means that there was nothing to do.
+    /// Errors are also reported as `State::Failed`, like with a reader thread.
+    pub fn step(&mut self) -> Result<bool, Error> {
+        self.reader.guarded_step()
+    }
+
+    /// Calls `step()` until the queue is full, returns the number of blocks that were written
+    pub fn fill(&mut self) -> Result<usize, Error> {
+        let mut blocks = 0;
+        while self.step()? {
+            blocks += 1;
+        }
+        Ok(blocks)
+    }
+}
+
+impl FileStreamer {
+    /// Starts a dedicated reader thread.
+    ///
+    /// Returns an error if the routing of any playlist entry refers to an output channel
+    /// that is not less than `channels`.
+    pub fn new(
+        playlist: Vec<PlaylistEntry>,
+        blocksize: usize,
+        channels: usize,
+    ) -> Result<FileStreamer, OutputChannelError> {
+        FileStreamer::with_thread_options(playlist, blocksize, channels, &Default::default())
+    }
+
+    /// Like `new()`, but with scheduling options for the reader thread.
+    ///
+    /// Options that cannot be applied are reported by `thread_warnings()`.
+    pub fn with_thread_options(
+        playlist: Vec<PlaylistEntry>,
+        blocksize: usize,
+        channels: usize,
+        options: &ThreadOptions,
+    ) -> Result<FileStreamer, OutputChannelError> {
+        FileStreamer::with_reader(playlist, blocksize, channels, |mut reader| {
+            let keep_reading = Arc::new(AtomicBool::new(true));
+            let keep = Arc::clone(&keep_reading);
+            let read = move || -> Result<(), Error> {
+                while keep.load(Ordering::Acquire) {
+                    if !reader.guarded_step()? {
                         // TODO: configurable sleep time?
                         thread::sleep(Duration::from_micros(1_000));
-                        continue;
                     }
-                };
-                let mut active_files = ActiveIter {
-                    block_start: current_frame,
-                    block_end: current_frame + blocksize,
-                    inner: playlist.iter_mut(),
-                };
-                // TODO: Is linear search too slow? How long can playlists be?
-                for file in &mut active_files {
-                    let offset = if file.start < current_frame {
-                        if current_frame == seek_frame {
-                            file.file.seek(current_frame - file.start)?;
-                        }
-                        0
-                    } else {
-                        file.file.seek(0)?;
-                        file.start - current_frame
-                    };
-                    file.file
-                        .fill_channels(&file.channels, blocksize, offset, block.channels())?;
                 }
-                current_frame += blocksize;
+                Ok(())
+            };
+            let (thread, warnings) = scheduling::spawn(options, "", read);
+            let handle = ReaderHandle::Thread {
+                thread,
+                keep_reading,
+            };
+            (handle, warnings)
+        })
+    }
+
+    /// Uses the worker threads of `pool` instead of starting a dedicated reader thread.
+    ///
+    /// See `new()` for possible errors.
+    pub fn with_pool(
+        playlist: Vec<PlaylistEntry>,
+        blocksize: usize,
+        channels: usize,
+        pool: &ReaderPool,
+    ) -> Result<FileStreamer, OutputChannelError> {
+        FileStreamer::with_reader(playlist, blocksize, channels, |reader| {
+            let (pool, reader) = pool.add(reader);
+            (ReaderHandle::Pool { pool, reader }, Vec::new())
+        })
+    }
 
-                // Make sure the block is queued before data_consumer is sent
-                drop(block);
+    /// Like `new()`, but the reader has to be driven explicitly (from any thread).
+    ///
+    /// This is meant for deterministic tests and for offline processing.
+    /// See `new()` for possible errors.
+    pub fn with_manual_reader(
+        playlist: Vec<PlaylistEntry>,
+        blocksize: usize,
+        channels: usize,
+    ) -> Result<(FileStreamer, ManualReader), OutputChannelError> {
+        let mut manual = None;
+        let streamer = FileStreamer::with_reader(playlist, blocksize, channels, |reader| {
+            manual = Some(ManualReader { reader });
+            (ReaderHandle::Manual, Vec::new())
+        })?;
+        Ok((streamer, manual.unwrap()))
+    }
 
-                // TODO: get this information from the queue itself?
-                if current_frame - seek_frame >= min_frames {
-                    if let Some(data_consumer) = data_consumer.take() {
-                        // There is only one data queue, push() will always succeed
-                        ready_producer.push((seek_frame, data_consumer)).unwrap();
-                    }
-                }
+    fn with_reader<F>(
+        playlist: Vec<PlaylistEntry>,
+        blocksize: usize,
+        channels: usize,
+        start_reader: F,
+    ) -> Result<FileStreamer, OutputChannelError>
+    where
+        F: FnOnce(Reader) -> (ReaderHandle, Vec<ThreadWarning>),
+    {
+        for (index, entry) in playlist.iter().enumerate() {
+            if let Some(route) = entry
+                .routing
+                .iter()
+                .find(|route| route.output_channel >= channels)
+            {
+                return Err(OutputChannelError {
+                    entry: index,
+                    channel: route.output_channel,
+                    channels,
+                });
             }
-            Ok(())
+        }
+
+        let playlist = playlist.into_boxed_slice();
+
+        // TODO: convert max_buffer_duration into queue capacity
+        let capacity = 100;
+
+        let duration = playlist
+            .iter()
+            .map(|entry| entry.end_frame())
+            .max()
+            .unwrap_or(0);
+        let transport = TransportHandle {
+            shared: Arc::new(SharedTransport {
+                position: AtomicUsize::new(0),
+                state: AtomicUsize::new(State::Stopped as usize),
+                end_action: AtomicUsize::new(EndAction::Continue as usize),
+                duration,
+                seek_requested: AtomicBool::new(false),
+                warnings: Mutex::new(Vec::new()),
+                failed: AtomicBool::new(false),
+                reader_error: Mutex::new(None),
+            }),
+        };
+
+        let (ready_producer, ready_consumer) = queue::spsc::new(1);
+        let (seek_producer, seek_consumer) = queue::spsc::new(1);
+        // TODO: configurable number of pending events?
+        let entries = playlist.len();
+        let (gain_automation, gain_envelopes) = make_gain_automation(entries, 1024);
+        let (data_producer, data_consumer) =
+            make_data_queue(capacity, blocksize, channels, duration);
+
+        let (reader, thread_warnings) = start_reader(Reader {
+            playlist,
+            gain_envelopes,
+            gains: Vec::new(),
+            transport: Arc::clone(&transport.shared),
+            seek_consumer,
+            ready_producer,
+            data_producer,
+            data_consumer: Some(data_consumer),
+            current_frame: 0,
+            seek_frame: 0,
+            buffered_frames: 0,
+            discontinuity: true,
+            pending: Vec::with_capacity(entries),
         });
-        FileStreamer {
+        Ok(FileStreamer {
             ready_consumer,
             seek_producer,
             data_consumer: None,
-            reader_thread: Some(reader_thread),
-            reader_thread_keep_reading,
+            reader: Some(reader),
             channels,
             blocksize,
+            capacity,
             previously_rolling: false,
             seek_frame: None,
-        }
+            seek_pending: false,
+            position: 0,
+            locate: None,
+            locate_timeout: None,
+            locate_clock: Box::new(Instant::now),
+            transport,
+            gain_automation: Some(gain_automation),
+            thread_warnings,
+            data_error: None,
+        })
     }
 
     pub fn channels(&self) -> usize {
         self.channels
     }
 
-    /// Return value of `false` means un-recoverable error
+    /// Scheduling options that couldn't be applied to the reader thread
+    ///
+    /// See also `ReaderPool::thread_warnings()`.
+    pub fn thread_warnings(&self) -> &[ThreadWarning] {
+        &self.thread_warnings
+    }
+
+    /// Nominal blocksize, i.e. the size of the blocks produced by the reader thread
+    pub fn blocksize(&self) -> usize {
+        self.blocksize
+    }
+
+    /// Maximum number of frames that can be requested with a single call to `get_data()`
+    pub fn max_frames(&self) -> usize {
+        min_buffer_frames(self.blocksize)
+    }
+
+    /// Changes the nominal blocksize.
+    ///
+    /// This allocates a new data queue, it should not be called from the audio thread.
+    /// Afterwards, the streamer is seeking (to the current position).
+    ///
+    /// Return value of `false` means that the blocksize cannot be changed right now
+    /// (because the transport is rolling or a seek is in progress), try again later.
     #[must_use]
-    pub unsafe fn get_data(&mut self, target: &[*mut f32], rolling: bool) -> bool {
+    pub fn set_blocksize(&mut self, blocksize: usize) -> bool {
+        if self.previously_rolling {
+            return false;
+        }
+        self.poll_ready_queue();
+        if self.data_consumer.take().is_none() {
+            return false;
+        }
+        let (producer, consumer) =
+            make_data_queue(self.capacity, blocksize, self.channels, self.duration());
+        self.request_seek(self.position, consumer, Some(producer));
+        self.seek_pending = true;
+        self.blocksize = blocksize;
+        self.publish_transport();
+        true
+    }
+
+    /// See `TransportHandle::position()`
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn state(&self) -> State {
+        self.transport.state()
+    }
+
+    /// See `TransportHandle::reader_error()`
+    pub fn reader_error(&self) -> Option<String> {
+        self.transport.reader_error()
+    }
+
+    /// See `TransportHandle::duration()`
+    pub fn duration(&self) -> usize {
+        self.transport.duration()
+    }
+
+    pub fn end_action(&self) -> EndAction {
+        self.transport.end_action()
+    }
+
+    /// See `TransportHandle::set_end_action()`
+    pub fn set_end_action(&self, action: EndAction) {
+        self.transport.set_end_action(action);
+    }
+
+    /// Returns a handle for querying the transport from other threads
+    pub fn transport(&self) -> TransportHandle {
+        self.transport.clone()
+    }
+
+    /// See `TransportHandle::take_warnings()`
+    pub fn take_warnings(&self) -> Vec<StreamWarning> {
+        self.transport.take_warnings()
+    }
+
+    /// Returns the sending side of the gain automation (only on the first call)
+    pub fn take_gain_automation(&mut self) -> Option<GainAutomation> {
+        self.gain_automation.take()
+    }
+
+    /// Maximum number of frames the reader thread can be ahead of the playback position
+    ///
+    /// Gain events have to be sent at least this long before their time stamp
+    /// in order to be applied sample-accurately.
+    pub fn automation_latency(&self) -> usize {
+        self.capacity * self.blocksize
+    }
+
+    /// Writes `frames` frames to each channel in `target`.
+    ///
+    /// `frames` can be different in each call, but it must not exceed `max_frames()`.
+    /// Samples are converted if `S` is not `f32`, see the `sample` module.
+    /// If `target` has more channels than `channels()`, the additional ones are filled with zeros.
+    ///
+    /// Return value of `false` means un-recoverable error, see `data_error()`
+    ///
+    /// # Safety
+    ///
+    /// Each pointer in `target` must be valid for writing `frames` samples.
+    #[must_use]
+    pub unsafe fn get_data<S: Sample>(
+        &mut self,
+        target: &[*mut S],
+        frames: usize,
+        rolling: bool,
+    ) -> bool {
+        let result = self.get_output(&Planar(target), frames, rolling);
+        self.data_error = result.err();
+        self.data_error.is_none()
+    }
+
+    /// Like `get_data()`, but with a single buffer of interleaved channels
+    ///
+    /// The number of frames is `target.len() / channels()`,
+    /// remaining samples (if any) are set to zero.
+    #[must_use]
+    pub fn get_data_interleaved<S: Sample>(&mut self, target: &mut [S], rolling: bool) -> bool {
+        let channels = self.channels;
+        let frames = target.len().checked_div(channels).unwrap_or(0);
+        for sample in &mut target[frames * channels..] {
+            *sample = S::from_f32(0.0);
+        }
+        let target = Interleaved {
+            ptr: target.as_mut_ptr(),
+            channels,
+        };
+        let result = unsafe { self.get_output(&target, frames, rolling) };
+        self.data_error = result.err();
+        self.data_error.is_none()
+    }
+
+    /// The reason for the last failed call to `get_data()` (or `get_data_interleaved()`)
+    ///
+    /// This is reset by each successful call.
+    pub fn data_error(&self) -> Option<DataError> {
+        self.data_error
+    }
+
+    unsafe fn get_output<O: Output>(
+        &mut self,
+        target: &O,
+        frames: usize,
+        rolling: bool,
+    ) -> Result<(), DataError> {
         // TODO: Check if disk thread is still running? return false if not?
 
+        if frames > self.max_frames() {
+            fill_with_zeros(target, 0, frames);
+            return Err(DataError::TooManyFrames);
+        }
+        if self.transport.shared.failed.load(Ordering::Acquire) {
+            fill_with_zeros(target, 0, frames);
+            return Err(DataError::ReaderFailed);
+        }
+        self.poll_ready_queue();
+        let rolling = rolling && !(self.is_at_end() && self.end_action() == EndAction::Stop);
+        if rolling && self.locate_is_ready() {
+            self.locate = None;
+        }
+
         let previously = self.previously_rolling;
         let result = if !rolling && !previously {
-            fill_with_zeros(target, self.blocksize);
-            true
+            fill_with_zeros(target, 0, frames);
+            Ok(())
         } else if let Some(ref mut queue) = self.data_consumer {
             let fade = if rolling && !previously {
                 Fade::In
@@ -372,61 +1570,390 @@ impl FileStreamer {
             } else {
                 Fade::None
             };
-            queue.write_channel_ptrs(target, fade)
+            if queue.write_output(target, frames, fade, &mut self.position) {
+                Ok(())
+            } else {
+                Err(DataError::Underrun)
+            }
         } else {
-            fill_with_zeros(target, self.blocksize);
-            false
+            fill_with_zeros(target, 0, frames);
+            Err(DataError::NotReady)
         };
         // NB: This has to be updated before seeking:
         self.previously_rolling = rolling;
         if let Some(frame) = self.seek_frame.take() {
             if rolling {
                 // NB: Seeking while rolling is not supported
-                return false;
+                self.publish_transport();
+                return Err(DataError::SeekWhileRolling);
             }
             let _ = self.seek(frame);
         }
+        if !rolling && self.locate.is_some() {
+            self.advance_locate();
+        }
+        self.publish_transport();
         result
     }
 
+    /// Return value of `true` means that the data for `frame` is available.
+    ///
+    /// While rolling, the seek is deferred until after the next call to `get_data()`.
+    /// Otherwise, a new seek is requested unless the data for `frame` is already available
+    /// (or the reader is still busy with an earlier seek).
+    /// This cancels any locate, see `request_locate()` for an alternative.
     #[must_use]
     pub fn seek(&mut self, frame: usize) -> bool {
         // TODO: Check if disk thread is still running? What if not?
 
+        self.locate = None;
         if self.previously_rolling {
             self.seek_frame = Some(frame);
+            self.position = frame;
+            self.publish_transport();
             // Don't seek yet; get_data() fades out and calls seek afterwards
             return false;
         }
+        self.poll_ready_queue();
+        let result = if self.data_consumer.is_some() && self.position == frame {
+            true
+        } else {
+            if let Some(queue) = self.data_consumer.take() {
+                self.request_seek(frame, queue, None);
+                self.seek_pending = true;
+            }
+            self.position = frame;
+            false
+        };
+        self.publish_transport();
+        result
+    }
+
+    /// Requests the data for `frame`, see `LocateState` for the possible transitions.
+    ///
+    /// This doesn't block and it can be called from the audio thread.
+    /// If the transport is rolling, seeking is deferred until `get_data()` has been
+    /// called with `rolling == false` (which fades out).
+    /// Several requests coalesce, only the most recent one is carried out.
+    pub fn request_locate(&mut self, frame: usize) {
+        // NB: Repeated requests for the same frame don't restart the timeout
+        if !matches!(self.locate, Some(ref locate) if locate.frame == frame) {
+            self.locate = Some(Locate {
+                frame,
+                requested: (self.locate_clock)(),
+            });
+        }
+        self.seek_frame = None;
+        self.advance_locate();
+    }
+
+    /// Returns `true` if the data for `frame` is available and the transport is not rolling.
+    ///
+    /// If `frame` is not the most recently requested frame, `request_locate()` is called.
+    /// This is meant to be called repeatedly, e.g. from a JACK sync callback.
+    pub fn is_ready(&mut self, frame: usize) -> bool {
+        match self.locate {
+            Some(ref locate) if locate.frame == frame => self.advance_locate(),
+            _ => self.request_locate(frame),
+        }
+        self.locate_state() == LocateState::Ready(frame)
+    }
+
+    /// See `LocateState`
+    ///
+    /// Unlike `is_ready()`, this doesn't check if the reader has finished seeking.
+    pub fn locate_state(&self) -> LocateState {
+        let locate = match self.locate {
+            Some(ref locate) => locate,
+            None => return LocateState::Idle,
+        };
+        if self.locate_is_ready() {
+            return LocateState::Ready(locate.frame);
+        }
+        if let Some(timeout) = self.locate_timeout {
+            if (self.locate_clock)().saturating_duration_since(locate.requested) >= timeout {
+                return LocateState::TimedOut(locate.frame);
+            }
+        }
+        if self.previously_rolling {
+            LocateState::Deferred(locate.frame)
+        } else {
+            LocateState::Seeking(locate.frame)
+        }
+    }
+
+    /// After `timeout`, a pending locate is reported as `LocateState::TimedOut`
+    ///
+    /// The locate is not cancelled, it is up to the host to decide what to do.
+    /// By default, there is no timeout.
+    pub fn set_locate_timeout(&mut self, timeout: Option<Duration>) {
+        self.locate_timeout = timeout;
+    }
+
+    /// Replaces `Instant::now()` for locate timeouts (e.g. for deterministic tests)
+    pub fn set_locate_clock<F>(&mut self, clock: F)
+    where
+        F: Fn() -> Instant + Send + 'static,
+    {
+        self.locate_clock = Box::new(clock);
+    }
+
+    fn locate_is_ready(&self) -> bool {
+        match self.locate {
+            Some(ref locate) => {
+                !self.previously_rolling
+                    && self.data_consumer.is_some()
+                    && self.position == locate.frame
+            }
+            None => false,
+        }
+    }
+
+    /// Starts seeking to the requested frame, if possible
+    fn advance_locate(&mut self) {
+        self.poll_ready_queue();
+        let frame = match self.locate {
+            Some(ref locate) => locate.frame,
+            None => return,
+        };
+        if !self.previously_rolling && self.position != frame {
+            // NB: If the reader is still busy, this is tried again when it has finished
+            if let Some(queue) = self.data_consumer.take() {
+                self.request_seek(frame, queue, None);
+                self.seek_pending = true;
+                self.position = frame;
+            }
+        }
+        self.publish_transport();
+    }
+
+    fn request_seek(&mut self, frame: usize, queue: DataConsumer, producer: Option<DataProducer>) {
+        self.transport
+            .shared
+            .seek_requested
+            .store(true, Ordering::Release);
+        // There is only one data queue, push() will always succeed
+        self.seek_producer.push((frame, queue, producer)).unwrap();
+    }
+
+    /// Takes over the data queue if the reader thread has finished seeking
+    fn poll_ready_queue(&mut self) {
         if self.data_consumer.is_none() {
             // NB: There can never be more than one message
             if let Ok((ready_frame, queue)) = self.ready_consumer.pop() {
                 self.data_consumer = Some(queue);
-                if ready_frame == frame {
-                    return true;
-                }
+                self.position = ready_frame;
+                self.seek_pending = false;
             }
         }
-        if let Some(queue) = self.data_consumer.take() {
-            self.seek_producer.push((frame, queue)).unwrap();
-        }
-        false
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.position >= self.duration() && self.end_action() != EndAction::Loop
+    }
+
+    fn publish_transport(&self) {
+        let state = if self.seek_frame.is_some() || self.seek_pending {
+            State::Seeking
+        } else if self.data_consumer.is_none() {
+            State::Stopped
+        } else if self.is_at_end() {
+            State::Ended
+        } else if self.previously_rolling {
+            State::Rolling
+        } else {
+            State::Ready
+        };
+        let shared = &self.transport.shared;
+        shared.position.store(self.position, Ordering::Release);
+        shared.state.store(state as usize, Ordering::Release);
     }
 }
 
 impl Drop for FileStreamer {
     fn drop(&mut self) {
-        self.reader_thread_keep_reading
-            .store(false, Ordering::Release);
-        // TODO: handle error from closure? log errors?
-        self.reader_thread.take().unwrap().join().unwrap().unwrap();
+        // NB: Errors from the reader have already been reported, see reader_error()
+        match self.reader.take() {
+            Some(ReaderHandle::Thread {
+                thread,
+                keep_reading,
+            }) => {
+                keep_reading.store(false, Ordering::Release);
+                let _ = thread.join();
+            }
+            Some(ReaderHandle::Pool { pool, reader }) => {
+                let _ = pool.remove(&reader);
+            }
+            Some(ReaderHandle::Manual) | None => {}
+        }
+    }
+}
+
+/// Fills `frames` frames of `channels` (starting at `offset`) with all active playlist entries
+///
+/// Entries that are still waiting for data are filled last, see `AudioFile::reads_pending()`.
+/// `pending` is only used to avoid allocations.
+#[allow(clippy::too_many_arguments)]
+fn fill_segment(
+    playlist: &mut [PlaylistEntry],
+    gain_envelopes: &mut GainEnvelopes,
+    gains: &mut [f32],
+    channels: &mut [Box<[f32]>],
+    offset: usize,
+    frames: usize,
+    current_frame: usize,
+    discontinuity: bool,
+    transport: &SharedTransport,
+    pending: &mut Vec<usize>,
+) -> Result<(), Error> {
+    let active_files = ActiveIter {
+        block_start: current_frame,
+        block_end: current_frame + frames,
+        inner: playlist.iter_mut().enumerate(),
+    };
+    for (_, file) in active_files {
+        file.file.submit_reads()?;
+    }
+    let mut fill = |index: usize, file: &mut PlaylistEntry| -> Result<(), Error> {
+        let start = if file.start < current_frame {
+            if discontinuity {
+                file.seek(file.file_offset + current_frame - file.start)?;
+            }
+            0
+        } else {
+            if !file.prefetched {
+                file.seek(file.file_offset)?;
+            }
+            file.start - current_frame
+        };
+        let end = match file.end {
+            Some(end) if end < current_frame + frames => end - current_frame,
+            _ => frames,
+        };
+        if end <= start {
+            // NB: This can only happen if an entry ends before it starts
+            return Ok(());
+        }
+        gain_envelopes.fill_gains(
+            index,
+            current_frame + start,
+            &mut gains[offset + start..offset + end],
+        );
+        file.apply_fades(
+            current_frame + start,
+            &mut gains[offset + start..offset + end],
+        );
+        let filled = file.fill_channels(gains, offset + end, offset + start, channels)?;
+        if filled.end_of_file && !file.ended_early {
+            let frame = file.file_offset + current_frame + start - file.start + filled.frames;
+            let frames = file.file.frames();
+            if frame < frames {
+                file.ended_early = true;
+                transport.warn(StreamWarning::FileTooShort {
+                    entry: index,
+                    frame,
+                    frames,
+                });
+            }
+        }
+        Ok(())
+    };
+    let active_files = ActiveIter {
+        block_start: current_frame,
+        block_end: current_frame + frames,
+        inner: playlist.iter_mut().enumerate(),
+    };
+    pending.clear();
+    // TODO: Is linear search too slow? How long can playlists be?
+    for (index, file) in active_files {
+        if file.reads_pending() {
+            pending.push(index);
+        } else {
+            fill(index, file)?;
+        }
+    }
+    while !pending.is_empty() {
+        // NB: If all remaining entries are still waiting, the first one is filled (which blocks)
+        let position = pending
+            .iter()
+            .position(|&index| !playlist[index].reads_pending())
+            .unwrap_or(0);
+        let index = pending.remove(position);
+        fill(index, &mut playlist[index])?;
+    }
+    Ok(())
+}
+
+/// Maximum number of warnings that are kept until `TransportHandle::take_warnings()`
+const MAX_WARNINGS: usize = 100;
+
+/// The reader thread hands over the data queue after seeking once this is reached
+fn min_buffer_frames(blocksize: usize) -> usize {
+    // TODO: provide min_buffer_duration in seconds?
+    std::cmp::max(4096, 2 * blocksize)
+}
+
+/// Playlist entries starting within this range are prefetched
+fn prefetch_frames(blocksize: usize) -> usize {
+    // TODO: make this configurable?
+    16 * min_buffer_frames(blocksize)
+}
+
+/// Output buffers of `FileStreamer::get_data()` and `get_data_interleaved()`
+trait Output {
+    fn channels(&self) -> usize;
+
+    /// The caller has to make sure that `channel` and `frame` are in range
+    unsafe fn write(&self, channel: usize, frame: usize, value: f32);
+}
+
+/// One pointer per channel
+struct Planar<'a, S: Sample>(&'a [*mut S]);
+
+impl<'a, S: Sample> Output for Planar<'a, S> {
+    fn channels(&self) -> usize {
+        self.0.len()
+    }
+
+    unsafe fn write(&self, channel: usize, frame: usize, value: f32) {
+        *self.0[channel].add(frame) = S::from_f32(value);
+    }
+}
+
+struct Interleaved<S: Sample> {
+    ptr: *mut S,
+    channels: usize,
+}
+
+impl<S: Sample> Output for Interleaved<S> {
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    unsafe fn write(&self, channel: usize, frame: usize, value: f32) {
+        *self.ptr.add(frame * self.channels + channel) = S::from_f32(value);
+    }
+}
+
+/// Returns the message of a panic (from the payload returned by `std::panic::catch_unwind()`)
+///
+/// The payload is typically a string, otherwise `"unknown panic"` is returned.
+/// This is also used by the C API, it's not part of the public API.
+#[doc(hidden)]
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
     }
 }
 
-unsafe fn fill_with_zeros(target: &[*mut f32], blocksize: usize) {
-    for ptr in target {
-        for f in 0..blocksize {
-            *ptr.add(f) = 0.0f32;
+unsafe fn fill_with_zeros<O: Output>(target: &O, offset: usize, frames: usize) {
+    for channel in 0..target.channels() {
+        for frame in offset..offset + frames {
+            target.write(channel, frame, 0.0);
         }
     }
 }
@@ -0,0 +1,487 @@
+//! Batched reads with `io_uring` (Linux only, needs the `io-uring` feature).
+//!
+//! Each `UringFile` reads one chunk ahead of its decoder.
+//! The reads of all files that share a `Uring` are queued and then submitted
+//! with a single system call before the active playlist entries are decoded.
+//! A decoder only waits if it needs a chunk that hasn't arrived yet,
+//! the reads of the other files continue in the meantime.
+//! Playlist entries whose next chunk is still in flight are decoded after all others
+//! (see `AudioFile::reads_pending()`).
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::disk::{self, AlignedBuffer, ReadAhead, ReadOptions, ALIGNMENT};
+
+/// A shared `io_uring` instance, see `ReadOptions::uring`
+///
+/// All files that are used by the same reader thread should share one `Uring`.
+#[derive(Clone)]
+pub struct Uring {
+    driver: Arc<Mutex<Driver>>,
+}
+
+impl fmt::Debug for Uring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Uring { .. }")
+    }
+}
+
+impl Uring {
+    /// `entries` is the size of the submission queue
+    pub fn new(entries: u32) -> io::Result<Uring> {
+        Ok(Uring {
+            driver: Arc::new(Mutex::new(Driver {
+                ring: IoUring::new(entries)?,
+                requests: Vec::new(),
+                queued: 0,
+            })),
+        })
+    }
+
+    /// Submits all queued reads without waiting for them
+    pub fn submit(&self) -> io::Result<()> {
+        self.driver.lock().unwrap().submit()
+    }
+}
+
+enum ChunkState {
+    Idle,
+    InFlight,
+    Done(io::Result<usize>),
+}
+
+struct Chunk {
+    buffer: AlignedBuffer,
+    /// File offset of the first byte in `buffer`
+    offset: u64,
+    state: ChunkState,
+}
+
+impl Chunk {
+    fn is_in_flight(&self) -> bool {
+        matches!(self.state, ChunkState::InFlight)
+    }
+}
+
+/// Keeps the buffer and the file descriptor alive while the kernel is using them
+struct Request {
+    chunk: Arc<Mutex<Chunk>>,
+    _file: Arc<fs::File>,
+}
+
+struct Driver {
+    ring: IoUring,
+    /// Reads that have not been completed yet, indexed by `user_data`
+    requests: Vec<Option<Request>>,
+    /// Number of reads that have been queued since the last submission
+    queued: usize,
+}
+
+impl Driver {
+    /// Errors are reported when waiting for the chunk
+    fn queue(&mut self, file: &Arc<fs::File>, chunk: &Arc<Mutex<Chunk>>) {
+        let index = match self.requests.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.requests.push(None);
+                self.requests.len() - 1
+            }
+        };
+        let entry = {
+            let mut chunk = chunk.lock().unwrap();
+            chunk.state = ChunkState::InFlight;
+            let fd = types::Fd(file.as_raw_fd());
+            opcode::Read::new(fd, chunk.buffer.as_mut_ptr(), chunk.buffer.len() as u32)
+                .offset(chunk.offset)
+                .build()
+                .user_data(index as u64)
+        };
+        // NB: The buffer is used by the kernel until the request is completed
+        while unsafe { self.ring.submission().push(&entry) }.is_err() {
+            // The submission queue is full
+            if let Err(e) = self.submit() {
+                chunk.lock().unwrap().state = ChunkState::Done(Err(e));
+                return;
+            }
+        }
+        self.requests[index] = Some(Request {
+            chunk: Arc::clone(chunk),
+            _file: Arc::clone(file),
+        });
+        self.queued += 1;
+    }
+
+    fn submit(&mut self) -> io::Result<()> {
+        if self.queued > 0 {
+            self.ring.submit()?;
+            self.queued = 0;
+        }
+        self.reap();
+        Ok(())
+    }
+
+    /// Waits until `chunk` is not in flight anymore
+    fn wait_for(&mut self, chunk: &Mutex<Chunk>) -> io::Result<()> {
+        while chunk.lock().unwrap().is_in_flight() {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+            self.queued = 0;
+            self.reap();
+        }
+        Ok(())
+    }
+
+    fn reap(&mut self) {
+        for entry in self.ring.completion() {
+            let request = self.requests[entry.user_data() as usize]
+                .take()
+                .expect("Completion for unknown request");
+            let result = entry.result();
+            request.chunk.lock().unwrap().state = ChunkState::Done(if result < 0 {
+                Err(io::Error::from_raw_os_error(-result))
+            } else {
+                Ok(result as usize)
+            });
+        }
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        // NB: The kernel might still write into the buffers
+        while self.requests.iter().any(Option::is_some) {
+            if self.ring.submit_and_wait(1).is_err() {
+                // Leaking is better than a use-after-free
+                std::mem::forget(std::mem::take(&mut self.requests));
+                break;
+            }
+            self.reap();
+        }
+    }
+}
+
+/// Checks whether the next chunk of a `UringFile` is still being read
+///
+/// This can be used while the `UringFile` itself is owned by a decoder.
+#[derive(Clone)]
+pub struct PendingRead {
+    next: Arc<Mutex<Chunk>>,
+    driver: Arc<Mutex<Driver>>,
+}
+
+impl PendingRead {
+    /// NB: The decoder might not even need the next chunk yet
+    pub fn is_pending(&self) -> bool {
+        // NB: Completions are only reaped when submitting and waiting
+        self.driver.lock().unwrap().reap();
+        self.next.lock().unwrap().is_in_flight()
+    }
+}
+
+/// Like `DiskFile`, but the next chunk is read with a `Uring`
+pub struct UringFile {
+    file: Arc<fs::File>,
+    length: u64,
+    position: u64,
+    current: AlignedBuffer,
+    /// File offset of the first byte in `current`
+    current_start: u64,
+    /// Number of valid bytes in `current`
+    current_len: usize,
+    next: Arc<Mutex<Chunk>>,
+    driver: Arc<Mutex<Driver>>,
+    read_ahead: Option<ReadAhead>,
+    direct: bool,
+}
+
+impl UringFile {
+    pub fn open<P>(path: P, options: &ReadOptions, uring: &Uring) -> io::Result<UringFile>
+    where
+        P: AsRef<Path>,
+    {
+        let file = Arc::new(disk::open(path.as_ref(), options)?);
+        let length = file.metadata()?.len();
+        let read_size = options.aligned_read_size();
+        Ok(UringFile {
+            read_ahead: ReadAhead::new(&file, options),
+            file,
+            length,
+            position: 0,
+            current: AlignedBuffer::new(read_size),
+            current_start: 0,
+            current_len: 0,
+            next: Arc::new(Mutex::new(Chunk {
+                buffer: AlignedBuffer::new(read_size),
+                offset: 0,
+                state: ChunkState::Idle,
+            })),
+            driver: Arc::clone(&uring.driver),
+            direct: options.direct,
+        })
+    }
+
+    /// See `DiskFile::read_ahead()`
+    pub fn read_ahead(&self) -> Option<ReadAhead> {
+        self.read_ahead.clone()
+    }
+
+    pub fn pending_read(&self) -> PendingRead {
+        PendingRead {
+            next: Arc::clone(&self.next),
+            driver: Arc::clone(&self.driver),
+        }
+    }
+
+    /// Makes the chunk containing the current position the current chunk
+    /// and queues a read for the chunk after it
+    fn load_chunk(&mut self) -> io::Result<()> {
+        // NB: The buffer cannot be re-used before the read has completed
+        self.driver.lock().unwrap().wait_for(&self.next)?;
+        let position = self.position;
+        let reused = {
+            let mut next = self.next.lock().unwrap();
+            let state = std::mem::replace(&mut next.state, ChunkState::Idle);
+            let offset = next.offset;
+            let covers = |len: usize| offset <= position && position < offset + len as u64;
+            match state {
+                ChunkState::Done(Ok(len)) if covers(len) => {
+                    self.current_start = next.offset;
+                    self.current_len = len;
+                    std::mem::swap(&mut self.current, &mut next.buffer);
+                    true
+                }
+                ChunkState::Done(Err(e)) if covers(next.buffer.len()) => return Err(e),
+                _ => false,
+            }
+        };
+        if !reused {
+            // After seeking (or at the beginning), there is nothing to wait for
+            let start = position - position % ALIGNMENT as u64;
+            self.current_start = start;
+            // NB: The buffer is invalid if reading fails
+            self.current_len = 0;
+            self.current_len = disk::read_chunk(&self.file, &mut self.current, start, self.direct)?;
+        }
+        let end = self.current_start + self.current_len as u64;
+        if end < self.length {
+            self.next.lock().unwrap().offset = end;
+            self.driver.lock().unwrap().queue(&self.file, &self.next);
+        }
+        Ok(())
+    }
+}
+
+impl Read for UringFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.length {
+            return Ok(0);
+        }
+        if self.position < self.current_start
+            || self.position >= self.current_start + self.current_len as u64
+        {
+            self.load_chunk()?;
+        }
+        let offset = (self.position - self.current_start) as usize;
+        if offset >= self.current_len {
+            // The file has been truncated
+            return Ok(0);
+        }
+        let available = &self.current[offset..self.current_len];
+        let n = std::cmp::min(buf.len(), available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for UringFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => disk::add_offset(self.length, offset),
+            SeekFrom::Current(offset) => disk::add_offset(self.position, offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                if let Some(ref read_ahead) = self.read_ahead {
+                    read_ahead.set_position(position);
+                }
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    /// Removes the file when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        /// Byte `i` has the value `(i * 7 + seed) % 251`
+        fn new(name: &str, len: usize, seed: usize) -> TempFile {
+            let path = std::env::temp_dir().join(format!(
+                "disk-streaming-uring-{}-{}.raw",
+                name,
+                std::process::id()
+            ));
+            fs::write(&path, content(len, seed)).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn content(len: usize, seed: usize) -> Vec<u8> {
+        (0..len).map(|i| ((i * 7 + seed) % 251) as u8).collect()
+    }
+
+    fn options(uring: &Uring) -> ReadOptions {
+        ReadOptions {
+            read_size: ALIGNMENT,
+            read_ahead: 0,
+            uring: Some(uring.clone()),
+            ..Default::default()
+        }
+    }
+
+    fn chunk(offset: u64) -> Arc<Mutex<Chunk>> {
+        Arc::new(Mutex::new(Chunk {
+            buffer: AlignedBuffer::new(ALIGNMENT),
+            offset,
+            state: ChunkState::Idle,
+        }))
+    }
+
+    #[test]
+    fn many_files() {
+        const FILES: usize = 50;
+        const LEN: usize = 5 * ALIGNMENT + 123;
+        let uring = Uring::new(8).unwrap();
+        let temp_files: Vec<_> = (0..FILES)
+            .map(|i| TempFile::new(&format!("many-{}", i), LEN, i))
+            .collect();
+        let mut files: Vec<_> = temp_files
+            .iter()
+            .map(|temp| UringFile::open(&temp.0, &options(&uring), &uring).unwrap())
+            .collect();
+        let mut data = vec![Vec::new(); FILES];
+        let mut buffer = [0; 1000];
+        // Round robin, like the reader thread
+        while data.iter().any(|data| data.len() < LEN) {
+            for (file, data) in files.iter_mut().zip(&mut data) {
+                let n = file.read(&mut buffer).unwrap();
+                data.extend_from_slice(&buffer[..n]);
+            }
+            uring.submit().unwrap();
+        }
+        for (i, data) in data.iter().enumerate() {
+            assert_eq!(*data, content(LEN, i), "file {}", i);
+        }
+        assert!(files
+            .iter_mut()
+            .all(|file| file.read(&mut buffer).unwrap() == 0));
+    }
+
+    #[test]
+    fn full_submission_queue() {
+        let temp = TempFile::new("full", 10 * ALIGNMENT, 0);
+        let file = Arc::new(fs::File::open(&temp.0).unwrap());
+        let uring = Uring::new(2).unwrap();
+        let chunks: Vec<_> = (0..10).map(|i| chunk((i * ALIGNMENT) as u64)).collect();
+        let mut driver = uring.driver.lock().unwrap();
+        // NB: The queue is submitted when it is full
+        for chunk in &chunks {
+            driver.queue(&file, chunk);
+        }
+        assert!(driver.queued <= 2);
+        let expected = content(10 * ALIGNMENT, 0);
+        for (i, chunk) in chunks.iter().enumerate() {
+            driver.wait_for(chunk).unwrap();
+            let chunk = chunk.lock().unwrap();
+            match chunk.state {
+                ChunkState::Done(Ok(len)) => assert_eq!(len, ALIGNMENT),
+                _ => panic!("chunk {} not read", i),
+            }
+            assert_eq!(*chunk.buffer, expected[i * ALIGNMENT..][..ALIGNMENT]);
+        }
+        assert!(driver.requests.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn pending_read() {
+        let temp = TempFile::new("pending", 3 * ALIGNMENT, 0);
+        let uring = Uring::new(4).unwrap();
+        let mut file = UringFile::open(&temp.0, &options(&uring), &uring).unwrap();
+        let pending = file.pending_read();
+        assert!(!pending.is_pending());
+        // The first chunk is read directly, the second one is queued
+        let mut buffer = [0; 10];
+        file.read_exact(&mut buffer).unwrap();
+        assert!(pending.is_pending());
+        uring.driver.lock().unwrap().wait_for(&file.next).unwrap();
+        assert!(!pending.is_pending());
+    }
+
+    #[test]
+    fn drop_with_reads_in_flight() {
+        let temp = TempFile::new("drop", 4 * ALIGNMENT, 0);
+        let file = Arc::new(fs::File::open(&temp.0).unwrap());
+        let uring = Uring::new(4).unwrap();
+        let chunks: Vec<_> = (0..4).map(|i| chunk((i * ALIGNMENT) as u64)).collect();
+        {
+            let mut driver = uring.driver.lock().unwrap();
+            for chunk in &chunks {
+                driver.queue(&file, chunk);
+            }
+            driver.submit().unwrap();
+        }
+        // Waits for the kernel before the buffers are released
+        drop(uring);
+        for chunk in &chunks {
+            assert_eq!(Arc::strong_count(chunk), 1);
+            assert!(!chunk.lock().unwrap().is_in_flight());
+        }
+        assert_eq!(Arc::strong_count(&file), 1);
+    }
+
+    #[test]
+    fn error_completion() {
+        let temp = TempFile::new("error", ALIGNMENT, 0);
+        // Reading from a file that is only open for writing fails with EBADF
+        let file = Arc::new(fs::OpenOptions::new().write(true).open(&temp.0).unwrap());
+        let uring = Uring::new(4).unwrap();
+        let chunk = chunk(0);
+        let mut driver = uring.driver.lock().unwrap();
+        driver.queue(&file, &chunk);
+        driver.wait_for(&chunk).unwrap();
+        match chunk.lock().unwrap().state {
+            ChunkState::Done(Err(ref e)) => assert_eq!(e.raw_os_error(), Some(libc::EBADF)),
+            _ => panic!("read should have failed"),
+        };
+        assert!(driver.requests.iter().all(Option::is_none));
+    }
+}
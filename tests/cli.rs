@@ -0,0 +1,233 @@
+//! Runs the `disk-streaming` command-line tool
+
+mod common;
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+use disk_streaming::sample::Sample;
+
+use common::{index_signal, sine, write_vorbis, write_wav, TempDir, WavFormat};
+
+const FRAMES: usize = 5000;
+const SAMPLERATE: usize = 44_100;
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_disk-streaming"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn check_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "stdout: {}\nstderr: {}",
+        stdout(output),
+        stderr(output)
+    );
+}
+
+fn file_channels() -> Vec<Vec<f32>> {
+    (0..2)
+        .map(|channel| index_signal(channel, FRAMES))
+        .collect()
+}
+
+/// A stereo file that starts at frame 100, its channels are swapped (and one is skipped)
+fn write_fixtures(dir: &TempDir) -> String {
+    write_wav(
+        dir.path("index.wav"),
+        SAMPLERATE,
+        &file_channels(),
+        WavFormat::Float32,
+    );
+    let description = r#"{
+        "samplerate": 44100,
+        "channels": 3,
+        "entries": [
+            {"path": "index.wav", "start": 100, "channel_map": [2, 0]}
+        ]
+    }"#;
+    let path = dir.path("playlist.json");
+    std::fs::write(&path, description).unwrap();
+    path.to_str().unwrap().into()
+}
+
+/// Interleaved output for the playlist from `write_fixtures()`
+fn expected_output() -> Vec<f32> {
+    let channels = file_channels();
+    let mut result = Vec::new();
+    for frame in 0..100 + FRAMES {
+        let sample = |channel: usize| {
+            if frame < 100 {
+                0.0
+            } else {
+                channels[channel][frame - 100]
+            }
+        };
+        result.extend(&[sample(1), 0.0, sample(0)]);
+    }
+    result
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn info() {
+    let dir = TempDir::new("cli-info");
+    write_fixtures(&dir);
+    let output = run(&["info", path_str(&dir.path("index.wav"))]);
+    check_success(&output);
+    let stdout = stdout(&output);
+    assert!(stdout.contains("WAV (32-bit float)"), "{}", stdout);
+    assert!(stdout.contains("channels:   2"), "{}", stdout);
+    assert!(stdout.contains("frames:     5000"), "{}", stdout);
+    assert!(stdout.contains("samplerate: 44100"), "{}", stdout);
+
+    write_wav(
+        dir.path("int.wav"),
+        SAMPLERATE,
+        &file_channels(),
+        WavFormat::Int16,
+    );
+    write_vorbis(
+        dir.path("sine.ogg"),
+        SAMPLERATE,
+        &[sine(440.0, SAMPLERATE, FRAMES)],
+        0.5,
+    );
+    let output = run(&[
+        "info",
+        path_str(&dir.path("int.wav")),
+        path_str(&dir.path("sine.ogg")),
+    ]);
+    check_success(&output);
+    let stdout = stdout(&output);
+    assert!(stdout.contains("WAV (16-bit PCM)"), "{}", stdout);
+    assert!(stdout.contains("Ogg Vorbis"), "{}", stdout);
+    assert!(stdout.contains("channels:   1"), "{}", stdout);
+}
+
+#[test]
+fn render() {
+    let dir = TempDir::new("cli-render");
+    let playlist = write_fixtures(&dir);
+    let expected = expected_output();
+
+    let output_path = dir.path("float.wav");
+    check_success(&run(&["render", &playlist, path_str(&output_path)]));
+    let mut reader = hound::WavReader::open(&output_path).unwrap();
+    let spec = reader.spec();
+    assert_eq!(spec.channels, 3);
+    assert_eq!(spec.sample_rate, SAMPLERATE as u32);
+    assert_eq!(spec.bits_per_sample, 32);
+    assert_eq!(spec.sample_format, hound::SampleFormat::Float);
+    let samples: Vec<f32> = reader.samples().map(Result::unwrap).collect();
+    assert_eq!(samples, expected);
+
+    let output_path = dir.path("int.wav");
+    check_success(&run(&[
+        "render",
+        "--bits",
+        "16",
+        "--blocksize",
+        "100",
+        &playlist,
+        path_str(&output_path),
+    ]));
+    let mut reader = hound::WavReader::open(&output_path).unwrap();
+    assert_eq!(reader.spec().bits_per_sample, 16);
+    let samples: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+    let expected: Vec<i16> = expected
+        .iter()
+        .map(|&sample| i16::from_f32(sample))
+        .collect();
+    assert_eq!(samples, expected);
+}
+
+#[test]
+fn bench() {
+    let dir = TempDir::new("cli-bench");
+    let playlist = write_fixtures(&dir);
+    let output = run(&["bench", "--blocksize", "256", &playlist]);
+    check_success(&output);
+    let stdout = stdout(&output);
+    assert!(stdout.contains("(20 blocks of 256 frames)"), "{}", stdout);
+    assert!(stdout.contains("x real time"), "{}", stdout);
+}
+
+#[test]
+fn verify() {
+    let dir = TempDir::new("cli-verify");
+    let playlist = write_fixtures(&dir);
+    let output = run(&["verify", &playlist]);
+    check_success(&output);
+    assert!(stdout(&output).contains("OK: "));
+
+    // The header announces more frames than there are in the file
+    let truncated = dir.path("truncated.wav");
+    std::fs::copy(dir.path("index.wav"), &truncated).unwrap();
+    let len = std::fs::metadata(&truncated).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&truncated)
+        .unwrap()
+        .set_len(len - 800)
+        .unwrap();
+    let output = run(&[
+        "verify",
+        path_str(&dir.path("index.wav")),
+        path_str(&truncated),
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("OK: "), "{}", stdout);
+    assert!(
+        stdout.contains("Reported 5000 frames, but 4900 could be decoded"),
+        "{}",
+        stdout
+    );
+    assert!(stderr(&output).contains("1 of 2 files failed verification"));
+}
+
+#[test]
+fn unused_options_are_rejected() {
+    let dir = TempDir::new("cli-options");
+    let playlist = write_fixtures(&dir);
+    let output = run(&["verify", "--samplerate", "48000", &playlist]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--samplerate cannot be used with \"verify\""));
+    let output = run(&["bench", "--bits", "16", &playlist]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--bits cannot be used with \"bench\""));
+
+    // Playlist descriptions specify their own sample rate
+    let output_path = dir.path("output.wav");
+    let output = run(&[
+        "render",
+        "--samplerate",
+        "48000",
+        &playlist,
+        path_str(&output_path),
+    ]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--samplerate 48000 cannot be used with"));
+    check_success(&run(&[
+        "render",
+        "--samplerate",
+        "44100",
+        &playlist,
+        path_str(&output_path),
+    ]));
+}
@@ -0,0 +1,383 @@
+//! Test support: synthetic signals, WAV/Vorbis/FLAC fixtures and in-memory playlist entries.
+//!
+//! WAV (16-bit integer and 32-bit float) and FLAC (16-bit) fixtures contain the exact values
+//! of `index_sample()`, Vorbis fixtures are lossy (and are therefore made from sines).
+
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use libc::{c_int, c_long};
+
+use disk_streaming::file::memory;
+use disk_streaming::streamer::{PlaylistEntry, Route};
+
+/// Maximum number of channels that can be encoded by `index_sample()`
+pub const INDEX_CHANNELS: usize = 8;
+
+/// Frame numbers are encoded by `index_sample()` modulo this
+pub const INDEX_PERIOD: usize = 65_536 / INDEX_CHANNELS;
+
+/// A sample value that encodes `frame` (modulo 8192) and `channel`
+///
+/// The values are exactly representable in 16-bit and float files.
+pub fn index_sample(frame: usize, channel: usize) -> f32 {
+    assert!(channel < INDEX_CHANNELS);
+    let value = ((frame * INDEX_CHANNELS + channel) % 65_536) as i32 - 32_768;
+    value as f32 / 32_767.0
+}
+
+/// Inverse of `index_sample()`, returns frame (modulo 8192) and channel
+pub fn decode_index(sample: f32) -> (usize, usize) {
+    let value = (sample * 32_767.0).round() as i32 + 32_768;
+    let value = value as usize;
+    (value / INDEX_CHANNELS, value % INDEX_CHANNELS)
+}
+
+pub fn index_signal(channel: usize, frames: usize) -> Vec<f32> {
+    (0..frames)
+        .map(|frame| index_sample(frame, channel))
+        .collect()
+}
+
+/// From 0 (inclusive) to 1 (exclusive)
+pub fn ramp(frames: usize) -> Vec<f32> {
+    (0..frames)
+        .map(|frame| frame as f32 / frames as f32)
+        .collect()
+}
+
+pub fn sine(frequency: f64, samplerate: usize, frames: usize) -> Vec<f32> {
+    (0..frames)
+        .map(|frame| {
+            let phase = 2.0 * std::f64::consts::PI * frequency * frame as f64 / samplerate as f64;
+            phase.sin() as f32
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum WavFormat {
+    /// Samples are rounded, see `index_sample()` for exact values
+    Int16,
+    /// Samples are rounded, values `n / 8_388_607.0` are exact
+    Int24,
+    /// Samples are rounded (only 24 bits are used when decoding)
+    Int32,
+    Float32,
+}
+
+/// Panics on errors
+pub fn write_wav<P>(path: P, samplerate: usize, channels: &[Vec<f32>], format: WavFormat)
+where
+    P: AsRef<Path>,
+{
+    let frames = channels.first().map_or(0, |channel| channel.len());
+    let spec = hound::WavSpec {
+        channels: channels.len() as u16,
+        sample_rate: samplerate as u32,
+        bits_per_sample: match format {
+            WavFormat::Int16 => 16,
+            WavFormat::Int24 => 24,
+            WavFormat::Int32 | WavFormat::Float32 => 32,
+        },
+        sample_format: match format {
+            WavFormat::Int16 | WavFormat::Int24 | WavFormat::Int32 => hound::SampleFormat::Int,
+            WavFormat::Float32 => hound::SampleFormat::Float,
+        },
+    };
+    let mut writer = hound::WavWriter::create(path, spec).unwrap();
+    for frame in 0..frames {
+        for channel in channels {
+            let sample = channel[frame];
+            match format {
+                WavFormat::Int16 => writer
+                    .write_sample((sample * 32_767.0).round() as i16)
+                    .unwrap(),
+                WavFormat::Int24 => writer
+                    .write_sample((sample * 8_388_607.0).round() as i32)
+                    .unwrap(),
+                WavFormat::Int32 => writer
+                    .write_sample((sample as f64 * i32::MAX as f64).round() as i32)
+                    .unwrap(),
+                WavFormat::Float32 => writer.write_sample(sample).unwrap(),
+            }
+        }
+    }
+    writer.finalize().unwrap();
+}
+
+/// Encodes `channels` with libvorbis (VBR with the given `quality`), panics on errors
+///
+/// The decoded file has the same number of frames, but the values are only approximations.
+pub fn write_vorbis<P>(path: P, samplerate: usize, channels: &[Vec<f32>], quality: f32)
+where
+    P: AsRef<Path>,
+{
+    use ogg_sys::*;
+    use vorbis_sys::*;
+
+    // https://xiph.org/vorbis/doc/libvorbis/overview.html
+    let frames = channels.first().map_or(0, |channel| channel.len());
+    let mut output = Vec::new();
+    let write_page = |output: &mut Vec<u8>, page: &ogg_page| unsafe {
+        output.extend_from_slice(std::slice::from_raw_parts(
+            page.header,
+            page.header_len as usize,
+        ));
+        output.extend_from_slice(std::slice::from_raw_parts(
+            page.body,
+            page.body_len as usize,
+        ));
+    };
+    unsafe {
+        let mut info: vorbis_info = std::mem::zeroed();
+        vorbis_info_init(&mut info);
+        let result = vorbisenc_sys::vorbis_encode_init_vbr(
+            &mut info,
+            channels.len() as c_long,
+            samplerate as c_long,
+            quality,
+        );
+        assert_eq!(result, 0, "vorbis_encode_init_vbr() failed");
+        let mut comment: vorbis_comment = std::mem::zeroed();
+        vorbis_comment_init(&mut comment);
+        let mut dsp: vorbis_dsp_state = std::mem::zeroed();
+        assert_eq!(vorbis_analysis_init(&mut dsp, &mut info), 0);
+        let mut block: vorbis_block = std::mem::zeroed();
+        assert_eq!(vorbis_block_init(&mut dsp, &mut block), 0);
+        let mut stream: ogg_stream_state = std::mem::zeroed();
+        assert_eq!(ogg_stream_init(&mut stream, 1), 0);
+        let mut page: ogg_page = std::mem::zeroed();
+        let mut packet: ogg_packet = std::mem::zeroed();
+
+        let mut headers: [ogg_packet; 3] = std::mem::zeroed();
+        let [ref mut ident, ref mut comments, ref mut code] = headers;
+        assert_eq!(
+            vorbis_analysis_headerout(&mut dsp, &mut comment, ident, comments, code),
+            0
+        );
+        for header in headers.iter_mut() {
+            assert_eq!(ogg_stream_packetin(&mut stream, header), 0);
+        }
+        // NB: The audio data has to start on a new page
+        while ogg_stream_flush(&mut stream, &mut page) != 0 {
+            write_page(&mut output, &page);
+        }
+
+        let mut offset = 0;
+        loop {
+            let count = std::cmp::min(1024, frames - offset);
+            if count > 0 {
+                let buffer = vorbis_analysis_buffer(&mut dsp, count as c_int);
+                for (i, channel) in channels.iter().enumerate() {
+                    std::slice::from_raw_parts_mut(*buffer.add(i), count)
+                        .copy_from_slice(&channel[offset..offset + count]);
+                }
+            }
+            // NB: Zero frames mark the end of the stream
+            assert_eq!(vorbis_analysis_wrote(&mut dsp, count as c_int), 0);
+            offset += count;
+            while vorbis_analysis_blockout(&mut dsp, &mut block) == 1 {
+                assert_eq!(vorbis_analysis(&mut block, ptr::null_mut()), 0);
+                assert_eq!(vorbis_bitrate_addblock(&mut block), 0);
+                while vorbis_bitrate_flushpacket(&mut dsp, &mut packet) == 1 {
+                    assert_eq!(ogg_stream_packetin(&mut stream, &mut packet), 0);
+                    while ogg_stream_pageout(&mut stream, &mut page) != 0 {
+                        write_page(&mut output, &page);
+                    }
+                }
+            }
+            if count == 0 {
+                break;
+            }
+        }
+        while ogg_stream_flush(&mut stream, &mut page) != 0 {
+            write_page(&mut output, &page);
+        }
+
+        ogg_stream_clear(&mut stream);
+        vorbis_block_clear(&mut block);
+        vorbis_dsp_clear(&mut dsp);
+        vorbis_comment_clear(&mut comment);
+        vorbis_info_clear(&mut info);
+    }
+    std::fs::write(path, output).unwrap();
+}
+
+/// Number of frames per FLAC frame in `write_flac()` (the last one is shorter)
+pub const FLAC_BLOCKSIZE: usize = 1152;
+
+/// Writes a 16-bit FLAC file (without compression), panics on errors
+///
+/// Samples are rounded like in `WavFormat::Int16`.
+pub fn write_flac<P>(path: P, samplerate: usize, channels: &[Vec<f32>])
+where
+    P: AsRef<Path>,
+{
+    // https://xiph.org/flac/format.html
+    let frames = channels.first().map_or(0, |channel| channel.len());
+    assert!((1..=8).contains(&channels.len()));
+    let mut output = b"fLaC".to_vec();
+    // Last metadata block, STREAMINFO with 34 bytes
+    output.extend_from_slice(&[0x80, 0, 0, 34]);
+    output.extend_from_slice(&(FLAC_BLOCKSIZE as u16).to_be_bytes());
+    output.extend_from_slice(&(FLAC_BLOCKSIZE as u16).to_be_bytes());
+    // Minimum and maximum frame size are unknown
+    output.extend_from_slice(&[0; 6]);
+    let info =
+        (samplerate as u64) << 44 | ((channels.len() - 1) as u64) << 41 | 15 << 36 | frames as u64;
+    output.extend_from_slice(&info.to_be_bytes());
+    // MD5 signature (unknown)
+    output.extend_from_slice(&[0; 16]);
+
+    for (number, start) in (0..frames).step_by(FLAC_BLOCKSIZE).enumerate() {
+        let blocksize = std::cmp::min(FLAC_BLOCKSIZE, frames - start);
+        // Sync code, fixed blocksize, 16-bit blocksize at the end of the header,
+        // sample rate from STREAMINFO, independent channels, 16 bits per sample
+        let mut frame = vec![0xFF, 0xF8, 0x70, ((channels.len() - 1) << 4 | 0x08) as u8];
+        frame.extend(flac_number(number as u32));
+        frame.extend_from_slice(&((blocksize - 1) as u16).to_be_bytes());
+        frame.push(flac_crc8(&frame));
+        for channel in channels {
+            // Verbatim subframe without wasted bits
+            frame.push(0x02);
+            for &sample in &channel[start..start + blocksize] {
+                frame.extend_from_slice(&((sample * 32_767.0).round() as i16).to_be_bytes());
+            }
+        }
+        let crc = flac_crc16(&frame);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        output.extend(frame);
+    }
+    std::fs::write(path, output).unwrap();
+}
+
+/// "UTF-8" coding of frame numbers
+fn flac_number(number: u32) -> Vec<u8> {
+    if number < 0x80 {
+        return vec![number as u8];
+    }
+    let mut bytes = 2;
+    while number >= 1 << (5 * bytes + 1) {
+        bytes += 1;
+    }
+    let mut result = vec![(0xFF00u16 >> bytes) as u8 | (number >> (6 * (bytes - 1))) as u8];
+    for i in (0..bytes - 1).rev() {
+        result.push(0x80 | ((number >> (6 * i)) as u8 & 0x3F));
+    }
+    result
+}
+
+/// Polynomial x^8 + x^2 + x + 1
+fn flac_crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Polynomial x^16 + x^15 + x^2 + 1
+fn flac_crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A temporary directory for fixtures, it is removed on drop
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "disk-streaming-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self, file_name: &str) -> PathBuf {
+        self.0.join(file_name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A playlist entry that plays `channels` from memory (without an end)
+pub fn memory_entry(
+    start: usize,
+    samplerate: usize,
+    channels: Vec<Vec<f32>>,
+    channel_map: &[Option<usize>],
+) -> PlaylistEntry {
+    let data = memory::Data::from_channels(samplerate, channels);
+    let file = Box::new(memory::File::new(Arc::new(data)));
+    PlaylistEntry::new(start, None, file, Route::from_channel_map(channel_map)).unwrap()
+}
+
+/// Concatenates two Ogg streams into a chained stream
+///
+/// The serial number of all pages of `second` is changed, as required for chaining.
+pub fn ogg_chain(first: &[u8], second: &[u8]) -> Vec<u8> {
+    let mut result = first.to_vec();
+    let mut offset = result.len();
+    result.extend_from_slice(second);
+    // https://xiph.org/ogg/doc/framing.html
+    while offset < result.len() {
+        assert_eq!(&result[offset..offset + 4], b"OggS", "Invalid Ogg page");
+        let segments = result[offset + 26] as usize;
+        let table = &result[offset + 27..offset + 27 + segments];
+        let len = 27 + segments + table.iter().map(|&x| x as usize).sum::<usize>();
+        let page = &mut result[offset..offset + len];
+        let serial = u32::from_le_bytes([page[14], page[15], page[16], page[17]]);
+        page[14..18].copy_from_slice(&serial.wrapping_add(1).to_le_bytes());
+        page[22..26].copy_from_slice(&[0; 4]);
+        let crc = ogg_crc(page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        offset += len;
+    }
+    result
+}
+
+fn ogg_crc(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,216 @@
+//! State transitions of `FileStreamer::request_locate()`
+//!
+//! The reader and the clock for the timeouts are driven explicitly.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use disk_streaming::streamer::{
+    load_audio_file, FileStreamer, LocateState, ManualReader, PlaylistEntry, Route,
+};
+
+use common::{decode_index, index_signal, write_wav, TempDir, WavFormat, INDEX_PERIOD};
+
+const BLOCKSIZE: usize = 1024;
+const FRAMES: usize = 60_000;
+const SAMPLERATE: usize = 44_100;
+const TIMEOUT: Duration = Duration::from_millis(50);
+
+/// The fixture is removed when the returned `TempDir` is dropped
+fn streamer() -> (TempDir, FileStreamer, ManualReader) {
+    let dir = TempDir::new("locate");
+    let path = dir.path("index.wav");
+    write_wav(
+        &path,
+        SAMPLERATE,
+        &[index_signal(0, FRAMES)],
+        WavFormat::Int16,
+    );
+    let file = load_audio_file(&path, SAMPLERATE).unwrap();
+    let entry = PlaylistEntry::new(0, None, file, Route::from_channel_map(&[Some(0)])).unwrap();
+    let (streamer, reader) = FileStreamer::with_manual_reader(vec![entry], BLOCKSIZE, 1).unwrap();
+    (dir, streamer, reader)
+}
+
+/// A clock that only advances when told to
+#[derive(Clone)]
+struct Clock(Arc<Mutex<Instant>>);
+
+impl Clock {
+    fn install(streamer: &mut FileStreamer) -> Clock {
+        let clock = Clock(Arc::new(Mutex::new(Instant::now())));
+        let now = clock.clone();
+        streamer.set_locate_clock(move || *now.0.lock().unwrap());
+        streamer.set_locate_timeout(Some(TIMEOUT));
+        clock
+    }
+
+    fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+fn make_ready(streamer: &mut FileStreamer, reader: &mut ManualReader, frame: usize) {
+    let mut attempts = 0;
+    while !streamer.is_ready(frame) {
+        // NB: One attempt may be needed to finish the initial seek
+        assert!(attempts < 2, "not ready after {} attempts", attempts);
+        reader.fill().unwrap();
+        attempts += 1;
+    }
+    assert_eq!(streamer.locate_state(), LocateState::Ready(frame));
+    assert_eq!(streamer.position(), frame);
+}
+
+fn get_data(streamer: &mut FileStreamer, reader: &mut ManualReader, rolling: bool) -> Vec<f32> {
+    reader.fill().unwrap();
+    let mut buffer = vec![0.0; BLOCKSIZE];
+    assert!(streamer.get_data_interleaved(&mut buffer, rolling));
+    buffer
+}
+
+/// Starts rolling at `frame` (the data has to be available)
+fn start_rolling(streamer: &mut FileStreamer, reader: &mut ManualReader, frame: usize) {
+    make_ready(streamer, reader, frame);
+    get_data(streamer, reader, true);
+    assert_eq!(streamer.locate_state(), LocateState::Idle);
+}
+
+/// Checks that `data` contains the frames starting at `frame` (see `index_sample()`)
+fn assert_frames(data: &[f32], frame: usize) {
+    for (i, &sample) in data.iter().enumerate() {
+        let expected = ((frame + i) % INDEX_PERIOD, 0);
+        assert_eq!(decode_index(sample), expected, "frame: {}", frame + i);
+    }
+}
+
+#[test]
+fn idle_to_seeking_to_ready() {
+    let (_dir, mut streamer, mut reader) = streamer();
+    assert_eq!(streamer.locate_state(), LocateState::Idle);
+    streamer.request_locate(10_000);
+    assert_eq!(streamer.locate_state(), LocateState::Seeking(10_000));
+    // Nothing happens until the reader is driven
+    assert!(!streamer.is_ready(10_000));
+    assert_eq!(streamer.locate_state(), LocateState::Seeking(10_000));
+    make_ready(&mut streamer, &mut reader, 10_000);
+}
+
+#[test]
+fn idle_to_ready() {
+    let (_dir, mut streamer, mut reader) = streamer();
+    make_ready(&mut streamer, &mut reader, 10_000);
+    assert!(streamer.seek(10_000));
+    assert_eq!(streamer.locate_state(), LocateState::Idle);
+    streamer.request_locate(10_000);
+    assert_eq!(streamer.locate_state(), LocateState::Ready(10_000));
+}
+
+#[test]
+fn ready_to_idle_when_rolling() {
+    let (_dir, mut streamer, mut reader) = streamer();
+    make_ready(&mut streamer, &mut reader, 20_000);
+    // NB: Zero frames avoid the fade-in
+    assert!(streamer.get_data_interleaved::<f32>(&mut [], true));
+    assert_eq!(streamer.locate_state(), LocateState::Idle);
+    assert_frames(&get_data(&mut streamer, &mut reader, true), 20_000);
+    assert!(!streamer.is_ready(20_000 + BLOCKSIZE));
+}
+
+#[test]
+fn ready_to_seeking_another_frame() {
+    let (_dir, mut streamer, mut reader) = streamer();
+    make_ready(&mut streamer, &mut reader, 10_000);
+    streamer.request_locate(10_000);
+    assert_eq!(streamer.locate_state(), LocateState::Ready(10_000));
+    streamer.request_locate(30_000);
+    assert_eq!(streamer.locate_state(), LocateState::Seeking(30_000));
+    make_ready(&mut streamer, &mut reader, 30_000);
+}
+
+#[test]
+fn seeking_coalesces() {
+    let (_dir, mut streamer, mut reader) = streamer();
+    streamer.request_locate(10_000);
+    streamer.request_locate(20_000);
+    assert_eq!(streamer.locate_state(), LocateState::Seeking(20_000));
+    streamer.request_locate(30_000);
+    assert_eq!(streamer.locate_state(), LocateState::Seeking(30_000));
+    make_ready(&mut streamer, &mut reader, 30_000);
+    assert!(streamer.get_data_interleaved::<f32>(&mut [], true));
+    assert_frames(&get_data(&mut streamer, &mut reader, true), 30_000);
+}
+
+#[test]
+fn is_ready_requests_locate() {
+    let (_dir, mut streamer, mut reader) = streamer();
+    assert!(!streamer.is_ready(40_000));
+    assert_eq!(streamer.locate_state(), LocateState::Seeking(40_000));
+    assert!(!streamer.is_ready(50_000));
+    assert_eq!(streamer.locate_state(), LocateState::Seeking(50_000));
+    make_ready(&mut streamer, &mut reader, 50_000);
+}
+
+#[test]
+fn deferred_while_rolling() {
+    let (_dir, mut streamer, mut reader) = streamer();
+    start_rolling(&mut streamer, &mut reader, 0);
+    streamer.request_locate(10_000);
+    assert_eq!(streamer.locate_state(), LocateState::Deferred(10_000));
+    get_data(&mut streamer, &mut reader, true);
+    assert_eq!(streamer.locate_state(), LocateState::Deferred(10_000));
+    assert!(!streamer.is_ready(10_000));
+    // Coalescing works while deferred, too
+    streamer.request_locate(20_000);
+    assert_eq!(streamer.locate_state(), LocateState::Deferred(20_000));
+    // Fade out
+    get_data(&mut streamer, &mut reader, false);
+    assert_eq!(streamer.locate_state(), LocateState::Seeking(20_000));
+    make_ready(&mut streamer, &mut reader, 20_000);
+}
+
+#[test]
+fn seeking_timed_out() {
+    let (_dir, mut streamer, mut reader) = streamer();
+    let clock = Clock::install(&mut streamer);
+    streamer.request_locate(10_000);
+    clock.advance(TIMEOUT - Duration::from_millis(1));
+    assert_eq!(streamer.locate_state(), LocateState::Seeking(10_000));
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(streamer.locate_state(), LocateState::TimedOut(10_000));
+    assert!(!streamer.is_ready(10_000));
+    // The locate is not cancelled
+    make_ready(&mut streamer, &mut reader, 10_000);
+}
+
+#[test]
+fn deferred_timed_out() {
+    let (_dir, mut streamer, mut reader) = streamer();
+    let clock = Clock::install(&mut streamer);
+    start_rolling(&mut streamer, &mut reader, 0);
+    streamer.request_locate(10_000);
+    assert_eq!(streamer.locate_state(), LocateState::Deferred(10_000));
+    clock.advance(TIMEOUT);
+    assert_eq!(streamer.locate_state(), LocateState::TimedOut(10_000));
+    // Requesting the same frame doesn't restart the timeout
+    streamer.request_locate(10_000);
+    assert_eq!(streamer.locate_state(), LocateState::TimedOut(10_000));
+    // A new frame does
+    streamer.request_locate(20_000);
+    assert_eq!(streamer.locate_state(), LocateState::Deferred(20_000));
+    clock.advance(TIMEOUT);
+    assert_eq!(streamer.locate_state(), LocateState::TimedOut(20_000));
+    get_data(&mut streamer, &mut reader, false);
+    assert_eq!(streamer.locate_state(), LocateState::TimedOut(20_000));
+    make_ready(&mut streamer, &mut reader, 20_000);
+}
+
+#[test]
+fn seek_cancels_locate() {
+    let (_dir, mut streamer, _reader) = streamer();
+    streamer.request_locate(10_000);
+    let _ = streamer.seek(20_000);
+    assert_eq!(streamer.locate_state(), LocateState::Idle);
+}
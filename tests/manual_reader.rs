@@ -0,0 +1,825 @@
+mod common;
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use disk_streaming::file::{memory, AudioFileBasics, Filled};
+use disk_streaming::streamer::{
+    load_audio_file, load_audio_file_native, AudioFile, DataError, EndAction, FileStreamer,
+    ManualReader, PlaylistEntry, Route, State,
+};
+
+use common::{
+    index_sample, index_signal, memory_entry, ramp, sine, write_flac, write_vorbis, write_wav,
+    TempDir, WavFormat,
+};
+
+const BLOCKSIZE: usize = 64;
+const SAMPLERATE: usize = 44_100;
+
+fn locate(streamer: &mut FileStreamer, reader: &mut ManualReader, frame: usize) {
+    let mut attempts = 0;
+    while !streamer.is_ready(frame) {
+        // NB: One attempt may be needed to finish the initial seek
+        assert!(attempts < 2, "not ready after {} attempts", attempts);
+        reader.fill().unwrap();
+        attempts += 1;
+    }
+}
+
+/// Starts rolling at `frame` and returns `frames` frames (one `Vec` per channel)
+fn play(
+    streamer: &mut FileStreamer,
+    reader: &mut ManualReader,
+    frame: usize,
+    frames: usize,
+) -> Vec<Vec<f32>> {
+    locate(streamer, reader, frame);
+    // NB: Zero frames avoid the fade-in
+    assert!(streamer.get_data_interleaved::<f32>(&mut [], true));
+    let channels = streamer.channels();
+    let mut result = vec![Vec::new(); channels];
+    let mut buffer = vec![0.0; BLOCKSIZE * channels];
+    while result[0].len() < frames {
+        reader.fill().unwrap();
+        assert!(streamer.get_data_interleaved(&mut buffer, true));
+        for (channel, data) in result.iter_mut().enumerate() {
+            data.extend(buffer.iter().skip(channel).step_by(channels));
+        }
+    }
+    for data in &mut result {
+        data.truncate(frames);
+    }
+    result
+}
+
+/// Stops rolling (this fades out)
+fn stop(streamer: &mut FileStreamer) {
+    let mut buffer = vec![0.0; BLOCKSIZE * streamer.channels()];
+    assert!(streamer.get_data_interleaved(&mut buffer, false));
+}
+
+#[test]
+fn seek_is_sample_accurate() {
+    let frames = 3000;
+    let playlist = vec![memory_entry(
+        0,
+        SAMPLERATE,
+        vec![index_signal(0, frames), index_signal(1, frames)],
+        &[Some(0), Some(1)],
+    )];
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(playlist, BLOCKSIZE, 2).unwrap();
+    for &start in &[0, 1, 63, 64, 65, 1000, 2950, 2999] {
+        let data = play(&mut streamer, &mut reader, start, 200);
+        for (channel, data) in data.iter().enumerate() {
+            let expected: Vec<_> = (start..start + 200)
+                .map(|frame| {
+                    if frame < frames {
+                        index_sample(frame, channel)
+                    } else {
+                        0.0
+                    }
+                })
+                .collect();
+            assert_eq!(data, &expected, "start: {}, channel: {}", start, channel);
+        }
+        stop(&mut streamer);
+    }
+}
+
+/// Continues rolling with a different number of frames in each call
+fn play_frames(
+    streamer: &mut FileStreamer,
+    reader: &mut ManualReader,
+    frame_counts: &[usize],
+) -> Vec<Vec<f32>> {
+    let channels = streamer.channels();
+    let mut result = vec![Vec::new(); channels];
+    for &frames in frame_counts {
+        reader.fill().unwrap();
+        let mut buffer = vec![0.0; frames * channels];
+        assert!(streamer.get_data_interleaved(&mut buffer, true));
+        for (channel, data) in result.iter_mut().enumerate() {
+            data.extend(buffer.iter().skip(channel).step_by(channels));
+        }
+    }
+    result
+}
+
+fn index_streamer(frames: usize, blocksize: usize) -> (FileStreamer, ManualReader) {
+    let playlist = vec![memory_entry(
+        0,
+        SAMPLERATE,
+        vec![index_signal(0, frames), index_signal(1, frames)],
+        &[Some(0), Some(1)],
+    )];
+    FileStreamer::with_manual_reader(playlist, blocksize, 2).unwrap()
+}
+
+fn index_data(channel: usize, start: usize, frames: usize) -> Vec<f32> {
+    (start..start + frames)
+        .map(|frame| index_sample(frame, channel))
+        .collect()
+}
+
+#[test]
+fn partial_blocks() {
+    let (mut streamer, mut reader) = index_streamer(10_000, BLOCKSIZE);
+    let start = 1000;
+    // NB: Zero frames start rolling without fade-in
+    let frame_counts = [0, 1, 7, 56, 63, 1, 64, 65, 0, 3, 200, 127];
+    let total = frame_counts.iter().sum();
+    locate(&mut streamer, &mut reader, start);
+    let data = play_frames(&mut streamer, &mut reader, &frame_counts);
+    for (channel, data) in data.iter().enumerate() {
+        assert_eq!(
+            data,
+            &index_data(channel, start, total),
+            "channel {}",
+            channel
+        );
+    }
+    assert_eq!(streamer.position(), start + total);
+}
+
+#[test]
+fn too_many_frames() {
+    let (mut streamer, mut reader) = index_streamer(10_000, BLOCKSIZE);
+    let max_frames = streamer.max_frames();
+    play_frames(&mut streamer, &mut reader, &[0, max_frames]);
+    let mut buffer = vec![1.0; (max_frames + 1) * 2];
+    assert!(!streamer.get_data_interleaved(&mut buffer, true));
+    assert_eq!(streamer.data_error(), Some(DataError::TooManyFrames));
+    assert!(buffer.iter().all(|&value| value == 0.0));
+    assert_eq!(streamer.position(), max_frames);
+}
+
+#[test]
+fn additional_planar_channels_are_silent() {
+    let (mut streamer, mut reader) = index_streamer(10_000, BLOCKSIZE);
+    locate(&mut streamer, &mut reader, 0);
+    // NB: Zero frames start rolling without fade-in
+    play_frames(&mut streamer, &mut reader, &[0]);
+    let frames = 100;
+    let mut buffers = vec![vec![1.0f32; frames]; 3];
+    let pointers: Vec<_> = buffers.iter_mut().map(|b| b.as_mut_ptr()).collect();
+    reader.fill().unwrap();
+    assert!(unsafe { streamer.get_data(&pointers, frames, true) });
+    assert_eq!(buffers[0], index_data(0, 0, frames));
+    assert_eq!(buffers[1], index_data(1, 0, frames));
+    assert_eq!(buffers[2], vec![0.0; frames]);
+}
+
+#[test]
+fn blocksize_can_be_changed() {
+    let (mut streamer, mut reader) = index_streamer(10_000, BLOCKSIZE);
+    play(&mut streamer, &mut reader, 0, 100);
+    // Not while rolling
+    assert!(!streamer.set_blocksize(100));
+    stop(&mut streamer);
+    let position = streamer.position();
+    assert!(streamer.set_blocksize(100));
+    assert_eq!(streamer.blocksize(), 100);
+    assert_eq!(streamer.state(), State::Seeking);
+    // Not while seeking
+    assert!(!streamer.set_blocksize(200));
+    assert_eq!(streamer.blocksize(), 100);
+    locate(&mut streamer, &mut reader, position);
+    let frame_counts = [0, 100, 30, 70, 250];
+    let data = play_frames(&mut streamer, &mut reader, &frame_counts);
+    for (channel, data) in data.iter().enumerate() {
+        assert_eq!(
+            data,
+            &index_data(channel, position, 450),
+            "channel {}",
+            channel
+        );
+    }
+    // A larger blocksize allows more frames per call
+    stop(&mut streamer);
+    assert!(streamer.set_blocksize(8192));
+    assert_eq!(streamer.max_frames(), 16_384);
+    let position = streamer.position();
+    locate(&mut streamer, &mut reader, position);
+    let data = play_frames(&mut streamer, &mut reader, &[0, 5000]);
+    assert_eq!(data[0], index_data(0, position, 5000));
+}
+
+#[test]
+fn overlapping_entries_are_mixed() {
+    let playlist = vec![
+        memory_entry(0, SAMPLERATE, vec![ramp(500)], &[Some(0)]),
+        memory_entry(100, SAMPLERATE, vec![vec![0.25; 300]], &[Some(0)]),
+    ];
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(playlist, BLOCKSIZE, 1).unwrap();
+    let data = play(&mut streamer, &mut reader, 0, 600);
+    let ramp = ramp(500);
+    for (frame, &value) in data[0].iter().enumerate() {
+        let mut expected = 0.0;
+        if frame < 500 {
+            expected += ramp[frame];
+        }
+        if (100..400).contains(&frame) {
+            expected += 0.25;
+        }
+        assert_eq!(value, expected, "frame {}", frame);
+    }
+}
+
+#[test]
+fn fades_are_applied() {
+    let mut entry = memory_entry(10, SAMPLERATE, vec![vec![1.0; 400]], &[Some(0)]);
+    entry.end = Some(210);
+    entry.fade_in = 50;
+    entry.fade_out = 30;
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(vec![entry], BLOCKSIZE, 1).unwrap();
+    let data = play(&mut streamer, &mut reader, 0, 300);
+    for (frame, &value) in data[0].iter().enumerate() {
+        let expected = if (10..210).contains(&frame) {
+            let mut gain = 1.0;
+            if frame - 10 < 50 {
+                gain *= (frame - 10 + 1) as f32 / 50.0;
+            }
+            if 210 - frame <= 30 {
+                gain *= (210 - frame) as f32 / 30.0;
+            }
+            gain
+        } else {
+            0.0
+        };
+        assert_eq!(value, expected, "frame {}", frame);
+    }
+}
+
+#[test]
+fn file_offset_is_applied() {
+    let mut entry = memory_entry(50, SAMPLERATE, vec![index_signal(0, 1000)], &[Some(0)]);
+    entry.end = Some(300);
+    entry.file_offset = 200;
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(vec![entry], BLOCKSIZE, 1).unwrap();
+    for &start in &[0, 100] {
+        let data = play(&mut streamer, &mut reader, start, 400);
+        for (i, &value) in data[0].iter().enumerate() {
+            let frame = start + i;
+            let expected = if (50..300).contains(&frame) {
+                index_sample(frame - 50 + 200, 0)
+            } else {
+                0.0
+            };
+            assert_eq!(value, expected, "start: {}, frame {}", start, frame);
+        }
+        stop(&mut streamer);
+    }
+}
+
+#[test]
+fn crossfade_with_file_offset() {
+    let mut first = memory_entry(0, SAMPLERATE, vec![index_signal(0, 1000)], &[Some(0)]);
+    first.end = Some(300);
+    first.file_offset = 200;
+    first.fade_out = 100;
+    let mut second = memory_entry(200, SAMPLERATE, vec![vec![0.5; 1000]], &[Some(0)]);
+    second.end = Some(500);
+    second.file_offset = 700;
+    second.fade_in = 100;
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(vec![first, second], BLOCKSIZE, 1).unwrap();
+    // NB: Locating in the middle of the fades
+    for &start in &[0, 150, 250] {
+        let data = play(&mut streamer, &mut reader, start, 600 - start);
+        for (i, &value) in data[0].iter().enumerate() {
+            let frame = start + i;
+            let mut expected = 0.0;
+            if frame < 300 {
+                let gain = (300 - frame).min(100) as f32 / 100.0;
+                expected += index_sample(frame + 200, 0) * gain;
+            }
+            if (200..500).contains(&frame) {
+                let gain = (frame - 200 + 1).min(100) as f32 / 100.0;
+                expected += 0.5 * gain;
+            }
+            assert!(
+                (value - expected).abs() < 1e-6,
+                "start: {}, frame {}: {} != {}",
+                start,
+                frame,
+                value,
+                expected
+            );
+        }
+        stop(&mut streamer);
+    }
+}
+
+#[test]
+fn underrun_and_recovery() {
+    let frames = 20_000;
+    let playlist = vec![memory_entry(
+        0,
+        SAMPLERATE,
+        vec![index_signal(0, frames)],
+        &[Some(0)],
+    )];
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(playlist, BLOCKSIZE, 1).unwrap();
+    locate(&mut streamer, &mut reader, 0);
+    assert!(streamer.get_data_interleaved::<f32>(&mut [], true));
+    let mut output = Vec::new();
+    let mut buffer = vec![0.0; BLOCKSIZE];
+    let mut underruns = 0;
+    while output.len() < frames {
+        let previous = streamer.position();
+        let success = streamer.get_data_interleaved(&mut buffer, true);
+        // NB: After an underrun, only the available part of the buffer is valid
+        let available = streamer.position() - previous;
+        output.extend_from_slice(&buffer[..available]);
+        if !success {
+            assert_eq!(streamer.data_error(), Some(DataError::Underrun));
+            assert!(buffer[available..].iter().all(|&sample| sample == 0.0));
+            underruns += 1;
+            // The reader catches up
+            assert!(reader.fill().unwrap() > 0);
+        }
+    }
+    // NB: The reader is only driven after underruns
+    assert!(underruns > 1);
+    output.truncate(frames);
+    assert_eq!(output, index_signal(0, frames));
+}
+
+#[test]
+fn wav_fixtures_decode_exactly() {
+    let dir = TempDir::new("fixtures");
+    let frames = 5000;
+    let channels: Vec<_> = (0..3)
+        .map(|channel| index_signal(channel, frames))
+        .collect();
+    for &format in &[WavFormat::Int16, WavFormat::Float32] {
+        let path = dir.path(&format!("{:?}.wav", format));
+        write_wav(&path, SAMPLERATE, &channels, format);
+        let mut file = load_audio_file_native(&path).unwrap();
+        assert_eq!(file.channels(), 3);
+        assert_eq!(file.frames(), frames);
+        assert_eq!(file.samplerate(), SAMPLERATE);
+        let data = memory::Data::decode(&mut *file).unwrap();
+        for (channel, expected) in channels.iter().enumerate() {
+            assert_eq!(data.channel(channel), &expected[..], "{:?}", format);
+        }
+    }
+
+    let signal = sine(1000.0, SAMPLERATE, frames);
+    let path = dir.path("sine.wav");
+    write_wav(
+        &path,
+        SAMPLERATE,
+        std::slice::from_ref(&signal),
+        WavFormat::Float32,
+    );
+    let mut file = load_audio_file_native(&path).unwrap();
+    let data = memory::Data::decode(&mut *file).unwrap();
+    assert_eq!(data.channel(0), &signal[..]);
+}
+
+#[test]
+fn wav_24_bit_to_i32_is_exact() {
+    let dir = TempDir::new("24-bit");
+    let path = dir.path("24-bit.wav");
+    let frames = 3000;
+    // The most negative value is clipped, see the `sample` module
+    let values: Vec<i32> = (0..frames as i32)
+        .map(|i| match i {
+            0 => 8_388_607,
+            1 => -8_388_607,
+            _ => (i * 2_797) % 8_388_607 - 4_194_303,
+        })
+        .collect();
+    let signal = values
+        .iter()
+        .map(|&value| value as f32 / 8_388_607.0)
+        .collect();
+    write_wav(&path, SAMPLERATE, &[signal], WavFormat::Int24);
+    let file = load_audio_file(&path, SAMPLERATE).unwrap();
+    let routing = Route::from_channel_map(&[Some(0)]);
+    let playlist = vec![PlaylistEntry::new(0, None, file, routing).unwrap()];
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(playlist, BLOCKSIZE, 1).unwrap();
+    locate(&mut streamer, &mut reader, 0);
+    // NB: Zero frames avoid the fade-in
+    assert!(streamer.get_data_interleaved::<i32>(&mut [], true));
+    let mut output = Vec::new();
+    let mut buffer = vec![0i32; BLOCKSIZE];
+    while output.len() < frames {
+        reader.fill().unwrap();
+        assert!(streamer.get_data_interleaved(&mut buffer, true));
+        output.extend_from_slice(&buffer);
+    }
+    output.truncate(frames);
+    let expected: Vec<_> = values.iter().map(|value| value << 8).collect();
+    assert_eq!(output, expected);
+}
+
+/// Plays a fixture with the content of `index_signal()` (2 channels, 3000 frames)
+fn check_index_fixture_in_playlist(path: &Path) {
+    let file = load_audio_file(path, SAMPLERATE).unwrap();
+    // Swap the channels
+    let routing = vec![Route::new(0, 1, 1.0), Route::new(1, 0, 1.0)];
+    let playlist = vec![PlaylistEntry::new(100, None, file, routing).unwrap()];
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(playlist, BLOCKSIZE, 2).unwrap();
+    for &start in &[0, 1234] {
+        let data = play(&mut streamer, &mut reader, start, 1000);
+        for (output_channel, data) in data.iter().enumerate() {
+            for (i, &value) in data.iter().enumerate() {
+                let frame = start + i;
+                let expected = if frame >= 100 {
+                    index_sample(frame - 100, 1 - output_channel)
+                } else {
+                    0.0
+                };
+                assert_eq!(value, expected, "start: {}, frame: {}", start, frame);
+            }
+        }
+        stop(&mut streamer);
+    }
+}
+
+fn index_channels(frames: usize) -> Vec<Vec<f32>> {
+    vec![index_signal(0, frames), index_signal(1, frames)]
+}
+
+#[test]
+fn wav_fixture_in_playlist() {
+    let dir = TempDir::new("playlist");
+    let path = dir.path("index.wav");
+    write_wav(&path, SAMPLERATE, &index_channels(3000), WavFormat::Int16);
+    check_index_fixture_in_playlist(&path);
+}
+
+/// There is no FLAC decoder in the library, the fixture is checked with `claxon`
+#[test]
+fn flac_fixture() {
+    let dir = TempDir::new("flac");
+    let path = dir.path("index.flac");
+    let channels = index_channels(3000);
+    write_flac(&path, SAMPLERATE, &channels);
+    let mut reader = claxon::FlacReader::open(&path).unwrap();
+    let info = reader.streaminfo();
+    assert_eq!(info.sample_rate as usize, SAMPLERATE);
+    assert_eq!(info.channels, 2);
+    assert_eq!(info.samples, Some(3000));
+    let samples: Vec<i32> = reader.samples().map(Result::unwrap).collect();
+    assert_eq!(samples.len(), 2 * 3000);
+    for (i, &sample) in samples.iter().enumerate() {
+        let expected = (channels[i % 2][i / 2] * 32_767.0).round() as i32;
+        assert_eq!(sample, expected, "sample {}", i);
+    }
+}
+
+#[test]
+fn vorbis_fixture_in_playlist() {
+    let dir = TempDir::new("vorbis");
+    let path = dir.path("sine.ogg");
+    let frames = 20_000;
+    let signals = vec![
+        sine(440.0, SAMPLERATE, frames),
+        sine(1000.0, SAMPLERATE, frames),
+    ];
+    let signals: Vec<Vec<f32>> = signals
+        .into_iter()
+        .map(|signal| signal.iter().map(|sample| sample * 0.5).collect())
+        .collect();
+    write_vorbis(&path, SAMPLERATE, &signals, 0.5);
+    let mut file = load_audio_file_native(&path).unwrap();
+    assert_eq!(file.frames(), frames);
+    let reference = memory::Data::decode(&mut *file).unwrap();
+    assert_eq!(reference.frames(), frames);
+    // Vorbis is lossy, the decoded data is used as reference below
+    for (channel, signal) in signals.iter().enumerate() {
+        for (a, b) in reference.channel(channel).iter().zip(signal) {
+            assert!((a - b).abs() < 0.05, "{} != {}", a, b);
+        }
+    }
+
+    let file = load_audio_file(&path, SAMPLERATE).unwrap();
+    let routing = (0..2).map(|c| Route::new(c, c, 1.0)).collect();
+    let playlist = vec![PlaylistEntry::new(100, None, file, routing).unwrap()];
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(playlist, BLOCKSIZE, 2).unwrap();
+    for &start in &[0, 1234, frames] {
+        let data = play(&mut streamer, &mut reader, start, 1000);
+        for (channel, data) in data.iter().enumerate() {
+            for (i, &value) in data.iter().enumerate() {
+                let frame = start + i;
+                let expected = if (100..frames + 100).contains(&frame) {
+                    reference.channel(channel)[frame - 100]
+                } else {
+                    0.0
+                };
+                assert_eq!(value, expected, "start: {}, frame: {}", start, frame);
+            }
+        }
+        stop(&mut streamer);
+    }
+}
+
+#[test]
+fn converter_in_playlist() {
+    // Frames after seeking that are not compared (see `Converter::seek()`)
+    const SETTLE: usize = 1000;
+    let dir = TempDir::new("converter");
+    let path = dir.path("sine.wav");
+    let frames = 10_000;
+    let signal: Vec<_> = sine(100.0, SAMPLERATE, frames)
+        .iter()
+        .map(|sample| sample * 0.5)
+        .collect();
+    write_wav(&path, SAMPLERATE, &[signal], WavFormat::Float32);
+    let mut file = load_audio_file(&path, 48_000).unwrap();
+    let converted_frames = file.frames();
+    assert_eq!(converted_frames, frames * 48_000 / SAMPLERATE);
+    assert_eq!(file.samplerate(), 48_000);
+    let reference = memory::Data::decode(&mut *file).unwrap();
+    let reference = reference.channel(0);
+    assert!((reference.len() as isize - converted_frames as isize).abs() <= 1);
+    let peak = reference
+        .iter()
+        .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+    assert!((peak - 0.5).abs() < 0.01, "peak: {}", peak);
+
+    let file = load_audio_file(&path, 48_000).unwrap();
+    let playlist = vec![PlaylistEntry::new(0, None, file, vec![Route::new(0, 0, 1.0)]).unwrap()];
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(playlist, BLOCKSIZE, 1).unwrap();
+    for &(start, settle) in &[(0, 0), (1234, SETTLE)] {
+        let data = play(&mut streamer, &mut reader, start, 5000).remove(0);
+        for (i, &value) in data.iter().enumerate().skip(settle) {
+            let expected = reference[start + i];
+            assert!(
+                (value - expected).abs() <= 0.01,
+                "start: {}, frame {}: {} != {}",
+                start,
+                start + i,
+                value,
+                expected
+            );
+        }
+        stop(&mut streamer);
+    }
+}
+
+#[test]
+fn invalid_output_channel_is_rejected() {
+    let playlist = vec![memory_entry(0, SAMPLERATE, vec![ramp(100)], &[Some(2)])];
+    let error = FileStreamer::with_manual_reader(playlist, BLOCKSIZE, 2)
+        .err()
+        .unwrap();
+    assert_eq!((error.entry, error.channel, error.channels), (0, 2, 2));
+}
+
+#[test]
+fn gain_automation_after_seeking() {
+    let playlist = vec![memory_entry(
+        0,
+        SAMPLERATE,
+        vec![vec![1.0; 1000]],
+        &[Some(0)],
+    )];
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(playlist, BLOCKSIZE, 1).unwrap();
+    let mut automation = streamer.take_gain_automation().unwrap();
+    automation.set_gain(0, 100, 0.5).unwrap();
+    // NB: The ramp crosses a block boundary
+    automation.ramp_gain(0, 120, 20, 0.0).unwrap();
+    let expected: Vec<_> = (0..300)
+        .map(|frame| match frame {
+            0..=99 => 1.0,
+            100..=119 => 0.5,
+            120..=139 => 0.5 - (frame - 120) as f32 / 40.0,
+            _ => 0.0,
+        })
+        .collect();
+    let data = play(&mut streamer, &mut reader, 0, 300);
+    assert_eq!(&data[0][..], &expected[..]);
+    stop(&mut streamer);
+    // The gains are re-computed after seeking
+    let data = play(&mut streamer, &mut reader, 50, 250);
+    assert_eq!(&data[0][..], &expected[50..]);
+    stop(&mut streamer);
+    let data = play(&mut streamer, &mut reader, 130, 170);
+    assert_eq!(&data[0][..], &expected[130..]);
+    stop(&mut streamer);
+    automation.set_gain(0, 200, 1.0).unwrap();
+    let data = play(&mut streamer, &mut reader, 0, 300);
+    assert_eq!(&data[0][..200], &expected[..200]);
+    assert_eq!(&data[0][200..], &[1.0; 100][..]);
+}
+
+/// Reports pending reads for the first `polls` calls of `reads_pending()` and logs its fills
+struct SlowFile {
+    id: usize,
+    file: memory::File,
+    polls: usize,
+    log: Arc<Mutex<Vec<usize>>>,
+}
+
+impl AudioFileBasics for SlowFile {
+    fn channels(&self) -> usize {
+        self.file.channels()
+    }
+
+    fn frames(&self) -> usize {
+        self.file.frames()
+    }
+
+    fn samplerate(&self) -> usize {
+        self.file.samplerate()
+    }
+
+    fn seek(&mut self, frame: usize) -> Result<(), Error> {
+        self.file.seek(frame)
+    }
+}
+
+impl AudioFile for SlowFile {
+    fn fill_channels(
+        &mut self,
+        routing: &[Route],
+        gains: &[f32],
+        blocksize: usize,
+        offset: usize,
+        channels: &mut [Box<[f32]>],
+    ) -> Result<Filled, Error> {
+        self.log.lock().unwrap().push(self.id);
+        AudioFile::fill_channels(&mut self.file, routing, gains, blocksize, offset, channels)
+    }
+
+    fn select_channels(&mut self, channels: &[usize]) -> Result<(), Error> {
+        AudioFile::select_channels(&mut self.file, channels)
+    }
+
+    fn reads_pending(&mut self) -> bool {
+        if self.polls > 0 {
+            self.polls -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Two entries (with index signals) with the given number of polls, returns the log of fills
+fn play_slow_files(polls: [usize; 2], frames: usize) -> Vec<usize> {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let playlist = (0..2)
+        .map(|id| {
+            let data = memory::Data::from_channels(SAMPLERATE, vec![index_signal(id, frames)]);
+            let file = Box::new(SlowFile {
+                id,
+                file: memory::File::new(Arc::new(data)),
+                polls: polls[id],
+                log: Arc::clone(&log),
+            });
+            PlaylistEntry::new(0, None, file, vec![Route::new(0, id, 1.0)]).unwrap()
+        })
+        .collect();
+    let (mut streamer, mut reader) =
+        FileStreamer::with_manual_reader(playlist, BLOCKSIZE, 2).unwrap();
+    let data = play(&mut streamer, &mut reader, 0, frames);
+    for (channel, data) in data.iter().enumerate() {
+        assert_eq!(data, &index_signal(channel, frames), "channel: {}", channel);
+    }
+    let log = log.lock().unwrap();
+    log.clone()
+}
+
+#[test]
+fn pending_entry_does_not_hold_back_others() {
+    let log = play_slow_files([1, 0], 1000);
+    // The delayed entry is filled after the other one, but still in the same block
+    assert_eq!(&log[..4], &[1, 0, 0, 1]);
+    let log = play_slow_files([0, 3], 1000);
+    assert_eq!(&log[..6], &[0, 1, 0, 1, 0, 1]);
+}
+
+#[test]
+fn pending_entries_are_filled_eventually() {
+    // NB: If all entries are pending, the first one is filled anyway
+    let log = play_slow_files([usize::MAX, usize::MAX], 1000);
+    assert_eq!(&log[..4], &[0, 1, 0, 1]);
+    let log = play_slow_files([usize::MAX, 0], 1000);
+    assert!(log.chunks(2).all(|pair| pair == [1, 0]));
+}
+
+fn end_action_streamer(action: EndAction) -> (FileStreamer, ManualReader) {
+    let mut entry = memory_entry(0, SAMPLERATE, vec![ramp(300)], &[Some(0)]);
+    entry.end = Some(300);
+    let (streamer, reader) = FileStreamer::with_manual_reader(vec![entry], BLOCKSIZE, 1).unwrap();
+    streamer.set_end_action(action);
+    (streamer, reader)
+}
+
+#[test]
+fn end_action_continue() {
+    let (mut streamer, mut reader) = end_action_streamer(EndAction::Continue);
+    let data = play(&mut streamer, &mut reader, 0, 10 * BLOCKSIZE);
+    let ramp = ramp(300);
+    assert_eq!(data[0][..300], ramp[..]);
+    assert!(data[0][300..].iter().all(|&value| value == 0.0));
+    assert_eq!(streamer.state(), State::Ended);
+    assert_eq!(streamer.position(), 10 * BLOCKSIZE);
+}
+
+#[test]
+fn end_action_stop() {
+    let (mut streamer, mut reader) = end_action_streamer(EndAction::Stop);
+    let data = play(&mut streamer, &mut reader, 0, 10 * BLOCKSIZE);
+    let ramp = ramp(300);
+    assert_eq!(data[0][..300], ramp[..]);
+    assert!(data[0][300..].iter().all(|&value| value == 0.0));
+    assert_eq!(streamer.state(), State::Ended);
+    // The block containing the end is played, then one block is faded out
+    let position = streamer.position();
+    assert_eq!(position, 300 / BLOCKSIZE * BLOCKSIZE + 2 * BLOCKSIZE);
+    let mut buffer = vec![0.0; BLOCKSIZE];
+    reader.fill().unwrap();
+    assert!(streamer.get_data_interleaved(&mut buffer, true));
+    assert_eq!(streamer.position(), position);
+    // Seeking restarts playback
+    let data = play(&mut streamer, &mut reader, 100, 100);
+    assert_eq!(data[0][..], ramp[100..200]);
+    assert_eq!(streamer.state(), State::Rolling);
+}
+
+#[test]
+fn end_action_loop() {
+    let (mut streamer, mut reader) = end_action_streamer(EndAction::Loop);
+    let frames = 10 * BLOCKSIZE;
+    let data = play(&mut streamer, &mut reader, 50, frames);
+    let ramp = ramp(300);
+    for (i, &value) in data[0].iter().enumerate() {
+        assert_eq!(value, ramp[(50 + i) % 300], "frame {}", i);
+    }
+    assert_eq!(streamer.state(), State::Rolling);
+    assert_eq!(streamer.position(), (50 + frames) % 300);
+}
+
+#[test]
+fn gain_automation_when_looping() {
+    let (mut streamer, mut reader) = end_action_streamer(EndAction::Loop);
+    let mut automation = streamer.take_gain_automation().unwrap();
+    automation.set_gain(0, 100, 0.5).unwrap();
+    automation.set_gain(0, 200, 0.25).unwrap();
+    let frames = 10 * BLOCKSIZE;
+    let data = play(&mut streamer, &mut reader, 0, frames);
+    let ramp = ramp(300);
+    // The breakpoints are applied in each iteration
+    for (i, &value) in data[0].iter().enumerate() {
+        let frame = i % 300;
+        let gain = match frame {
+            0..=99 => 1.0,
+            100..=199 => 0.5,
+            _ => 0.25,
+        };
+        assert_eq!(value, ramp[frame] * gain, "frame {}", i);
+    }
+}
+
+#[test]
+fn transport_follows_the_streamer() {
+    let (mut streamer, mut reader) = end_action_streamer(EndAction::Continue);
+    let transport = streamer.transport();
+    assert_eq!(transport.duration(), 300);
+    let check = |streamer: &FileStreamer, state: State, position: usize| {
+        assert_eq!(streamer.state(), state);
+        assert_eq!(streamer.position(), position);
+        assert_eq!(transport.state(), state);
+        assert_eq!(transport.position(), position);
+    };
+    check(&streamer, State::Stopped, 0);
+    locate(&mut streamer, &mut reader, 100);
+    check(&streamer, State::Ready, 100);
+    play(&mut streamer, &mut reader, 100, BLOCKSIZE);
+    check(&streamer, State::Rolling, 100 + BLOCKSIZE);
+    // The seek is deferred until after the fade-out
+    assert!(!streamer.seek(10));
+    check(&streamer, State::Seeking, 10);
+    stop(&mut streamer);
+    check(&streamer, State::Seeking, 10);
+    locate(&mut streamer, &mut reader, 10);
+    check(&streamer, State::Ready, 10);
+    play(&mut streamer, &mut reader, 10, 300);
+    check(
+        &streamer,
+        State::Ended,
+        10 + 300 / BLOCKSIZE * BLOCKSIZE + BLOCKSIZE,
+    );
+    // The end action can also be changed via the transport handle
+    transport.set_end_action(EndAction::Loop);
+    assert_eq!(streamer.end_action(), EndAction::Loop);
+}
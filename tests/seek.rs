@@ -0,0 +1,325 @@
+//! Property tests for all `AudioFile` backends:
+//! `seek(n)` followed by reading must give the same samples as
+//! reading continuously from the beginning and skipping `n` frames.
+
+mod common;
+
+use std::sync::Arc;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestRunner};
+
+use disk_streaming::disk::ReadOptions;
+use disk_streaming::file::{memory, wav, Route};
+use disk_streaming::streamer::{
+    load_audio_file, load_audio_file_native, load_audio_file_with_options, AudioFile,
+};
+
+use common::{index_signal, ogg_chain, sine, write_vorbis, write_wav, TempDir, WavFormat};
+
+const CASES: u32 = 32;
+const FRAMES: usize = 10_000;
+const SAMPLERATE: usize = 44_100;
+/// Number of frames that are compared after each seek
+const LENGTH: usize = 3000;
+
+type Open<'a> = &'a dyn Fn() -> Box<dyn AudioFile + Send>;
+
+/// Reads up to `frames` frames in blocks of `blocksize`, returns one `Vec` per channel
+fn read(file: &mut dyn AudioFile, blocksize: usize, frames: usize) -> Vec<Vec<f32>> {
+    let channels = file.channels();
+    let routing: Vec<_> = (0..channels).map(|c| Route::new(c, c, 1.0)).collect();
+    let gains = vec![1.0; blocksize];
+    let mut buffers: Vec<Box<[f32]>> = vec![vec![0.0; blocksize].into_boxed_slice(); channels];
+    let mut result = vec![Vec::new(); channels];
+    while result[0].len() < frames {
+        let blocksize = std::cmp::min(blocksize, frames - result[0].len());
+        for buffer in &mut buffers {
+            buffer.iter_mut().for_each(|sample| *sample = 0.0);
+        }
+        let filled = file
+            .fill_channels(&routing, &gains, blocksize, 0, &mut buffers)
+            .unwrap();
+        for (data, buffer) in result.iter_mut().zip(&buffers) {
+            data.extend_from_slice(&buffer[..filled.frames]);
+        }
+        if filled.end_of_file {
+            break;
+        }
+    }
+    result
+}
+
+/// Random block sizes and seek positions (including some past the end)
+fn check_seek_consistency(open: Open, tolerance: f32) {
+    let frames = open().frames();
+    let strategy = (1..4096usize, vec(0..frames + 100, 1..5));
+    let mut runner = TestRunner::new(Config::with_cases(CASES));
+    runner
+        .run(&strategy, |(blocksize, positions)| {
+            let mut file = open();
+            let reference = read(&mut *file, blocksize, usize::MAX);
+            prop_assert_eq!(reference[0].len(), frames);
+            for &position in &positions {
+                file.seek(position).unwrap();
+                let data = read(&mut *file, blocksize, LENGTH);
+                for (channel, (data, reference)) in data.iter().zip(&reference).enumerate() {
+                    let expected = reference.get(position..).unwrap_or(&[]);
+                    let expected = &expected[..std::cmp::min(LENGTH, expected.len())];
+                    prop_assert_eq!(data.len(), expected.len());
+                    for (i, (a, b)) in data.iter().zip(expected).enumerate() {
+                        prop_assert!(
+                            (a - b).abs() <= tolerance,
+                            "position: {}, channel: {}, frame {}: {} != {}",
+                            position,
+                            channel,
+                            i,
+                            a,
+                            b
+                        );
+                    }
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+fn index_channels() -> Vec<Vec<f32>> {
+    (0..2)
+        .map(|channel| index_signal(channel, FRAMES))
+        .collect()
+}
+
+/// Vorbis is lossy, sines are used instead of `index_signal()`
+fn sine_channels() -> Vec<Vec<f32>> {
+    [440.0, 1000.0]
+        .iter()
+        .map(|&frequency| {
+            sine(frequency, SAMPLERATE, FRAMES)
+                .iter()
+                .map(|sample| sample * 0.5)
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn memory() {
+    let data = Arc::new(memory::Data::from_channels(SAMPLERATE, index_channels()));
+    check_seek_consistency(&|| Box::new(memory::File::new(Arc::clone(&data))), 0.0);
+}
+
+#[test]
+fn wav() {
+    let dir = TempDir::new("seek-wav");
+    for &format in &[
+        WavFormat::Int16,
+        WavFormat::Int24,
+        WavFormat::Int32,
+        WavFormat::Float32,
+    ] {
+        let path = dir.path(&format!("{:?}.wav", format));
+        write_wav(&path, SAMPLERATE, &index_channels(), format);
+        check_seek_consistency(
+            &|| Box::new(wav::File::new(std::fs::File::open(&path).unwrap()).unwrap()),
+            0.0,
+        );
+    }
+}
+
+#[test]
+fn wav_truncated() {
+    let dir = TempDir::new("seek-wav-truncated");
+    let path = dir.path("truncated.wav");
+    write_wav(&path, SAMPLERATE, &index_channels(), WavFormat::Float32);
+    let len = std::fs::metadata(&path).unwrap().len();
+    // NB: 2 channels with 4 bytes each, the last frame is cut in the middle
+    let available = FRAMES - 1001;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(len - 1000 * 8 - 3)
+        .unwrap();
+    let open = || -> Box<dyn AudioFile + Send> {
+        Box::new(wav::File::new(std::fs::File::open(&path).unwrap()).unwrap())
+    };
+    // The header still announces all frames
+    assert_eq!(open().frames(), FRAMES);
+    let data = read(&mut *open(), 1000, FRAMES);
+    let expected: Vec<Vec<f32>> = index_channels()
+        .into_iter()
+        .map(|mut channel| {
+            channel.truncate(available);
+            channel
+        })
+        .collect();
+    assert_eq!(data, expected);
+    for &frame in &[available - 10, available, FRAMES] {
+        let mut file = open();
+        file.seek(frame).unwrap();
+        let data = read(&mut *file, 1000, FRAMES);
+        assert_eq!(data[0], expected[0][frame.min(available)..]);
+    }
+}
+
+#[test]
+fn disk() {
+    let dir = TempDir::new("seek-disk");
+    let path = dir.path("index.wav");
+    write_wav(&path, SAMPLERATE, &index_channels(), WavFormat::Int16);
+    // NB: The file has 40000 bytes of audio data
+    for &read_size in &[4096, 12_288, 64 * 1024] {
+        let options = ReadOptions {
+            read_size,
+            read_ahead: 0,
+            ..Default::default()
+        };
+        check_seek_consistency(
+            &|| load_audio_file_with_options(&path, SAMPLERATE, &options).unwrap(),
+            0.0,
+        );
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+#[test]
+fn uring() {
+    use disk_streaming::uring::Uring;
+
+    let dir = TempDir::new("seek-uring");
+    let wav_path = dir.path("index.wav");
+    write_wav(&wav_path, SAMPLERATE, &index_channels(), WavFormat::Int16);
+    let vorbis_path = dir.path("sine.ogg");
+    write_vorbis(&vorbis_path, SAMPLERATE, &sine_channels(), 0.5);
+    // All files share one (small) submission queue, like in the reader thread
+    let uring = Uring::new(4).unwrap();
+    for &read_size in &[4096, 64 * 1024] {
+        let options = ReadOptions {
+            read_size,
+            read_ahead: 0,
+            uring: Some(uring.clone()),
+            ..Default::default()
+        };
+        check_seek_consistency(
+            &|| load_audio_file_with_options(&wav_path, SAMPLERATE, &options).unwrap(),
+            0.0,
+        );
+        check_seek_consistency(
+            &|| load_audio_file_with_options(&vorbis_path, SAMPLERATE, &options).unwrap(),
+            1e-6,
+        );
+    }
+}
+
+#[test]
+fn mmap() {
+    let dir = TempDir::new("seek-mmap");
+    let path = dir.path("index.wav");
+    write_wav(&path, SAMPLERATE, &index_channels(), WavFormat::Float32);
+    let options = ReadOptions {
+        mmap: true,
+        ..Default::default()
+    };
+    check_seek_consistency(
+        &|| load_audio_file_with_options(&path, SAMPLERATE, &options).unwrap(),
+        0.0,
+    );
+}
+
+#[test]
+fn vorbis() {
+    let dir = TempDir::new("seek-vorbis");
+    let path = dir.path("sine.ogg");
+    write_vorbis(&path, SAMPLERATE, &sine_channels(), 0.5);
+    assert_eq!(load_audio_file_native(&path).unwrap().frames(), FRAMES);
+    check_seek_consistency(&|| load_audio_file_native(&path).unwrap(), 1e-6);
+}
+
+#[test]
+fn vorbis_chained() {
+    let dir = TempDir::new("seek-vorbis-chained");
+    let single = dir.path("single.ogg");
+    let path = dir.path("chained.ogg");
+    write_vorbis(&single, SAMPLERATE, &sine_channels(), 0.5);
+    let data = std::fs::read(&single).unwrap();
+    std::fs::write(&path, ogg_chain(&data, &data)).unwrap();
+    assert_eq!(load_audio_file_native(&path).unwrap().frames(), 2 * FRAMES);
+    check_seek_consistency(&|| load_audio_file_native(&path).unwrap(), 1e-6);
+}
+
+/// Sample rate conversion is not sample-exact after seeking (see `Converter::seek()`)
+#[test]
+fn converter() {
+    // Frames at the beginning (after seeking) and at the end are not compared
+    const SETTLE: usize = 1000;
+    let dir = TempDir::new("seek-converter");
+    let path = dir.path("sine.wav");
+    let signal: Vec<_> = sine(100.0, SAMPLERATE, FRAMES)
+        .iter()
+        .map(|sample| sample * 0.5)
+        .collect();
+    write_wav(&path, SAMPLERATE, &[signal], WavFormat::Float32);
+    let open = || load_audio_file(&path, 48_000).unwrap();
+    let frames = open().frames();
+    let strategy = (1..4096usize, vec(0..frames, 1..5));
+    let mut runner = TestRunner::new(Config::with_cases(CASES));
+    runner
+        .run(&strategy, |(blocksize, positions)| {
+            let mut file = open();
+            let reference = read(&mut *file, blocksize, usize::MAX).remove(0);
+            prop_assert!((reference.len() as isize - frames as isize).abs() <= 1);
+            for &position in &positions {
+                file.seek(position).unwrap();
+                let data = read(&mut *file, blocksize, usize::MAX).remove(0);
+                let expected = &reference[position..];
+                prop_assert!((data.len() as isize - expected.len() as isize).abs() <= 2);
+                let end = std::cmp::min(data.len(), expected.len()).saturating_sub(SETTLE);
+                for i in SETTLE..std::cmp::max(end, SETTLE) {
+                    prop_assert!(
+                        (data[i] - expected[i]).abs() <= 0.01,
+                        "position: {}, frame {}: {} != {}",
+                        position,
+                        i,
+                        data[i],
+                        expected[i]
+                    );
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn seek_beyond_u32() {
+    let dir = TempDir::new("seek-u32");
+    let path = dir.path("index.wav");
+    write_wav(&path, SAMPLERATE, &index_channels(), WavFormat::Int16);
+    let vorbis_path = dir.path("sine.ogg");
+    write_vorbis(&vorbis_path, SAMPLERATE, &sine_channels(), 0.5);
+    let frame = u32::MAX as usize + 101;
+    let files: Vec<Box<dyn AudioFile + Send>> = vec![
+        Box::new(wav::File::new(std::fs::File::open(&path).unwrap()).unwrap()),
+        load_audio_file_native(&path).unwrap(),
+        load_audio_file_with_options(
+            &path,
+            SAMPLERATE,
+            &ReadOptions {
+                mmap: true,
+                ..Default::default()
+            },
+        )
+        .unwrap(),
+        load_audio_file_native(&vorbis_path).unwrap(),
+    ];
+    for mut file in files {
+        file.seek(frame).unwrap();
+        assert!(read(&mut *file, 1024, LENGTH)[0].is_empty());
+        // Seeking back still works
+        file.seek(100).unwrap();
+        assert_eq!(read(&mut *file, 1024, 1)[0].len(), 1);
+    }
+}